@@ -1,5 +1,8 @@
 use crate::types::blake3;
-use crate::types::{Address, AuthorizedTransaction, Body, GetAddress, Transaction, Verify};
+use crate::types::{
+    validate_authorization_count, Address, AuthorizedTransaction, Body, GetAddress, Transaction,
+    Verify, WrongNumberOfAuthorizations,
+};
 pub use ed25519_dalek::{Keypair, PublicKey, Signature, SignatureError, Signer, Verifier};
 use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
@@ -52,6 +55,10 @@ struct Package<'a> {
 pub fn verify_authorized_transaction<C: Clone + Serialize + Sync>(
     transaction: &AuthorizedTransaction<Authorization, C>,
 ) -> Result<(), Error> {
+    validate_authorization_count(
+        std::iter::once(&transaction.transaction),
+        transaction.authorizations.len(),
+    )?;
     let serialized_transaction = bincode::serialize(&transaction.transaction)?;
     let messages: Vec<_> = std::iter::repeat(serialized_transaction.as_slice())
         .take(transaction.authorizations.len())
@@ -73,6 +80,7 @@ pub fn verify_authorized_transaction<C: Clone + Serialize + Sync>(
 pub fn verify_authorizations<C: Clone + Serialize + Sync>(
     body: &Body<Authorization, C>,
 ) -> Result<(), Error> {
+    validate_authorization_count(&body.transactions, body.authorizations.len())?;
     let input_numbers = body
         .transactions
         .iter()
@@ -177,8 +185,103 @@ pub enum Error {
         address: Address,
         hash_public_key: Address,
     },
+    #[error("{0}")]
+    WrongNumberOfAuthorizations(#[from] WrongNumberOfAuthorizations),
     #[error("ed25519_dalek error")]
     DalekError(#[from] SignatureError),
     #[error("bincode error")]
     BincodeError(#[from] bincode::Error),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::{Content, OutPoint, Output, Txid};
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = (&secret).into();
+        Keypair { secret, public }
+    }
+
+    fn authorized_transaction(num_inputs: u8) -> AuthorizedTransaction<Authorization, ()> {
+        let keypair = keypair(1);
+        let address = get_address(&keypair.public);
+        let transaction = Transaction {
+            inputs: (0..num_inputs)
+                .map(|n| OutPoint::Regular {
+                    txid: Txid([n; 32]),
+                    vout: 0,
+                })
+                .collect(),
+            outputs: vec![Output {
+                address,
+                content: Content::Value(1),
+            }],
+            lock_time: None,
+            relative_locks: vec![],
+        };
+        let addresses_keypairs = vec![(address, &keypair); num_inputs as usize];
+        authorize(&addresses_keypairs, transaction).unwrap()
+    }
+
+    #[test]
+    fn transaction_authorization_count() {
+        let transaction = authorized_transaction(2);
+        assert!(verify_authorized_transaction(&transaction).is_ok());
+
+        let mut missing = transaction.clone();
+        missing.authorizations.pop();
+        assert!(matches!(
+            verify_authorized_transaction(&missing),
+            Err(Error::WrongNumberOfAuthorizations(
+                WrongNumberOfAuthorizations {
+                    inputs: 2,
+                    authorizations: 1
+                }
+            ))
+        ));
+
+        let mut extra = transaction;
+        extra.authorizations.push(extra.authorizations[0].clone());
+        assert!(matches!(
+            verify_authorized_transaction(&extra),
+            Err(Error::WrongNumberOfAuthorizations(
+                WrongNumberOfAuthorizations {
+                    inputs: 2,
+                    authorizations: 3
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn body_authorization_count() {
+        let body = Body::new(vec![authorized_transaction(2)], vec![]);
+        assert!(verify_authorizations(&body).is_ok());
+
+        let mut missing = body.clone();
+        missing.authorizations.pop();
+        assert!(matches!(
+            verify_authorizations(&missing),
+            Err(Error::WrongNumberOfAuthorizations(
+                WrongNumberOfAuthorizations {
+                    inputs: 2,
+                    authorizations: 1
+                }
+            ))
+        ));
+
+        let mut extra = body;
+        extra.authorizations.push(extra.authorizations[0].clone());
+        assert!(matches!(
+            verify_authorizations(&extra),
+            Err(Error::WrongNumberOfAuthorizations(
+                WrongNumberOfAuthorizations {
+                    inputs: 2,
+                    authorizations: 3
+                }
+            ))
+        ));
+    }
+}
@@ -0,0 +1,409 @@
+//! Transports for talking to the mainchain.
+use super::client::{
+    AmountBtc, Block, Deposit, FailedWithdrawal, MainClient, SpentWithdrawal, WithdrawalStatus,
+};
+use super::Error;
+use crate::types::bitcoin;
+use base64::Engine as _;
+use jsonrpsee::core::async_trait;
+use jsonrpsee::http_client::{HeaderMap, HttpClient, HttpClientBuilder};
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+/// Mainchain API used by `Drivechain` and `Miner`.
+#[async_trait]
+pub trait MainchainBackend: Send + Sync {
+    async fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error>;
+    async fn get_block_count(&self) -> Result<usize, Error>;
+    async fn get_block(&self, block_hash: &bitcoin::BlockHash) -> Result<Block, Error>;
+    async fn get_block_hash(&self, height: u32) -> Result<bitcoin::BlockHash, Error>;
+    /// Returns the hex encoded transaction.
+    async fn get_raw_transaction(&self, txid: &bitcoin::Txid) -> Result<String, Error>;
+    /// Returns the hex encoded block, unlike `get_raw_transaction` this
+    /// doesn't need -txindex.
+    async fn get_raw_block(&self, block_hash: &bitcoin::BlockHash) -> Result<String, Error>;
+    async fn verify_bmm(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+    ) -> Result<serde_json::Value, Error>;
+    async fn create_bmm_critical_data_tx(
+        &self,
+        amount: bitcoin::Amount,
+        height: u32,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+        prev_bytes: &str,
+    ) -> Result<serde_json::Value, Error>;
+    async fn list_sidechain_deposits_by_block(
+        &self,
+        sidechain_number: u8,
+        end: Option<bitcoin::BlockHash>,
+        start: Option<bitcoin::BlockHash>,
+    ) -> Result<Vec<Deposit>, Error>;
+    async fn list_withdrawal_status(
+        &self,
+        sidechain_number: u8,
+    ) -> Result<Vec<WithdrawalStatus>, Error>;
+    async fn list_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error>;
+    async fn list_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error>;
+    /// `raw_transaction` is hex encoded.
+    async fn receive_withdrawal_bundle(
+        &self,
+        sidechain_number: u8,
+        raw_transaction: &str,
+    ) -> Result<serde_json::Value, Error>;
+    async fn generate(&self, num: u32) -> Result<serde_json::Value, Error>;
+    async fn create_sidechain_deposit(
+        &self,
+        sidechain_number: u8,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<serde_json::Value, Error>;
+}
+
+/// Credentials for the mainchain JSON-RPC server.
+#[derive(Debug, Clone)]
+pub enum MainchainAuth {
+    None,
+    UserPassword {
+        user: String,
+        password: String,
+    },
+    /// Path to the `.cookie` file written by the mainchain node, it is read
+    /// every time the client is built, since the node rewrites it on restart.
+    CookieFile(PathBuf),
+}
+
+/// JSON-RPC over HTTP or HTTPS.
+#[derive(Clone)]
+pub struct JsonRpcBackend {
+    client: HttpClient,
+}
+
+impl JsonRpcBackend {
+    /// `url` is the full server url, for example `https://127.0.0.1:18443`.
+    pub fn new(url: &str, auth: &MainchainAuth) -> Result<Self, Error> {
+        let mut headers = HeaderMap::new();
+        let credentials = match auth {
+            MainchainAuth::None => None,
+            MainchainAuth::UserPassword { user, password } => Some(format!("{user}:{password}")),
+            MainchainAuth::CookieFile(path) => {
+                Some(std::fs::read_to_string(path)?.trim().to_string())
+            }
+        };
+        if let Some(credentials) = credentials {
+            let header_value = format!(
+                "Basic {}",
+                base64::engine::general_purpose::STANDARD_NO_PAD.encode(credentials)
+            )
+            .parse()?;
+            headers.insert("authorization", header_value);
+        }
+        let client = HttpClientBuilder::default()
+            .set_headers(headers)
+            .build(url)?;
+        Ok(Self { client })
+    }
+}
+
+#[async_trait]
+impl MainchainBackend for JsonRpcBackend {
+    async fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error> {
+        Ok(self.client.getbestblockhash().await?)
+    }
+
+    async fn get_block_count(&self) -> Result<usize, Error> {
+        Ok(self.client.getblockcount().await?)
+    }
+
+    async fn get_block(&self, block_hash: &bitcoin::BlockHash) -> Result<Block, Error> {
+        Ok(self.client.getblock(block_hash, None).await?)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<bitcoin::BlockHash, Error> {
+        Ok(self.client.getblockhash(height as usize).await?)
+    }
+
+    async fn get_raw_transaction(&self, txid: &bitcoin::Txid) -> Result<String, Error> {
+        Ok(self.client.getrawtransaction(txid, false).await?)
+    }
+
+    async fn get_raw_block(&self, block_hash: &bitcoin::BlockHash) -> Result<String, Error> {
+        use jsonrpsee::core::client::ClientT as _;
+        // Verbosity 0 returns the hex encoded block instead of a `Block`.
+        let params = jsonrpsee::rpc_params![block_hash, 0];
+        Ok(self.client.request("getblock", params).await?)
+    }
+
+    async fn verify_bmm(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+    ) -> Result<serde_json::Value, Error> {
+        Ok(self
+            .client
+            .verifybmm(block_hash, critical_hash, sidechain_number)
+            .await?)
+    }
+
+    async fn create_bmm_critical_data_tx(
+        &self,
+        amount: bitcoin::Amount,
+        height: u32,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+        prev_bytes: &str,
+    ) -> Result<serde_json::Value, Error> {
+        Ok(self
+            .client
+            .createbmmcriticaldatatx(
+                AmountBtc(amount),
+                height,
+                critical_hash,
+                sidechain_number,
+                prev_bytes,
+            )
+            .await?)
+    }
+
+    async fn list_sidechain_deposits_by_block(
+        &self,
+        sidechain_number: u8,
+        end: Option<bitcoin::BlockHash>,
+        start: Option<bitcoin::BlockHash>,
+    ) -> Result<Vec<Deposit>, Error> {
+        Ok(self
+            .client
+            .listsidechaindepositsbyblock(sidechain_number, end, start)
+            .await?)
+    }
+
+    async fn list_withdrawal_status(
+        &self,
+        sidechain_number: u8,
+    ) -> Result<Vec<WithdrawalStatus>, Error> {
+        Ok(self.client.listwithdrawalstatus(sidechain_number).await?)
+    }
+
+    async fn list_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error> {
+        Ok(self.client.listspentwithdrawals().await?)
+    }
+
+    async fn list_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error> {
+        Ok(self.client.listfailedwithdrawals().await?)
+    }
+
+    async fn receive_withdrawal_bundle(
+        &self,
+        sidechain_number: u8,
+        raw_transaction: &str,
+    ) -> Result<serde_json::Value, Error> {
+        Ok(self
+            .client
+            .receivewithdrawalbundle(sidechain_number, raw_transaction)
+            .await?)
+    }
+
+    async fn generate(&self, num: u32) -> Result<serde_json::Value, Error> {
+        Ok(self.client.generate(num).await?)
+    }
+
+    async fn create_sidechain_deposit(
+        &self,
+        sidechain_number: u8,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<serde_json::Value, Error> {
+        Ok(self
+            .client
+            .createsidechaindeposit(
+                sidechain_number,
+                deposit_address,
+                AmountBtc(amount),
+                AmountBtc(fee),
+            )
+            .await?)
+    }
+}
+
+/// Caches blocks that are buried deep enough to never be reorged out.
+///
+/// Only blocks that already have a `nextblockhash` are cached, and the
+/// `confirmations` of a cached block are recomputed from the current block
+/// count, so cached blocks never go stale. Everything else is forwarded to
+/// the wrapped backend.
+pub struct CachingBackend {
+    backend: Arc<dyn MainchainBackend>,
+    min_confirmations: usize,
+    blocks: Mutex<HashMap<bitcoin::BlockHash, Block>>,
+}
+
+impl CachingBackend {
+    pub fn new(backend: Arc<dyn MainchainBackend>, min_confirmations: usize) -> Self {
+        Self {
+            backend,
+            min_confirmations,
+            blocks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn blocks(&self) -> std::sync::MutexGuard<'_, HashMap<bitcoin::BlockHash, Block>> {
+        self.blocks.lock().expect("block cache lock poisoned")
+    }
+}
+
+#[async_trait]
+impl MainchainBackend for CachingBackend {
+    async fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, Error> {
+        self.backend.get_best_block_hash().await
+    }
+
+    async fn get_block_count(&self) -> Result<usize, Error> {
+        self.backend.get_block_count().await
+    }
+
+    async fn get_block(&self, block_hash: &bitcoin::BlockHash) -> Result<Block, Error> {
+        let cached = self.blocks().get(block_hash).cloned();
+        if let Some(mut block) = cached {
+            let block_count = self.backend.get_block_count().await?;
+            block.confirmations = (block_count + 1).saturating_sub(block.height);
+            return Ok(block);
+        }
+        let block = self.backend.get_block(block_hash).await?;
+        if block.confirmations >= self.min_confirmations && block.nextblockhash.is_some() {
+            self.blocks().insert(*block_hash, block.clone());
+        }
+        Ok(block)
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<bitcoin::BlockHash, Error> {
+        self.backend.get_block_hash(height).await
+    }
+
+    async fn get_raw_transaction(&self, txid: &bitcoin::Txid) -> Result<String, Error> {
+        self.backend.get_raw_transaction(txid).await
+    }
+
+    async fn get_raw_block(&self, block_hash: &bitcoin::BlockHash) -> Result<String, Error> {
+        self.backend.get_raw_block(block_hash).await
+    }
+
+    async fn verify_bmm(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+    ) -> Result<serde_json::Value, Error> {
+        self.backend
+            .verify_bmm(block_hash, critical_hash, sidechain_number)
+            .await
+    }
+
+    async fn create_bmm_critical_data_tx(
+        &self,
+        amount: bitcoin::Amount,
+        height: u32,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+        prev_bytes: &str,
+    ) -> Result<serde_json::Value, Error> {
+        self.backend
+            .create_bmm_critical_data_tx(
+                amount,
+                height,
+                critical_hash,
+                sidechain_number,
+                prev_bytes,
+            )
+            .await
+    }
+
+    async fn list_sidechain_deposits_by_block(
+        &self,
+        sidechain_number: u8,
+        end: Option<bitcoin::BlockHash>,
+        start: Option<bitcoin::BlockHash>,
+    ) -> Result<Vec<Deposit>, Error> {
+        self.backend
+            .list_sidechain_deposits_by_block(sidechain_number, end, start)
+            .await
+    }
+
+    async fn list_withdrawal_status(
+        &self,
+        sidechain_number: u8,
+    ) -> Result<Vec<WithdrawalStatus>, Error> {
+        self.backend.list_withdrawal_status(sidechain_number).await
+    }
+
+    async fn list_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, Error> {
+        self.backend.list_spent_withdrawals().await
+    }
+
+    async fn list_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, Error> {
+        self.backend.list_failed_withdrawals().await
+    }
+
+    async fn receive_withdrawal_bundle(
+        &self,
+        sidechain_number: u8,
+        raw_transaction: &str,
+    ) -> Result<serde_json::Value, Error> {
+        self.backend
+            .receive_withdrawal_bundle(sidechain_number, raw_transaction)
+            .await
+    }
+
+    async fn generate(&self, num: u32) -> Result<serde_json::Value, Error> {
+        self.backend.generate(num).await
+    }
+
+    async fn create_sidechain_deposit(
+        &self,
+        sidechain_number: u8,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<serde_json::Value, Error> {
+        self.backend
+            .create_sidechain_deposit(sidechain_number, deposit_address, amount, fee)
+            .await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivechain::mock::MockMainchain;
+
+    #[test]
+    fn caching_backend_does_not_cache_the_tip() {
+        let mainchain = MockMainchain::new();
+        mainchain.mine(2);
+        let backend = CachingBackend::new(Arc::new(mainchain.clone()), 0);
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let tip = mainchain.get_tip();
+            let block = backend.get_block(&tip).await.unwrap();
+            assert_eq!(block.nextblockhash, None);
+            let next = mainchain.mine(1)[0];
+            let block = backend.get_block(&tip).await.unwrap();
+            assert_eq!(block.nextblockhash, Some(next));
+            assert_eq!(block.confirmations, 2);
+            // Cached now, confirmations still follow the tip.
+            mainchain.mine(1);
+            let block = backend.get_block(&tip).await.unwrap();
+            assert_eq!(block.nextblockhash, Some(next));
+            assert_eq!(block.confirmations, 3);
+        });
+    }
+}
@@ -0,0 +1,124 @@
+//! BMM requests as they appear in mainchain blocks.
+use crate::types::bitcoin;
+use bitcoin::hashes::Hash as _;
+
+/// A BIP301 BMM request. The request is committed to in an `OP_RETURN`
+/// output, and the bid is paid to an anyone can spend `OP_TRUE` output that
+/// the miner of the including block collects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BmmRequest {
+    pub txid: bitcoin::Txid,
+    pub sidechain_number: u8,
+    pub critical_hash: bitcoin::BlockHash,
+    /// Last 4 bytes of the hash of the mainchain block the request builds
+    /// on, see `prevbytes`.
+    pub prevbytes: [u8; 4],
+    /// Bid in sats.
+    pub amount: u64,
+}
+
+impl BmmRequest {
+    const TAG: [u8; 3] = [0x00, 0xbf, 0x00];
+    const DATA_LEN: usize = Self::TAG.len() + 1 + 32 + 4;
+
+    /// `OP_RETURN` output script committing to the request.
+    pub fn script_pubkey(
+        sidechain_number: u8,
+        critical_hash: &bitcoin::BlockHash,
+        prevbytes: &[u8; 4],
+    ) -> bitcoin::ScriptBuf {
+        let script = [
+            &[
+                bitcoin::blockdata::opcodes::all::OP_RETURN.to_u8(),
+                Self::DATA_LEN as u8,
+            ][..],
+            &Self::TAG,
+            &[sidechain_number],
+            &critical_hash.to_byte_array(),
+            prevbytes,
+        ]
+        .concat();
+        bitcoin::ScriptBuf::from(script)
+    }
+
+    /// Output script the bid is paid to.
+    pub fn bid_script_pubkey() -> bitcoin::ScriptBuf {
+        bitcoin::ScriptBuf::from(vec![bitcoin::blockdata::opcodes::all::OP_PUSHNUM_1.to_u8()])
+    }
+
+    pub fn from_transaction(transaction: &bitcoin::Transaction) -> Option<Self> {
+        let data = transaction.output.iter().find_map(|txout| {
+            // OP_RETURN followed by a single direct push.
+            match txout.script_pubkey.as_bytes() {
+                [0x6a, len, data @ ..]
+                    if *len as usize == Self::DATA_LEN
+                        && data.len() == Self::DATA_LEN
+                        && data.starts_with(&Self::TAG) =>
+                {
+                    Some(&data[Self::TAG.len()..])
+                }
+                _ => None,
+            }
+        })?;
+        let bid_script_pubkey = Self::bid_script_pubkey();
+        let amount = transaction
+            .output
+            .iter()
+            .filter(|txout| txout.script_pubkey == bid_script_pubkey)
+            .map(|txout| txout.value)
+            .sum();
+        Some(Self {
+            txid: transaction.txid(),
+            sidechain_number: data[0],
+            critical_hash: bitcoin::BlockHash::from_slice(&data[1..33]).ok()?,
+            prevbytes: data[33..37].try_into().ok()?,
+            amount,
+        })
+    }
+}
+
+/// Prevbytes of a BMM request building on `block_hash`, the last 8 hex
+/// digits of the block hash.
+pub fn prevbytes(block_hash: &bitcoin::BlockHash) -> [u8; 4] {
+    let block_hash = block_hash.to_string();
+    let mut prevbytes = [0; 4];
+    hex::decode_to_slice(&block_hash[block_hash.len() - 8..], &mut prevbytes)
+        .expect("block hash is hex");
+    prevbytes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bmm_request_round_trip() {
+        let critical_hash = bitcoin::BlockHash::from_byte_array([7; 32]);
+        let prev_main_hash = bitcoin::BlockHash::from_byte_array([9; 32]);
+        let transaction = bitcoin::Transaction {
+            version: 2,
+            lock_time: bitcoin::blockdata::locktime::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn::default()],
+            output: vec![
+                bitcoin::TxOut {
+                    value: 0,
+                    script_pubkey: BmmRequest::script_pubkey(
+                        3,
+                        &critical_hash,
+                        &prevbytes(&prev_main_hash),
+                    ),
+                },
+                bitcoin::TxOut {
+                    value: 1000,
+                    script_pubkey: BmmRequest::bid_script_pubkey(),
+                },
+            ],
+        };
+        let request = BmmRequest::from_transaction(&transaction).unwrap();
+        assert_eq!(request.sidechain_number, 3);
+        assert_eq!(request.critical_hash, critical_hash);
+        assert_eq!(request.prevbytes, [9; 4]);
+        assert_eq!(request.amount, 1000);
+        assert_eq!(request.txid, transaction.txid());
+    }
+}
@@ -2,27 +2,27 @@ use bitcoin::amount::serde::SerdeAmount;
 use jsonrpsee::proc_macros::rpc;
 use std::ops::{Deref, DerefMut};
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct WithdrawalStatus {
-    hash: bitcoin::Txid,
-    nblocksleft: usize,
-    nworkscore: usize,
+    pub hash: bitcoin::Txid,
+    pub nblocksleft: usize,
+    pub nworkscore: usize,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct SpentWithdrawal {
     pub nsidechain: u8,
     pub hash: bitcoin::Txid,
     pub hashblock: bitcoin::BlockHash,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct FailedWithdrawal {
     pub nsidechain: u8,
     pub hash: bitcoin::Txid,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "snake_case")]
 pub enum Vote {
     Upvote,
@@ -30,7 +30,7 @@ pub enum Vote {
     Downvote,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Block {
     pub hash: bitcoin::BlockHash,
@@ -53,7 +53,7 @@ pub struct Block {
     pub nextblockhash: Option<bitcoin::BlockHash>,
 }
 
-#[derive(Debug, serde::Serialize, serde::Deserialize)]
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Deposit {
     pub hashblock: bitcoin::BlockHash,
@@ -67,7 +67,6 @@ pub struct Deposit {
 pub trait Main {
     #[method(name = "stop")]
     async fn stop(&self) -> Result<String, jsonrpsee::core::Error>;
-    // FIXME: Define a "Deposit Address" type.
     #[method(name = "listwithdrawalstatus")]
     async fn listwithdrawalstatus(
         &self,
@@ -79,6 +78,19 @@ pub trait Main {
     async fn listfailedwithdrawals(&self) -> Result<Vec<FailedWithdrawal>, jsonrpsee::core::Error>;
     #[method(name = "getblockcount")]
     async fn getblockcount(&self) -> Result<usize, jsonrpsee::core::Error>;
+    #[method(name = "getblockhash")]
+    async fn getblockhash(
+        &self,
+        height: usize,
+    ) -> Result<bitcoin::BlockHash, jsonrpsee::core::Error>;
+    // Requires -txindex on mainchain for transactions not in the wallet or
+    // mempool.
+    #[method(name = "getrawtransaction")]
+    async fn getrawtransaction(
+        &self,
+        txid: &bitcoin::Txid,
+        verbose: bool,
+    ) -> Result<String, jsonrpsee::core::Error>;
     #[method(name = "getbestblockhash")]
     async fn getbestblockhash(&self) -> Result<bitcoin::BlockHash, jsonrpsee::core::Error>;
     #[method(name = "getblock")]
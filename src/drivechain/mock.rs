@@ -0,0 +1,1496 @@
+//! In-process mock of a drivechain enabled mainchain node.
+//!
+//! Implements the subset of the mainchain JSON-RPC API used by `Drivechain`
+//! and `Miner`, with scriptable blocks, deposits, withdrawal bundle outcomes
+//! and reorgs, so sidechains can be tested end to end without bitcoind.
+use super::backend::MainchainBackend;
+use super::client::{
+    AmountBtc, Block, Deposit, FailedWithdrawal, SpentWithdrawal, WithdrawalStatus,
+};
+use crate::types::bitcoin;
+use bitcoin::blockdata::{opcodes, script};
+use bitcoin::consensus::{Decodable, Encodable};
+use bitcoin::hashes::Hash as _;
+use jsonrpsee::core::{async_trait, RpcResult};
+use jsonrpsee::proc_macros::rpc;
+use jsonrpsee::server::{ServerBuilder, ServerHandle};
+use jsonrpsee::types::error::CallError;
+use jsonrpsee::types::ErrorObject;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+/// Timestamp of the genesis block, every next block is 10 minutes later.
+const GENESIS_TIME: u32 = 1_600_000_000;
+const BLOCK_INTERVAL: u32 = 600;
+/// Number of blocks a withdrawal bundle stays pending before it would fail.
+const WITHDRAWAL_BUNDLE_MAX_AGE: u32 = 26_300;
+
+#[rpc(server)]
+pub trait MockMain {
+    #[method(name = "stop")]
+    fn stop(&self) -> RpcResult<String>;
+    #[method(name = "listwithdrawalstatus")]
+    fn listwithdrawalstatus(&self, nsidechain: u8) -> RpcResult<Vec<WithdrawalStatus>>;
+    #[method(name = "listspentwithdrawals")]
+    fn listspentwithdrawals(&self) -> RpcResult<Vec<SpentWithdrawal>>;
+    #[method(name = "listfailedwithdrawals")]
+    fn listfailedwithdrawals(&self) -> RpcResult<Vec<FailedWithdrawal>>;
+    #[method(name = "getblockcount")]
+    fn getblockcount(&self) -> RpcResult<usize>;
+    #[method(name = "getbestblockhash")]
+    fn getbestblockhash(&self) -> RpcResult<bitcoin::BlockHash>;
+    #[method(name = "getblockhash")]
+    fn getblockhash(&self, height: usize) -> RpcResult<bitcoin::BlockHash>;
+    #[method(name = "getrawtransaction")]
+    fn getrawtransaction(&self, txid: bitcoin::Txid, verbose: Option<bool>) -> RpcResult<String>;
+    #[method(name = "getblock")]
+    /// Verbosity 0 returns the hex encoded block, anything else a `Block`.
+    fn getblock(
+        &self,
+        blockhash: bitcoin::BlockHash,
+        verbosity: Option<usize>,
+    ) -> RpcResult<serde_json::Value>;
+    #[method(name = "createbmmcriticaldatatx")]
+    fn createbmmcriticaldatatx(
+        &self,
+        amount: AmountBtc,
+        height: u32,
+        criticalhash: bitcoin::BlockHash,
+        nsidechain: u8,
+        prevbytes: String,
+    ) -> RpcResult<serde_json::Value>;
+    #[method(name = "verifybmm")]
+    fn verifybmm(
+        &self,
+        blockhash: bitcoin::BlockHash,
+        criticalhash: bitcoin::BlockHash,
+        nsidechain: u8,
+    ) -> RpcResult<serde_json::Value>;
+    #[method(name = "listsidechaindepositsbyblock")]
+    fn listsidechaindepositsbyblock(
+        &self,
+        nsidechain: u8,
+        end_blockhash: Option<bitcoin::BlockHash>,
+        start_blockhash: Option<bitcoin::BlockHash>,
+    ) -> RpcResult<Vec<Deposit>>;
+    #[method(name = "receivewithdrawalbundle")]
+    fn receivewithdrawalbundle(
+        &self,
+        nsidechain: u8,
+        rawtx: String,
+    ) -> RpcResult<serde_json::Value>;
+    #[method(name = "generate")]
+    fn generate(&self, num: u32) -> RpcResult<serde_json::Value>;
+    #[method(name = "getnewaddress")]
+    fn getnewaddress(
+        &self,
+        account: String,
+        address_type: String,
+    ) -> RpcResult<bitcoin::Address<bitcoin::address::NetworkUnchecked>>;
+    #[method(name = "createsidechaindeposit")]
+    fn createsidechaindeposit(
+        &self,
+        nsidechain: u8,
+        depositaddress: String,
+        amount: AmountBtc,
+        fee: AmountBtc,
+    ) -> RpcResult<serde_json::Value>;
+}
+
+/// Outcome of a withdrawal bundle, as seen by the mock mainchain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MockBundleStatus {
+    Pending,
+    Confirmed(bitcoin::BlockHash),
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+enum MockTransactionKind {
+    Deposit {
+        nsidechain: u8,
+        strdest: String,
+    },
+    Payout {
+        nsidechain: u8,
+        bundle: bitcoin::Txid,
+    },
+}
+
+#[derive(Debug, Clone)]
+struct MockTransaction {
+    transaction: bitcoin::Transaction,
+    kind: MockTransactionKind,
+}
+
+impl MockTransaction {
+    fn nsidechain(&self) -> u8 {
+        match self.kind {
+            MockTransactionKind::Deposit { nsidechain, .. }
+            | MockTransactionKind::Payout { nsidechain, .. } => nsidechain,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct BmmRequest {
+    transaction: bitcoin::Transaction,
+    critical_hash: bitcoin::BlockHash,
+    prevbytes: String,
+}
+
+#[derive(Debug, Clone)]
+struct MockBlock {
+    hash: bitcoin::BlockHash,
+    prev: Option<bitcoin::BlockHash>,
+    height: u32,
+    // nsidechain -> BMM request included in this block.
+    bmm: HashMap<u8, BmmRequest>,
+    transactions: Vec<MockTransaction>,
+}
+
+#[derive(Debug, Clone)]
+struct MockBundle {
+    nsidechain: u8,
+    transaction: bitcoin::Transaction,
+    received_height: u32,
+    failed: bool,
+}
+
+#[derive(Debug)]
+struct MockState {
+    blocks: HashMap<bitcoin::BlockHash, MockBlock>,
+    // Active chain, indexed by height.
+    chain: Vec<bitcoin::BlockHash>,
+    mempool: Vec<MockTransaction>,
+    bmm_requests: HashMap<u8, BmmRequest>,
+    // Critical transaction index pair of every sidechain, including mempool
+    // transactions.
+    ctips: HashMap<u8, (bitcoin::OutPoint, u64)>,
+    bundles: HashMap<bitcoin::Txid, MockBundle>,
+    // Every transaction ever created, as if mainchain ran with -txindex.
+    transactions: HashMap<bitcoin::Txid, bitcoin::Transaction>,
+    nonce: u64,
+}
+
+impl MockState {
+    fn next_hash(&mut self) -> [u8; 32] {
+        self.nonce += 1;
+        crate::types::hash(&self.nonce)
+    }
+
+    fn tip(&self) -> &MockBlock {
+        &self.blocks[self
+            .chain
+            .last()
+            .expect("mock chain always has a genesis block")]
+    }
+
+    fn is_active(&self, block: &MockBlock) -> bool {
+        self.chain.get(block.height as usize) == Some(&block.hash)
+    }
+
+    fn get_active_block(&self, hash: &bitcoin::BlockHash) -> RpcResult<&MockBlock> {
+        match self.blocks.get(hash) {
+            Some(block) if self.is_active(block) => Ok(block),
+            Some(_) => Err(not_found_error(format!(
+                "block {hash} is not in the active chain"
+            ))),
+            None => Err(not_found_error(format!("block {hash} not found"))),
+        }
+    }
+
+    fn mine_block(&mut self) -> bitcoin::BlockHash {
+        let prev = self.tip().hash;
+        let height = self.tip().height + 1;
+        let prevbytes = prev.to_string();
+        let prevbytes = &prevbytes[prevbytes.len() - 8..];
+        let bmm = std::mem::take(&mut self.bmm_requests)
+            .into_iter()
+            .filter(|(_, request)| request.prevbytes == prevbytes)
+            .collect();
+        let transactions = std::mem::take(&mut self.mempool);
+        let hash = bitcoin::BlockHash::from_byte_array(self.next_hash());
+        let block = MockBlock {
+            hash,
+            prev: Some(prev),
+            height,
+            bmm,
+            transactions,
+        };
+        self.blocks.insert(hash, block);
+        self.chain.push(hash);
+        hash
+    }
+
+    // Locate the block that includes the payout of `bundle`, if any.
+    fn get_payout_block(&self, bundle: &bitcoin::Txid) -> Option<bitcoin::BlockHash> {
+        self.chain.iter().find_map(|hash| {
+            self.blocks[hash]
+                .transactions
+                .iter()
+                .any(|transaction| match &transaction.kind {
+                    MockTransactionKind::Payout { bundle: txid, .. } => txid == bundle,
+                    MockTransactionKind::Deposit { .. } => false,
+                })
+                .then_some(*hash)
+        })
+    }
+
+    fn get_bundle_status(&self, txid: &bitcoin::Txid) -> Option<MockBundleStatus> {
+        let bundle = self.bundles.get(txid)?;
+        if bundle.failed {
+            return Some(MockBundleStatus::Failed);
+        }
+        match self.get_payout_block(txid) {
+            Some(block_hash) => Some(MockBundleStatus::Confirmed(block_hash)),
+            None => Some(MockBundleStatus::Pending),
+        }
+    }
+
+    // Recompute CTIPs from the active chain, dropping mempool transactions
+    // that no longer spend the CTIP of their sidechain.
+    fn reset_ctips(&mut self) {
+        self.ctips.clear();
+        for hash in &self.chain {
+            for transaction in &self.blocks[hash].transactions {
+                let txid = transaction.transaction.txid();
+                let value = transaction.transaction.output[0].value;
+                self.ctips.insert(
+                    transaction.nsidechain(),
+                    (bitcoin::OutPoint { txid, vout: 0 }, value),
+                );
+            }
+        }
+        for transaction in std::mem::take(&mut self.mempool) {
+            let nsidechain = transaction.nsidechain();
+            let spends_ctip = match self.ctips.get(&nsidechain) {
+                Some((ctip, _)) => transaction.transaction.input[0].previous_output == *ctip,
+                // Only the funding input.
+                None => transaction.transaction.input.len() == 1,
+            };
+            if !spends_ctip {
+                continue;
+            }
+            let txid = transaction.transaction.txid();
+            let value = transaction.transaction.output[0].value;
+            self.ctips
+                .insert(nsidechain, (bitcoin::OutPoint { txid, vout: 0 }, value));
+            self.mempool.push(transaction);
+        }
+    }
+
+    // Create a transaction spending the sidechain CTIP and a new `funding`
+    // output, and put it in the mempool. `outputs` follow the new CTIP output.
+    fn spend_ctip(
+        &mut self,
+        nsidechain: u8,
+        value: u64,
+        funding: u64,
+        outputs: Vec<bitcoin::TxOut>,
+        kind: MockTransactionKind,
+    ) -> bitcoin::Txid {
+        let mut input = vec![];
+        if let Some((ctip, _)) = self.ctips.get(&nsidechain) {
+            input.push(bitcoin::TxIn {
+                previous_output: *ctip,
+                ..bitcoin::TxIn::default()
+            });
+        }
+        // Every transaction gets a unique funding input, so txids never repeat.
+        let funding_transaction = bitcoin::Transaction {
+            version: 2,
+            lock_time: bitcoin::blockdata::locktime::absolute::LockTime::ZERO,
+            input: vec![bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint {
+                    txid: bitcoin::Txid::from_byte_array(self.next_hash()),
+                    vout: 0,
+                },
+                ..bitcoin::TxIn::default()
+            }],
+            output: vec![bitcoin::TxOut {
+                value: funding,
+                script_pubkey: bitcoin::ScriptBuf::new(),
+            }],
+        };
+        let funding_txid = funding_transaction.txid();
+        self.transactions.insert(funding_txid, funding_transaction);
+        input.push(bitcoin::TxIn {
+            previous_output: bitcoin::OutPoint {
+                txid: funding_txid,
+                vout: 0,
+            },
+            ..bitcoin::TxIn::default()
+        });
+        let ctip_txout = bitcoin::TxOut {
+            value,
+            script_pubkey: ctip_script(nsidechain),
+        };
+        let transaction = bitcoin::Transaction {
+            version: 2,
+            lock_time: bitcoin::blockdata::locktime::absolute::LockTime::ZERO,
+            input,
+            output: [vec![ctip_txout], outputs].concat(),
+        };
+        let txid = transaction.txid();
+        self.ctips
+            .insert(nsidechain, (bitcoin::OutPoint { txid, vout: 0 }, value));
+        self.transactions.insert(txid, transaction.clone());
+        self.mempool.push(MockTransaction { transaction, kind });
+        txid
+    }
+
+    fn to_raw_block(&self, block: &MockBlock) -> RpcResult<String> {
+        let header = bitcoin::block::Header {
+            version: bitcoin::block::Version::from_consensus(0x20000000),
+            prev_blockhash: block.prev.unwrap_or_else(bitcoin::BlockHash::all_zeros),
+            merkle_root: bitcoin::hash_types::TxMerkleNode::all_zeros(),
+            time: GENESIS_TIME + block.height * BLOCK_INTERVAL,
+            bits: bitcoin::CompactTarget::from_consensus(0x207fffff),
+            nonce: 0,
+        };
+        let txdata = block
+            .transactions
+            .iter()
+            .map(|transaction| transaction.transaction.clone())
+            .chain(
+                block
+                    .bmm
+                    .values()
+                    .map(|request| request.transaction.clone()),
+            )
+            .collect();
+        let mut rawblock = vec![];
+        bitcoin::Block { header, txdata }
+            .consensus_encode(&mut rawblock)
+            .map_err(|err| rpc_error(err.to_string()))?;
+        Ok(hex::encode(rawblock))
+    }
+
+    fn to_rpc_block(&self, block: &MockBlock) -> Block {
+        let active = self.is_active(block);
+        let tip_height = self.tip().height;
+        let nextblockhash = if active {
+            self.chain.get(block.height as usize + 1).copied()
+        } else {
+            None
+        };
+        let time = GENESIS_TIME + block.height * BLOCK_INTERVAL;
+        Block {
+            hash: block.hash,
+            confirmations: if active {
+                (tip_height - block.height + 1) as usize
+            } else {
+                0
+            },
+            strippedsize: 0,
+            size: 0,
+            weight: 0,
+            height: block.height as usize,
+            version: 0x20000000,
+            version_hex: "20000000".into(),
+            merkleroot: bitcoin::hash_types::TxMerkleNode::all_zeros(),
+            tx: block
+                .transactions
+                .iter()
+                .map(|transaction| transaction.transaction.txid())
+                .collect(),
+            time,
+            mediantime: time,
+            nonce: 0,
+            bits: "207fffff".into(),
+            difficulty: 0.0,
+            chainwork: format!("{:064x}", block.height + 1),
+            previousblockhash: block.prev,
+            nextblockhash,
+        }
+    }
+}
+
+/// Mock mainchain. Clones share the same chain.
+#[derive(Debug, Clone)]
+pub struct MockMainchain {
+    state: Arc<Mutex<MockState>>,
+}
+
+impl Default for MockMainchain {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MockMainchain {
+    pub fn new() -> Self {
+        let genesis = MockBlock {
+            hash: bitcoin::BlockHash::from_byte_array(crate::types::hash(&0u64)),
+            prev: None,
+            height: 0,
+            bmm: HashMap::new(),
+            transactions: vec![],
+        };
+        let state = MockState {
+            chain: vec![genesis.hash],
+            blocks: HashMap::from([(genesis.hash, genesis)]),
+            mempool: vec![],
+            bmm_requests: HashMap::new(),
+            ctips: HashMap::new(),
+            bundles: HashMap::new(),
+            transactions: HashMap::new(),
+            nonce: 0,
+        };
+        Self {
+            state: Arc::new(Mutex::new(state)),
+        }
+    }
+
+    /// Serve the mainchain JSON-RPC API on `addr`, use port 0 to pick any
+    /// free port. Returns the actual address, pass it to `Drivechain::new`.
+    pub async fn start(
+        &self,
+        addr: SocketAddr,
+    ) -> Result<(SocketAddr, ServerHandle), jsonrpsee::core::Error> {
+        let server = ServerBuilder::default().build(addr).await?;
+        let addr = server.local_addr()?;
+        let handle = server.start(self.clone().into_rpc());
+        Ok((addr, handle))
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, MockState> {
+        self.state
+            .lock()
+            .expect("mock mainchain state lock poisoned")
+    }
+
+    pub fn get_tip(&self) -> bitcoin::BlockHash {
+        self.state().tip().hash
+    }
+
+    pub fn get_height(&self) -> u32 {
+        self.state().tip().height
+    }
+
+    /// Mine `num` blocks including all pending BMM requests that build on
+    /// the current tip, deposits and withdrawal bundle payouts.
+    pub fn mine(&self, num: u32) -> Vec<bitcoin::BlockHash> {
+        let mut state = self.state();
+        (0..num).map(|_| state.mine_block()).collect()
+    }
+
+    /// Disconnect the top `depth` blocks, as if mainchain switched to a
+    /// chain without them. CTIPs roll back to the new tip, the transactions
+    /// and BMM requests of the disconnected blocks are dropped, and so are
+    /// mempool transactions spending a dropped CTIP. The disconnected blocks
+    /// stay known but inactive.
+    pub fn reorg(&self, depth: u32) -> Vec<bitcoin::BlockHash> {
+        let mut state = self.state();
+        let depth = std::cmp::min(depth as usize, state.chain.len() - 1);
+        let disconnected = state.chain.split_off(state.chain.len() - depth);
+        state.reset_ctips();
+        disconnected
+    }
+
+    /// Create a deposit of `amount` sats to `strdest` on sidechain
+    /// `nsidechain`, it is included in the next mined block.
+    pub fn create_deposit(&self, nsidechain: u8, strdest: &str, amount: u64) -> bitcoin::Txid {
+        let mut state = self.state();
+        let ctip_value = state.ctips.get(&nsidechain).map_or(0, |(_, value)| *value);
+        let kind = MockTransactionKind::Deposit {
+            nsidechain,
+            strdest: strdest.into(),
+        };
+        state.spend_ctip(nsidechain, ctip_value + amount, amount, vec![], kind)
+    }
+
+    /// Pay out a received withdrawal bundle in the next mined block.
+    pub fn confirm_withdrawal_bundle(&self, bundle: &bitcoin::Txid) -> Option<bitcoin::Txid> {
+        let outputs = self.state().bundles.get(bundle)?.transaction.output.clone();
+        self.pay_out_withdrawal_bundle(bundle, outputs)
+    }
+
+    /// Pay out a received withdrawal bundle with `outputs` instead of the
+    /// bundle outputs in the next mined block.
+    pub fn pay_out_withdrawal_bundle(
+        &self,
+        bundle: &bitcoin::Txid,
+        outputs: Vec<bitcoin::TxOut>,
+    ) -> Option<bitcoin::Txid> {
+        let mut state = self.state();
+        let nsidechain = state.bundles.get(bundle)?.nsidechain;
+        let payout: u64 = outputs.iter().map(|txout| txout.value).sum();
+        let ctip_value = state.ctips.get(&nsidechain).map_or(0, |(_, value)| *value);
+        let kind = MockTransactionKind::Payout {
+            nsidechain,
+            bundle: *bundle,
+        };
+        let txid = state.spend_ctip(
+            nsidechain,
+            ctip_value.saturating_sub(payout),
+            0,
+            outputs,
+            kind,
+        );
+        Some(txid)
+    }
+
+    /// Mark a received withdrawal bundle as failed.
+    pub fn fail_withdrawal_bundle(&self, bundle: &bitcoin::Txid) -> bool {
+        match self.state().bundles.get_mut(bundle) {
+            Some(bundle) => {
+                bundle.failed = true;
+                true
+            }
+            None => false,
+        }
+    }
+
+    pub fn get_withdrawal_bundle_status(&self, bundle: &bitcoin::Txid) -> Option<MockBundleStatus> {
+        self.state().get_bundle_status(bundle)
+    }
+}
+
+impl MockMainServer for MockMainchain {
+    fn stop(&self) -> RpcResult<String> {
+        Ok("mock mainchain stopping".into())
+    }
+
+    fn listwithdrawalstatus(&self, nsidechain: u8) -> RpcResult<Vec<WithdrawalStatus>> {
+        let state = self.state();
+        let tip_height = state.tip().height;
+        let mut statuses = vec![];
+        for (hash, bundle) in &state.bundles {
+            if bundle.nsidechain != nsidechain
+                || state.get_bundle_status(hash) != Some(MockBundleStatus::Pending)
+            {
+                continue;
+            }
+            let age = tip_height.saturating_sub(bundle.received_height);
+            statuses.push(WithdrawalStatus {
+                hash: *hash,
+                nblocksleft: WITHDRAWAL_BUNDLE_MAX_AGE.saturating_sub(age) as usize,
+                nworkscore: age as usize,
+            });
+        }
+        Ok(statuses)
+    }
+
+    fn listspentwithdrawals(&self) -> RpcResult<Vec<SpentWithdrawal>> {
+        let state = self.state();
+        let mut spent = vec![];
+        for (hash, bundle) in &state.bundles {
+            if let Some(MockBundleStatus::Confirmed(hashblock)) = state.get_bundle_status(hash) {
+                spent.push(SpentWithdrawal {
+                    nsidechain: bundle.nsidechain,
+                    hash: *hash,
+                    hashblock,
+                });
+            }
+        }
+        Ok(spent)
+    }
+
+    fn listfailedwithdrawals(&self) -> RpcResult<Vec<FailedWithdrawal>> {
+        let state = self.state();
+        let failed = state
+            .bundles
+            .iter()
+            .filter(|(_, bundle)| bundle.failed)
+            .map(|(hash, bundle)| FailedWithdrawal {
+                nsidechain: bundle.nsidechain,
+                hash: *hash,
+            })
+            .collect();
+        Ok(failed)
+    }
+
+    fn getblockcount(&self) -> RpcResult<usize> {
+        Ok(self.get_height() as usize)
+    }
+
+    fn getbestblockhash(&self) -> RpcResult<bitcoin::BlockHash> {
+        Ok(self.get_tip())
+    }
+
+    fn getblockhash(&self, height: usize) -> RpcResult<bitcoin::BlockHash> {
+        self.state()
+            .chain
+            .get(height)
+            .copied()
+            .ok_or_else(|| rpc_error(format!("block height {height} out of range")))
+    }
+
+    fn getrawtransaction(&self, txid: bitcoin::Txid, _verbose: Option<bool>) -> RpcResult<String> {
+        let state = self.state();
+        let transaction = state
+            .transactions
+            .get(&txid)
+            .ok_or_else(|| not_found_error(format!("transaction {txid} not found")))?;
+        let mut rawtx = vec![];
+        transaction
+            .consensus_encode(&mut rawtx)
+            .map_err(|err| rpc_error(err.to_string()))?;
+        Ok(hex::encode(rawtx))
+    }
+
+    fn getblock(
+        &self,
+        blockhash: bitcoin::BlockHash,
+        verbosity: Option<usize>,
+    ) -> RpcResult<serde_json::Value> {
+        let state = self.state();
+        let block = state
+            .blocks
+            .get(&blockhash)
+            .ok_or_else(|| not_found_error(format!("block {blockhash} not found")))?;
+        if verbosity == Some(0) {
+            return Ok(serde_json::Value::String(state.to_raw_block(block)?));
+        }
+        serde_json::to_value(state.to_rpc_block(block)).map_err(|err| rpc_error(err.to_string()))
+    }
+
+    fn createbmmcriticaldatatx(
+        &self,
+        amount: AmountBtc,
+        height: u32,
+        criticalhash: bitcoin::BlockHash,
+        nsidechain: u8,
+        prevbytes: String,
+    ) -> RpcResult<serde_json::Value> {
+        let mut state = self.state();
+        let tip = state.tip().hash.to_string();
+        if prevbytes != tip[tip.len() - 8..] {
+            return Err(rpc_error(format!(
+                "prevbytes {prevbytes} don't match mainchain tip {tip}"
+            )));
+        }
+        let mut prevbytes_array = [0; 4];
+        hex::decode_to_slice(&prevbytes, &mut prevbytes_array)
+            .map_err(|err| rpc_error(err.to_string()))?;
+        let transaction = bitcoin::Transaction {
+            version: 2,
+            lock_time: bitcoin::blockdata::locktime::absolute::LockTime::from_consensus(height),
+            // A unique input, so txids never repeat.
+            input: vec![bitcoin::TxIn {
+                previous_output: bitcoin::OutPoint {
+                    txid: bitcoin::Txid::from_byte_array(state.next_hash()),
+                    vout: 0,
+                },
+                ..bitcoin::TxIn::default()
+            }],
+            output: vec![
+                bitcoin::TxOut {
+                    value: 0,
+                    script_pubkey: super::BmmRequest::script_pubkey(
+                        nsidechain,
+                        &criticalhash,
+                        &prevbytes_array,
+                    ),
+                },
+                bitcoin::TxOut {
+                    value: amount.to_sat(),
+                    script_pubkey: super::BmmRequest::bid_script_pubkey(),
+                },
+            ],
+        };
+        let txid = transaction.txid();
+        state.transactions.insert(txid, transaction.clone());
+        let request = BmmRequest {
+            transaction,
+            critical_hash: criticalhash,
+            prevbytes,
+        };
+        state.bmm_requests.insert(nsidechain, request);
+        Ok(serde_json::json!({ "txid": { "txid": txid.to_string() } }))
+    }
+
+    fn verifybmm(
+        &self,
+        blockhash: bitcoin::BlockHash,
+        criticalhash: bitcoin::BlockHash,
+        nsidechain: u8,
+    ) -> RpcResult<serde_json::Value> {
+        let state = self.state();
+        let block = state.get_active_block(&blockhash)?;
+        match block.bmm.get(&nsidechain) {
+            Some(request) if request.critical_hash == criticalhash => {
+                Ok(serde_json::json!({ "txid": request.transaction.txid().to_string() }))
+            }
+            _ => Err(rpc_error(format!(
+                "h* {criticalhash} not found in block {blockhash}"
+            ))),
+        }
+    }
+
+    fn listsidechaindepositsbyblock(
+        &self,
+        nsidechain: u8,
+        end_blockhash: Option<bitcoin::BlockHash>,
+        start_blockhash: Option<bitcoin::BlockHash>,
+    ) -> RpcResult<Vec<Deposit>> {
+        let state = self.state();
+        let end = match end_blockhash {
+            Some(end) => state.get_active_block(&end)?.height,
+            None => state.tip().height,
+        };
+        let start = match start_blockhash {
+            Some(start) => state.get_active_block(&start)?.height,
+            None => 0,
+        };
+        let mut deposits = vec![];
+        for hash in &state.chain[start as usize..=end as usize] {
+            for transaction in &state.blocks[hash].transactions {
+                let MockTransactionKind::Deposit {
+                    nsidechain: deposit_sidechain,
+                    strdest,
+                } = &transaction.kind
+                else {
+                    continue;
+                };
+                if *deposit_sidechain != nsidechain {
+                    continue;
+                }
+                let mut txhex = vec![];
+                transaction
+                    .transaction
+                    .consensus_encode(&mut txhex)
+                    .map_err(|err| rpc_error(err.to_string()))?;
+                deposits.push(Deposit {
+                    hashblock: *hash,
+                    nburnindex: 0,
+                    ntx: 0,
+                    strdest: strdest.clone(),
+                    txhex: hex::encode(txhex),
+                });
+            }
+        }
+        Ok(deposits)
+    }
+
+    fn receivewithdrawalbundle(
+        &self,
+        nsidechain: u8,
+        rawtx: String,
+    ) -> RpcResult<serde_json::Value> {
+        let rawtx = hex::decode(rawtx).map_err(|err| rpc_error(err.to_string()))?;
+        let transaction = bitcoin::Transaction::consensus_decode(&mut std::io::Cursor::new(rawtx))
+            .map_err(|err| rpc_error(err.to_string()))?;
+        let txid = transaction.txid();
+        let mut state = self.state();
+        let received_height = state.tip().height;
+        state.bundles.entry(txid).or_insert(MockBundle {
+            nsidechain,
+            transaction,
+            received_height,
+            failed: false,
+        });
+        Ok(serde_json::json!({ "wtxid": txid.to_string() }))
+    }
+
+    fn generate(&self, num: u32) -> RpcResult<serde_json::Value> {
+        let hashes: Vec<String> = self.mine(num).iter().map(ToString::to_string).collect();
+        Ok(serde_json::json!(hashes))
+    }
+
+    fn getnewaddress(
+        &self,
+        _account: String,
+        _address_type: String,
+    ) -> RpcResult<bitcoin::Address<bitcoin::address::NetworkUnchecked>> {
+        let pubkey_hash = bitcoin::PubkeyHash::from_byte_array(
+            self.state().next_hash()[..20]
+                .try_into()
+                .expect("slice is 20 bytes long"),
+        );
+        Ok(bitcoin::Address::new(
+            bitcoin::Network::Regtest,
+            bitcoin::address::Payload::PubkeyHash(pubkey_hash),
+        ))
+    }
+
+    fn createsidechaindeposit(
+        &self,
+        nsidechain: u8,
+        depositaddress: String,
+        amount: AmountBtc,
+        _fee: AmountBtc,
+    ) -> RpcResult<serde_json::Value> {
+        let strdest = depositaddress
+            .parse::<crate::types::DepositAddress>()
+            .ok()
+            .filter(|deposit_address| deposit_address.sidechain_number == nsidechain)
+            .map(|deposit_address| deposit_address.address.to_string())
+            .ok_or_else(|| rpc_error(format!("invalid deposit address {depositaddress}")))?;
+        let txid = self.create_deposit(nsidechain, &strdest, amount.to_sat());
+        Ok(serde_json::json!({ "txid": txid.to_string() }))
+    }
+}
+
+/// Calls the mock directly, without going through a JSON-RPC server.
+#[async_trait]
+impl MainchainBackend for MockMainchain {
+    async fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, super::Error> {
+        Ok(self.getbestblockhash()?)
+    }
+
+    async fn get_block_count(&self) -> Result<usize, super::Error> {
+        Ok(self.getblockcount()?)
+    }
+
+    async fn get_block(&self, block_hash: &bitcoin::BlockHash) -> Result<Block, super::Error> {
+        let state = self.state();
+        let block = state
+            .blocks
+            .get(block_hash)
+            .ok_or_else(|| not_found_error(format!("block {block_hash} not found")))?;
+        Ok(state.to_rpc_block(block))
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<bitcoin::BlockHash, super::Error> {
+        Ok(self.getblockhash(height as usize)?)
+    }
+
+    async fn get_raw_transaction(&self, txid: &bitcoin::Txid) -> Result<String, super::Error> {
+        Ok(self.getrawtransaction(*txid, None)?)
+    }
+
+    async fn get_raw_block(&self, block_hash: &bitcoin::BlockHash) -> Result<String, super::Error> {
+        let state = self.state();
+        let block = state
+            .blocks
+            .get(block_hash)
+            .ok_or_else(|| not_found_error(format!("block {block_hash} not found")))?;
+        Ok(state.to_raw_block(block)?)
+    }
+
+    async fn verify_bmm(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+    ) -> Result<serde_json::Value, super::Error> {
+        Ok(self.verifybmm(*block_hash, *critical_hash, sidechain_number)?)
+    }
+
+    async fn create_bmm_critical_data_tx(
+        &self,
+        amount: bitcoin::Amount,
+        height: u32,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+        prev_bytes: &str,
+    ) -> Result<serde_json::Value, super::Error> {
+        Ok(self.createbmmcriticaldatatx(
+            AmountBtc(amount),
+            height,
+            *critical_hash,
+            sidechain_number,
+            prev_bytes.into(),
+        )?)
+    }
+
+    async fn list_sidechain_deposits_by_block(
+        &self,
+        sidechain_number: u8,
+        end: Option<bitcoin::BlockHash>,
+        start: Option<bitcoin::BlockHash>,
+    ) -> Result<Vec<Deposit>, super::Error> {
+        Ok(self.listsidechaindepositsbyblock(sidechain_number, end, start)?)
+    }
+
+    async fn list_withdrawal_status(
+        &self,
+        sidechain_number: u8,
+    ) -> Result<Vec<WithdrawalStatus>, super::Error> {
+        Ok(self.listwithdrawalstatus(sidechain_number)?)
+    }
+
+    async fn list_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, super::Error> {
+        Ok(self.listspentwithdrawals()?)
+    }
+
+    async fn list_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, super::Error> {
+        Ok(self.listfailedwithdrawals()?)
+    }
+
+    async fn receive_withdrawal_bundle(
+        &self,
+        sidechain_number: u8,
+        raw_transaction: &str,
+    ) -> Result<serde_json::Value, super::Error> {
+        Ok(self.receivewithdrawalbundle(sidechain_number, raw_transaction.into())?)
+    }
+
+    async fn generate(&self, num: u32) -> Result<serde_json::Value, super::Error> {
+        Ok(MockMainServer::generate(self, num)?)
+    }
+
+    async fn create_sidechain_deposit(
+        &self,
+        sidechain_number: u8,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<serde_json::Value, super::Error> {
+        Ok(self.createsidechaindeposit(
+            sidechain_number,
+            deposit_address.into(),
+            AmountBtc(amount),
+            AmountBtc(fee),
+        )?)
+    }
+}
+
+/// Wraps a backend and makes its calls fail on demand, failed calls return
+/// `jsonrpsee::core::Error::RequestTimeout` like an unresponsive mainchain.
+pub struct FaultyBackend {
+    backend: Arc<dyn MainchainBackend>,
+    failures_left: AtomicUsize,
+    offline: AtomicBool,
+}
+
+impl FaultyBackend {
+    pub fn new(backend: Arc<dyn MainchainBackend>) -> Self {
+        Self {
+            backend,
+            failures_left: AtomicUsize::new(0),
+            offline: AtomicBool::new(false),
+        }
+    }
+
+    /// Fail the next `num` calls.
+    pub fn fail_next(&self, num: usize) {
+        self.failures_left.store(num, Ordering::SeqCst);
+    }
+
+    /// Fail every call until set back to `false`.
+    pub fn set_offline(&self, offline: bool) {
+        self.offline.store(offline, Ordering::SeqCst);
+    }
+
+    fn fault(&self) -> Result<(), super::Error> {
+        let failing = self.offline.load(Ordering::SeqCst)
+            || self
+                .failures_left
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                    left.checked_sub(1)
+                })
+                .is_ok();
+        if failing {
+            return Err(jsonrpsee::core::Error::RequestTimeout.into());
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl MainchainBackend for FaultyBackend {
+    async fn get_best_block_hash(&self) -> Result<bitcoin::BlockHash, super::Error> {
+        self.fault()?;
+        self.backend.get_best_block_hash().await
+    }
+
+    async fn get_block_count(&self) -> Result<usize, super::Error> {
+        self.fault()?;
+        self.backend.get_block_count().await
+    }
+
+    async fn get_block(&self, block_hash: &bitcoin::BlockHash) -> Result<Block, super::Error> {
+        self.fault()?;
+        self.backend.get_block(block_hash).await
+    }
+
+    async fn get_block_hash(&self, height: u32) -> Result<bitcoin::BlockHash, super::Error> {
+        self.fault()?;
+        self.backend.get_block_hash(height).await
+    }
+
+    async fn get_raw_transaction(&self, txid: &bitcoin::Txid) -> Result<String, super::Error> {
+        self.fault()?;
+        self.backend.get_raw_transaction(txid).await
+    }
+
+    async fn get_raw_block(&self, block_hash: &bitcoin::BlockHash) -> Result<String, super::Error> {
+        self.fault()?;
+        self.backend.get_raw_block(block_hash).await
+    }
+
+    async fn verify_bmm(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+    ) -> Result<serde_json::Value, super::Error> {
+        self.fault()?;
+        self.backend
+            .verify_bmm(block_hash, critical_hash, sidechain_number)
+            .await
+    }
+
+    async fn create_bmm_critical_data_tx(
+        &self,
+        amount: bitcoin::Amount,
+        height: u32,
+        critical_hash: &bitcoin::BlockHash,
+        sidechain_number: u8,
+        prev_bytes: &str,
+    ) -> Result<serde_json::Value, super::Error> {
+        self.fault()?;
+        self.backend
+            .create_bmm_critical_data_tx(
+                amount,
+                height,
+                critical_hash,
+                sidechain_number,
+                prev_bytes,
+            )
+            .await
+    }
+
+    async fn list_sidechain_deposits_by_block(
+        &self,
+        sidechain_number: u8,
+        end: Option<bitcoin::BlockHash>,
+        start: Option<bitcoin::BlockHash>,
+    ) -> Result<Vec<Deposit>, super::Error> {
+        self.fault()?;
+        self.backend
+            .list_sidechain_deposits_by_block(sidechain_number, end, start)
+            .await
+    }
+
+    async fn list_withdrawal_status(
+        &self,
+        sidechain_number: u8,
+    ) -> Result<Vec<WithdrawalStatus>, super::Error> {
+        self.fault()?;
+        self.backend.list_withdrawal_status(sidechain_number).await
+    }
+
+    async fn list_spent_withdrawals(&self) -> Result<Vec<SpentWithdrawal>, super::Error> {
+        self.fault()?;
+        self.backend.list_spent_withdrawals().await
+    }
+
+    async fn list_failed_withdrawals(&self) -> Result<Vec<FailedWithdrawal>, super::Error> {
+        self.fault()?;
+        self.backend.list_failed_withdrawals().await
+    }
+
+    async fn receive_withdrawal_bundle(
+        &self,
+        sidechain_number: u8,
+        raw_transaction: &str,
+    ) -> Result<serde_json::Value, super::Error> {
+        self.fault()?;
+        self.backend
+            .receive_withdrawal_bundle(sidechain_number, raw_transaction)
+            .await
+    }
+
+    async fn generate(&self, num: u32) -> Result<serde_json::Value, super::Error> {
+        self.fault()?;
+        self.backend.generate(num).await
+    }
+
+    async fn create_sidechain_deposit(
+        &self,
+        sidechain_number: u8,
+        deposit_address: &str,
+        amount: bitcoin::Amount,
+        fee: bitcoin::Amount,
+    ) -> Result<serde_json::Value, super::Error> {
+        self.fault()?;
+        self.backend
+            .create_sidechain_deposit(sidechain_number, deposit_address, amount, fee)
+            .await
+    }
+}
+
+/// Script of the CTIP output of sidechain `nsidechain`.
+pub fn ctip_script(nsidechain: u8) -> bitcoin::ScriptBuf {
+    script::Builder::new()
+        // OP_NOP5 is OP_DRIVECHAIN in BIP300.
+        .push_opcode(opcodes::all::OP_NOP5)
+        .push_int(nsidechain as i64)
+        .into_script()
+}
+
+fn rpc_error(message: String) -> jsonrpsee::core::Error {
+    call_error(super::RPC_MISC_ERROR, message)
+}
+
+/// Unknown block or transaction.
+fn not_found_error(message: String) -> jsonrpsee::core::Error {
+    call_error(super::RPC_INVALID_ADDRESS_OR_KEY, message)
+}
+
+/// Same error as mainchain returns over HTTP, so the mock can be used as a
+/// backend directly.
+fn call_error(code: i32, message: String) -> jsonrpsee::core::Error {
+    jsonrpsee::core::Error::Call(CallError::Custom(ErrorObject::owned(
+        code, message, None::<()>,
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::{authorize, get_address, Authorization, Keypair};
+    use crate::drivechain::{Drivechain, Error, RpcConfig};
+    use crate::state::{ConsensusParams, State};
+    use crate::types::{
+        BlockHash, Body, Content, GetValue, Header, OutPoint, Output, PayoutMismatch, Transaction,
+        WithdrawalBundleAlert, WithdrawalBundleRecord, WithdrawalBundleStatus,
+    };
+
+    /// Sidechain state following the mock mainchain, blocks are connected
+    /// the way `Node::submit_block` connects them, without BMM.
+    struct Sidechain {
+        env: heed::Env,
+        state: State<Authorization, ()>,
+        drivechain: Drivechain<()>,
+        height: u32,
+        path: std::path::PathBuf,
+    }
+
+    impl Sidechain {
+        fn new(name: &str, mainchain: &MockMainchain) -> Self {
+            let path = std::env::temp_dir().join(format!("ddk-mock-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            let env = heed::EnvOpenOptions::new()
+                .map_size(10 * 1024 * 1024)
+                .max_dbs(State::<Authorization, ()>::NUM_DBS)
+                .open(&path)
+                .unwrap();
+            let state = State::new(&env, ConsensusParams::DEFAULT).unwrap();
+            let drivechain = Drivechain::with_backend(0, Arc::new(mainchain.clone()));
+            Self {
+                env,
+                state,
+                drivechain,
+                height: 0,
+                path,
+            }
+        }
+
+        async fn connect(&mut self, body: &Body<Authorization, ()>) {
+            let (start, ctip, pending_bundles) = {
+                let txn = self.env.read_txn().unwrap();
+                (
+                    self.state.get_last_deposit_block_hash(&txn).unwrap(),
+                    self.state.get_ctip(&txn).unwrap(),
+                    self.state.get_pending_withdrawal_bundles(&txn).unwrap(),
+                )
+            };
+            let end = self.drivechain.get_mainchain_tip().await.unwrap();
+            let two_way_peg_data = self
+                .drivechain
+                .get_two_way_peg_data(end, start, ctip, &pending_bundles)
+                .await
+                .unwrap();
+            let mut txn = self.env.write_txn().unwrap();
+            self.state.validate_body(&txn, self.height, body).unwrap();
+            self.state
+                .set_main_tip(
+                    &mut txn,
+                    two_way_peg_data.main_block_height,
+                    two_way_peg_data.main_block_time,
+                )
+                .unwrap();
+            self.state
+                .connect_body(&mut txn, self.height, body)
+                .unwrap();
+            self.state
+                .connect_two_way_peg_data(&mut txn, &two_way_peg_data, self.height)
+                .unwrap();
+            txn.commit().unwrap();
+            self.height += 1;
+        }
+    }
+
+    impl Drop for Sidechain {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn keypair() -> Keypair {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[1; 32]).unwrap();
+        let public = (&secret).into();
+        Keypair { secret, public }
+    }
+
+    /// Deposit to `keypair()`, withdraw the deposit and connect blocks until
+    /// the sidechain collects a withdrawal bundle, then send the bundle to
+    /// mainchain.
+    async fn withdraw(
+        mainchain: &MockMainchain,
+        sidechain: &mut Sidechain,
+    ) -> (bitcoin::Txid, WithdrawalBundleRecord<()>) {
+        let keypair = keypair();
+        let address = get_address(&keypair.public);
+        let deposit_txid = mainchain.create_deposit(0, &address.to_string(), 100_000);
+        mainchain.mine(ConsensusParams::DEFAULT.deposit_confirmations);
+        // Block 1 connects the deposit.
+        sidechain.connect(&Body::new(vec![], vec![])).await;
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: deposit_txid,
+            vout: 0,
+        });
+        let utxos = {
+            let txn = sidechain.env.read_txn().unwrap();
+            sidechain.state.get_utxos(&txn).unwrap()
+        };
+        assert_eq!(utxos[&deposit].get_value(), 100_000);
+
+        // Block 2 spends it to a withdrawal.
+        let main_address = mainchain
+            .getnewaddress(String::new(), String::new())
+            .unwrap();
+        let transaction = Transaction {
+            inputs: vec![deposit],
+            outputs: vec![Output {
+                address,
+                content: Content::Withdrawal {
+                    value: 90_000,
+                    main_fee: 1_000,
+                    main_address,
+                },
+            }],
+            lock_time: None,
+            relative_locks: vec![],
+        };
+        let transaction = authorize(&[(address, &keypair)], transaction).unwrap();
+        sidechain
+            .connect(&Body::new(vec![transaction], vec![]))
+            .await;
+
+        // Bundles are collected once the failure gap has passed.
+        let mut pending_bundles = HashMap::new();
+        while pending_bundles.is_empty() {
+            assert!(sidechain.height < 10, "no withdrawal bundle collected");
+            sidechain.connect(&Body::new(vec![], vec![])).await;
+            let txn = sidechain.env.read_txn().unwrap();
+            pending_bundles = sidechain
+                .state
+                .get_pending_withdrawal_bundles(&txn)
+                .unwrap();
+        }
+        assert_eq!(pending_bundles.len(), 1);
+        let (txid, record) = pending_bundles.into_iter().next().unwrap();
+        assert_eq!(record.bundle.spent_utxos.len(), 1);
+        assert!(record
+            .bundle
+            .transaction
+            .output
+            .iter()
+            .any(|txout| txout.value == 90_000));
+        sidechain
+            .drivechain
+            .broadcast_withdrawal_bundle(record.bundle.transaction.clone())
+            .await
+            .unwrap();
+        assert_eq!(
+            mainchain.get_withdrawal_bundle_status(&txid),
+            Some(MockBundleStatus::Pending)
+        );
+        (txid, record)
+    }
+
+    #[test]
+    fn deposit_block_withdrawal_bundle() {
+        let mainchain = MockMainchain::new();
+        let mut sidechain = Sidechain::new("deposit_block_withdrawal_bundle", &mainchain);
+        block_on(async {
+            let (txid, _) = withdraw(&mainchain, &mut sidechain).await;
+            mainchain.mine(1);
+            sidechain.connect(&Body::new(vec![], vec![])).await;
+            let record = {
+                let txn = sidechain.env.read_txn().unwrap();
+                sidechain.state.get_withdrawal_bundle(&txn, &txid).unwrap()
+            };
+            assert!(matches!(
+                record.unwrap().status,
+                Some(WithdrawalBundleStatus::Pending { work_score: 1, .. })
+            ));
+
+            // Mainchain pays the bundle out and the sidechain sees it.
+            mainchain.confirm_withdrawal_bundle(&txid).unwrap();
+            let payout_block = mainchain.mine(1)[0];
+            assert_eq!(
+                mainchain.get_withdrawal_bundle_status(&txid),
+                Some(MockBundleStatus::Confirmed(payout_block))
+            );
+            sidechain.connect(&Body::new(vec![], vec![])).await;
+            let txn = sidechain.env.read_txn().unwrap();
+            assert!(sidechain
+                .state
+                .get_pending_withdrawal_bundles(&txn)
+                .unwrap()
+                .is_empty());
+            let record = sidechain
+                .state
+                .get_withdrawal_bundle(&txn, &txid)
+                .unwrap()
+                .unwrap();
+            assert!(matches!(
+                record.status,
+                Some(WithdrawalBundleStatus::Confirmed)
+            ));
+            assert!(sidechain
+                .state
+                .get_withdrawal_bundle_alerts(&txn)
+                .unwrap()
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn bundle_payout_without_return_destination_confirms() {
+        let mainchain = MockMainchain::new();
+        let mut sidechain = Sidechain::new("bundle_payout_without_return_destination", &mainchain);
+        block_on(async {
+            let (txid, record) = withdraw(&mainchain, &mut sidechain).await;
+            // Mainchain rewrites the return destination placeholder.
+            let outputs = record.bundle.transaction.output[1..].to_vec();
+            mainchain.pay_out_withdrawal_bundle(&txid, outputs).unwrap();
+            mainchain.mine(1);
+            sidechain.connect(&Body::new(vec![], vec![])).await;
+            let txn = sidechain.env.read_txn().unwrap();
+            let record = sidechain
+                .state
+                .get_withdrawal_bundle(&txn, &txid)
+                .unwrap()
+                .unwrap();
+            assert!(matches!(
+                record.status,
+                Some(WithdrawalBundleStatus::Confirmed)
+            ));
+            assert!(sidechain
+                .state
+                .get_withdrawal_bundle_alerts(&txn)
+                .unwrap()
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn bundle_payout_with_altered_amount_raises_alert() {
+        let mainchain = MockMainchain::new();
+        let mut sidechain = Sidechain::new("bundle_payout_with_altered_amount", &mainchain);
+        block_on(async {
+            let (txid, record) = withdraw(&mainchain, &mut sidechain).await;
+            let mut outputs = record.bundle.transaction.output.clone();
+            outputs.last_mut().unwrap().value += 1;
+            mainchain.pay_out_withdrawal_bundle(&txid, outputs).unwrap();
+            let payout_block = mainchain.mine(1)[0];
+            sidechain.connect(&Body::new(vec![], vec![])).await;
+            let txn = sidechain.env.read_txn().unwrap();
+            assert_eq!(
+                sidechain.state.get_withdrawal_bundle_alerts(&txn).unwrap(),
+                vec![WithdrawalBundleAlert {
+                    txid,
+                    main_block_hash: Some(payout_block),
+                    main_height: mainchain.state().tip().height,
+                    reason: PayoutMismatch::WrongOutputs,
+                }]
+            );
+            // The withdrawal stays spent and nothing is refunded.
+            let utxos = sidechain.state.get_utxos(&txn).unwrap();
+            assert!(utxos
+                .keys()
+                .all(|outpoint| !record.bundle.spent_utxos.contains_key(outpoint)
+                    && !matches!(outpoint, OutPoint::Refund { .. })));
+        });
+    }
+
+    #[test]
+    fn reorg_rolls_back_ctip() {
+        let mainchain = MockMainchain::new();
+        let first = mainchain.create_deposit(0, "first", 1_000);
+        mainchain.mine(1);
+        mainchain.create_deposit(0, "second", 2_000);
+        mainchain.reorg(1);
+        // The second deposit spent the CTIP of the first one, so it is
+        // dropped with it.
+        let third = mainchain.create_deposit(0, "third", 3_000);
+        mainchain.mine(1);
+        let deposits = mainchain
+            .listsidechaindepositsbyblock(0, None, None)
+            .unwrap();
+        let txids: Vec<_> = deposits
+            .iter()
+            .map(|deposit| {
+                let txhex = hex::decode(&deposit.txhex).unwrap();
+                bitcoin::Transaction::consensus_decode(&mut std::io::Cursor::new(txhex))
+                    .unwrap()
+                    .txid()
+            })
+            .collect();
+        assert_eq!(txids, vec![third]);
+        let state = mainchain.state();
+        assert!(!state.transactions[&third]
+            .input
+            .iter()
+            .any(|input| input.previous_output.txid == first));
+        assert_eq!(state.ctips[&0].1, 3_000);
+    }
+
+    #[test]
+    fn verify_bmm_fails_only_on_disproven_commitment() {
+        let mainchain = MockMainchain::new();
+        let backend = Arc::new(FaultyBackend::new(Arc::new(mainchain.clone())));
+        let drivechain = Drivechain::<()>::with_rpc_config(
+            0,
+            backend.clone(),
+            RpcConfig {
+                max_retries: 0,
+                ..RpcConfig::default()
+            },
+        );
+        let header = |prev_side_hash: u8| Header {
+            merkle_root: Body::<Authorization, ()>::new(vec![], vec![]).compute_merkle_root(),
+            prev_side_hash: BlockHash::from([prev_side_hash; 32]),
+            prev_main_hash: mainchain.state().tip().hash,
+        };
+        block_on(async {
+            let bmmed = header(1);
+            let tip = bmmed.prev_main_hash.to_string();
+            mainchain
+                .create_bmm_critical_data_tx(
+                    bitcoin::Amount::from_sat(1_000),
+                    1,
+                    &bmmed.hash().into(),
+                    0,
+                    &tip[tip.len() - 8..],
+                )
+                .await
+                .unwrap();
+            let not_bmmed = header(2);
+            mainchain.mine(1);
+            drivechain.verify_bmm(&bmmed).await.unwrap();
+            let err = drivechain.verify_bmm(&not_bmmed).await.unwrap_err();
+            assert!(matches!(err, Error::BmmNotFound { .. }));
+            assert!(err.is_invalid_bmm());
+
+            // Mainchain doesn't have the including block yet.
+            let err = drivechain.verify_bmm(&header(3)).await.unwrap_err();
+            assert!(matches!(err, Error::NoNextBlock { .. }));
+            assert!(!err.is_invalid_bmm());
+
+            backend.set_offline(true);
+            let err = drivechain.verify_bmm(&not_bmmed).await.unwrap_err();
+            assert!(!err.is_invalid_bmm());
+        });
+    }
+}
@@ -1,49 +1,224 @@
+mod backend;
+mod bmm;
 mod client;
+#[cfg(any(test, feature = "mock"))]
+pub mod mock;
+mod retry;
+mod watcher;
 use crate::types::bitcoin::consensus::{Decodable, Encodable};
 use crate::types::*;
-use base64::Engine as _;
-pub use client::MainClient;
-use jsonrpsee::http_client::{HeaderMap, HttpClient, HttpClientBuilder};
+pub use backend::{CachingBackend, JsonRpcBackend, MainchainAuth, MainchainBackend};
+pub use bmm::BmmRequest;
+pub use client::{
+    AmountBtc, Block, Deposit, FailedWithdrawal, MainClient, SpentWithdrawal, WithdrawalStatus,
+};
+use jsonrpsee::types::error::CallError;
+pub use retry::{MainchainHealth, RpcConfig};
 use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 use std::{collections::HashMap, marker::PhantomData};
+pub use watcher::{MainchainEvent, MainchainWatcher, WatcherConfig};
+
+/// Mainchain JSON-RPC error codes, the same as in Bitcoin Core. `verifybmm`
+/// fails with `RPC_MISC_ERROR` if the block doesn't include the request.
+const RPC_MISC_ERROR: i32 = -1;
+const RPC_INVALID_ADDRESS_OR_KEY: i32 = -5;
 
 #[derive(Clone)]
 pub struct Drivechain<C> {
     pub sidechain_number: u8,
-    pub client: HttpClient,
+    pub backend: Arc<dyn MainchainBackend>,
+    circuit_breaker: retry::CircuitBreaker,
+    /// Payouts of confirmed bundles that are still pending on the sidechain,
+    /// so they are looked up only once.
+    bundle_payouts: Arc<Mutex<HashMap<bitcoin::Txid, BundlePayout>>>,
     pub _content: PhantomData<C>,
 }
 
 impl<C> Drivechain<C> {
+    /// Maximum number of mainchain blocks to list deposits for in one call.
+    pub const DEPOSIT_SCAN_BATCH_SIZE: u32 = 1000;
+
+    /// Check that the mainchain block right after `header.prev_main_hash`
+    /// includes the BMM request for `header`. The request commits to the
+    /// previous sidechain block through h*, and to the mainchain block it
+    /// builds on through its prevbytes.
+    ///
+    /// Fails with `BmmNotFound` or `BmmPrevMismatch` only if the including
+    /// block was fetched and disproves the commitment.
     pub async fn verify_bmm(&self, header: &Header) -> Result<(), Error> {
         let prev_main_hash = header.prev_main_hash;
-        let block_hash = self
-            .client
-            .getblock(&prev_main_hash, None)
+        let main_block_hash = self
+            .get_block(&prev_main_hash)
             .await?
             .nextblockhash
             .ok_or(Error::NoNextBlock { prev_main_hash })?;
-        self.client
-            .verifybmm(&block_hash, &header.hash().into(), self.sidechain_number)
-            .await?;
+        let critical_hash: bitcoin::BlockHash = header.hash().into();
+        let request = self
+            .get_bmm_requests(&main_block_hash)
+            .await?
+            .into_iter()
+            .find(|request| request.critical_hash == critical_hash)
+            .ok_or(Error::BmmNotFound {
+                critical_hash,
+                main_block_hash,
+            })?;
+        if request.prevbytes != bmm::prevbytes(&prev_main_hash) {
+            return Err(Error::BmmPrevMismatch {
+                main_block_hash,
+                prev_main_hash,
+            });
+        }
         Ok(())
     }
 
+    /// Like `verify_bmm`, also checking that `header` is BMMed in a later
+    /// mainchain block than `parent`, the header it builds on. Every
+    /// mainchain block can BMM only one sidechain block.
+    pub async fn verify_bmm_chain(
+        &self,
+        header: &Header,
+        parent: Option<&Header>,
+    ) -> Result<(), Error> {
+        if let Some(parent) = parent {
+            let height = self.get_block(&header.prev_main_hash).await?.height;
+            let parent_height = self.get_block(&parent.prev_main_hash).await?.height;
+            if height <= parent_height {
+                return Err(Error::BmmOutOfOrder {
+                    prev_main_hash: header.prev_main_hash,
+                    parent_prev_main_hash: parent.prev_main_hash,
+                });
+            }
+        }
+        self.verify_bmm(header).await
+    }
+
+    /// Look for the BMM request of `header` in the `max_depth` mainchain
+    /// blocks after `header.prev_main_hash`.
+    pub async fn get_bmm_state(&self, header: &Header, max_depth: u32) -> Result<BmmState, Error> {
+        let prev_main_hash = header.prev_main_hash;
+        let critical_hash: bitcoin::BlockHash = header.hash().into();
+        let prev_main_block = self.get_block(&prev_main_hash).await?;
+        let mut next = prev_main_block.nextblockhash;
+        for _ in 0..max_depth {
+            let Some(main_block_hash) = next else {
+                break;
+            };
+            let verified = self
+                .call(true, || {
+                    self.backend
+                        .verify_bmm(&main_block_hash, &critical_hash, self.sidechain_number)
+                })
+                .await;
+            match verified {
+                Ok(_) => return Ok(BmmState::Included { main_block_hash }),
+                Err(err) if err.is_bmm_not_found() => {}
+                Err(err) => return Err(err),
+            }
+            next = self.get_block(&main_block_hash).await?.nextblockhash;
+        }
+        let main_height = self.call(true, || self.backend.get_block_count()).await? as u32;
+        if main_height >= prev_main_block.height as u32 + max_depth {
+            Ok(BmmState::Expired)
+        } else {
+            Ok(BmmState::Pending)
+        }
+    }
+
+    /// BMM requests for this sidechain included in `main_block_hash`.
+    pub async fn get_bmm_requests(
+        &self,
+        main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<Vec<BmmRequest>, Error> {
+        let main_block = self.get_raw_block(main_block_hash).await?;
+        Ok(main_block
+            .txdata
+            .iter()
+            .filter_map(BmmRequest::from_transaction)
+            .filter(|request| request.sidechain_number == self.sidechain_number)
+            .collect())
+    }
+
     pub async fn get_mainchain_tip(&self) -> Result<bitcoin::BlockHash, Error> {
-        Ok(self.client.getbestblockhash().await?)
+        self.call(true, || self.backend.get_best_block_hash()).await
+    }
+
+    pub fn get_health(&self) -> MainchainHealth {
+        self.circuit_breaker.health()
+    }
+
+    /// Mainchain tip watcher sharing the timeouts and circuit breaker of
+    /// this client.
+    pub fn watcher(&self, config: WatcherConfig) -> MainchainWatcher {
+        MainchainWatcher::new(self.backend.clone(), self.circuit_breaker.clone(), config)
+    }
+
+    /// Call the backend with the configured timeout and circuit breaker,
+    /// retrying on transient errors if the call is `idempotent`.
+    pub async fn call<T, F, Fut>(&self, idempotent: bool, call: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: std::future::Future<Output = Result<T, Error>>,
+    {
+        self.circuit_breaker.call(idempotent, call).await
     }
 
+    async fn get_block(&self, block_hash: &bitcoin::BlockHash) -> Result<Block, Error> {
+        self.call(true, || self.backend.get_block(block_hash)).await
+    }
+
+    /// `pending_bundles` are the sidechain bundles waiting for mainchain,
+    /// payouts are fetched for the ones mainchain reports as confirmed.
     pub async fn get_two_way_peg_data(
         &self,
         end: bitcoin::BlockHash,
         start: Option<bitcoin::BlockHash>,
+        ctip: Option<Ctip>,
+        pending_bundles: &HashMap<bitcoin::Txid, WithdrawalBundleRecord<C>>,
     ) -> Result<TwoWayPegData<C>, Error> {
-        let (deposits, deposit_block_hash) = self.get_deposit_outputs(end, start).await?;
-        let bundle_statuses = self.get_withdrawal_bundle_statuses().await?;
+        let main_block = self.get_block(&end).await?;
+        let scan = self
+            .get_deposit_outputs(end, main_block.height as u32, start, ctip)
+            .await?;
+        let (bundle_statuses, spent_blocks) = self.get_withdrawal_bundle_statuses().await?;
+        self.bundle_payouts()
+            .retain(|txid, _| pending_bundles.contains_key(txid));
+        let mut bundle_payouts = HashMap::new();
+        for (txid, record) in pending_bundles {
+            let Some(main_block_hash) = spent_blocks.get(txid) else {
+                continue;
+            };
+            let cached = self
+                .bundle_payouts()
+                .get(txid)
+                .filter(|payout| payout.main_block_hash == *main_block_hash)
+                .cloned();
+            let payout = match cached {
+                Some(payout) => payout,
+                None => {
+                    let transaction = self
+                        .find_bundle_payout(&record.bundle.transaction, main_block_hash)
+                        .await?;
+                    let payout = BundlePayout {
+                        main_block_hash: *main_block_hash,
+                        transaction,
+                    };
+                    self.bundle_payouts().insert(*txid, payout.clone());
+                    payout
+                }
+            };
+            bundle_payouts.insert(*txid, payout);
+        }
         let two_way_peg_data = TwoWayPegData {
-            deposits,
-            deposit_block_hash,
+            deposits: scan.outputs,
+            deposit_heights: scan.heights,
+            deposit_block_hash: scan.last_block_hash,
+            invalid_deposits: scan.invalid_deposits,
+            ctip: scan.ctip,
+            main_block_height: main_block.height as u32,
+            main_block_time: main_block.mediantime,
             bundle_statuses,
+            bundle_payouts,
         };
         Ok(two_way_peg_data)
     }
@@ -55,101 +230,331 @@ impl<C> Drivechain<C> {
         let mut rawtx = vec![];
         transaction.consensus_encode(&mut rawtx)?;
         let rawtx = hex::encode(&rawtx);
-        self.client
-            .receivewithdrawalbundle(self.sidechain_number, &rawtx)
-            .await?;
+        // Not retried here, bundles are broadcast again on every new
+        // mainchain block.
+        self.call(false, || {
+            self.backend
+                .receive_withdrawal_bundle(self.sidechain_number, &rawtx)
+        })
+        .await?;
         Ok(())
     }
 
+    /// Scan mainchain blocks after `start` up to and including `end` for
+    /// deposits, `DEPOSIT_SCAN_BATCH_SIZE` blocks per call. `ctip` is the
+    /// sidechain CTIP as of `start`.
     async fn get_deposit_outputs(
         &self,
         end: bitcoin::BlockHash,
+        end_height: u32,
         start: Option<bitcoin::BlockHash>,
-    ) -> Result<(HashMap<OutPoint, Output<C>>, Option<bitcoin::BlockHash>), Error> {
-        let deposits = self
-            .client
-            .listsidechaindepositsbyblock(self.sidechain_number, Some(end), start)
-            .await?;
-        let mut last_block_hash = None;
-        let mut last_total = 0;
-        let mut outputs = HashMap::new();
-        for deposit in &deposits {
-            let transaction = hex::decode(&deposit.txhex)?;
-            let transaction =
-                bitcoin::Transaction::consensus_decode(&mut std::io::Cursor::new(transaction))?;
-            if let Some(start) = start {
-                if deposit.hashblock == start {
-                    last_total = transaction.output[deposit.nburnindex].value;
+        mut ctip: Option<Ctip>,
+    ) -> Result<DepositScan<C>, Error> {
+        let mut scan = DepositScan {
+            outputs: HashMap::new(),
+            heights: HashMap::new(),
+            invalid_deposits: vec![],
+            last_block_hash: None,
+            ctip: None,
+            block_heights: HashMap::new(),
+        };
+        let mut height = match start {
+            Some(start) => self.get_block(&start).await?.height as u32,
+            None => 0,
+        };
+        let mut batch_start = start;
+        while height < end_height {
+            let batch_end_height =
+                std::cmp::min(height + Self::DEPOSIT_SCAN_BATCH_SIZE, end_height);
+            let batch_end = if batch_end_height == end_height {
+                end
+            } else {
+                self.call(true, || self.backend.get_block_hash(batch_end_height))
+                    .await?
+            };
+            let deposits = self
+                .call(true, || {
+                    self.backend.list_sidechain_deposits_by_block(
+                        self.sidechain_number,
+                        Some(batch_end),
+                        batch_start,
+                    )
+                })
+                .await?;
+            for deposit in &deposits {
+                // Deposits in the first block of a batch were already scanned.
+                if Some(deposit.hashblock) == batch_start {
                     continue;
                 }
+                ctip = Some(self.scan_deposit(deposit, ctip, &mut scan).await?);
             }
-            let total = transaction.output[deposit.nburnindex].value;
-            if total < last_total {
-                last_total = total;
-                continue;
+            batch_start = Some(batch_end);
+            height = batch_end_height;
+            scan.last_block_hash = Some(batch_end);
+        }
+        scan.ctip = ctip;
+        Ok(scan)
+    }
+
+    /// Add `deposit` to `scan` and return the new CTIP.
+    async fn scan_deposit(
+        &self,
+        deposit: &Deposit,
+        ctip: Option<Ctip>,
+        scan: &mut DepositScan<C>,
+    ) -> Result<Ctip, Error> {
+        let transaction = hex::decode(&deposit.txhex)?;
+        let transaction =
+            bitcoin::Transaction::consensus_decode(&mut std::io::Cursor::new(transaction))?;
+        let txid = transaction.txid();
+        let ctip_txout = transaction
+            .output
+            .get(deposit.nburnindex)
+            .ok_or(Error::NoCtipOutput { txid })?;
+        let spent_ctip_value = self
+            .get_spent_ctip_value(&transaction, &ctip_txout.script_pubkey, ctip)
+            .await?;
+        let main_outpoint = bitcoin::OutPoint {
+            txid,
+            vout: deposit.nburnindex as u32,
+        };
+        let new_ctip = Ctip {
+            outpoint: main_outpoint,
+            value: ctip_txout.value,
+        };
+        if ctip_txout.value <= spent_ctip_value {
+            println!("deposit {txid} doesn't increase the CTIP value, ignoring it");
+            return Ok(new_ctip);
+        }
+        let value = ctip_txout.value - spent_ctip_value;
+        let address = match self.parse_deposit_destination(&deposit.strdest) {
+            Ok(address) => address,
+            Err(reason) => {
+                println!("invalid deposit {txid} to {}: {reason:?}", deposit.strdest);
+                scan.invalid_deposits.push(InvalidDeposit {
+                    outpoint: main_outpoint,
+                    main_block_hash: deposit.hashblock,
+                    strdest: deposit.strdest.clone(),
+                    value,
+                    reason,
+                });
+                return Ok(new_ctip);
             }
-            let value = total - last_total;
-            let outpoint = OutPoint::Deposit(bitcoin::OutPoint {
-                txid: transaction.txid(),
-                vout: deposit.nburnindex as u32,
-            });
-            last_total = total;
-            last_block_hash = Some(deposit.hashblock);
-            let address: Address = match deposit.strdest.parse() {
-                Ok(address) => address,
-                Err(_) => {
-                    continue;
+        };
+        let outpoint = OutPoint::Deposit(main_outpoint);
+        let output = Output {
+            address,
+            content: Content::Value(value),
+        };
+        let height = match scan.block_heights.get(&deposit.hashblock) {
+            Some(height) => *height,
+            None => {
+                let height = self.get_block(&deposit.hashblock).await?.height as u32;
+                scan.block_heights.insert(deposit.hashblock, height);
+                height
+            }
+        };
+        scan.outputs.insert(outpoint, output);
+        scan.heights.insert(outpoint, height);
+        Ok(new_ctip)
+    }
+
+    /// Value of the CTIP spent by `transaction`, the spent CTIP is recognized
+    /// by having the same script as the new one. Returns 0 for the first
+    /// deposit to the sidechain.
+    async fn get_spent_ctip_value(
+        &self,
+        transaction: &bitcoin::Transaction,
+        ctip_script: &bitcoin::Script,
+        ctip: Option<Ctip>,
+    ) -> Result<u64, Error> {
+        if let Some(ctip) = ctip {
+            if transaction
+                .input
+                .iter()
+                .any(|input| input.previous_output == ctip.outpoint)
+            {
+                return Ok(ctip.value);
+            }
+        }
+        // CTIP changed since it was last seen, for example by a withdrawal
+        // bundle payout, so look at every spent output.
+        for input in &transaction.input {
+            let prev_txid = input.previous_output.txid;
+            let prev_transaction = self
+                .call(true, || self.backend.get_raw_transaction(&prev_txid))
+                .await?;
+            let prev_transaction = hex::decode(prev_transaction)?;
+            let prev_transaction = bitcoin::Transaction::consensus_decode(
+                &mut std::io::Cursor::new(prev_transaction),
+            )?;
+            if let Some(prev_txout) = prev_transaction
+                .output
+                .get(input.previous_output.vout as usize)
+            {
+                if prev_txout.script_pubkey.as_script() == ctip_script {
+                    return Ok(prev_txout.value);
                 }
-            };
-            let output = Output {
-                address,
-                content: Content::Value(value),
-            };
-            outputs.insert(outpoint, output);
+            }
+        }
+        Ok(0)
+    }
+
+    /// Mainchain passes the destination of a deposit as it was given, so it
+    /// is either a full deposit address or just a sidechain address.
+    fn parse_deposit_destination(&self, strdest: &str) -> Result<Address, InvalidDepositReason> {
+        if !strdest.contains('_') {
+            return strdest.parse().map_err(|_| InvalidDepositReason::Malformed);
+        }
+        let deposit_address: DepositAddress = strdest.parse().map_err(|err| match err {
+            DepositAddressParseError::WrongChecksum => InvalidDepositReason::WrongChecksum,
+            _ => InvalidDepositReason::Malformed,
+        })?;
+        if deposit_address.sidechain_number != self.sidechain_number {
+            return Err(InvalidDepositReason::WrongSidechain {
+                sidechain_number: deposit_address.sidechain_number,
+            });
         }
-        Ok((outputs, last_block_hash))
+        Ok(deposit_address.address)
+    }
+
+    /// Find the transaction in `main_block_hash` paying out `bundle`, it is
+    /// recognized by the bundle inputs commitment output.
+    async fn find_bundle_payout(
+        &self,
+        bundle: &bitcoin::Transaction,
+        main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<Option<bitcoin::Transaction>, Error> {
+        let Some(commitment_txout) = bundle.output.get(2) else {
+            return Ok(None);
+        };
+        let main_block = self.get_raw_block(main_block_hash).await?;
+        Ok(main_block
+            .txdata
+            .into_iter()
+            .find(|transaction| transaction.output.contains(commitment_txout)))
+    }
+
+    async fn get_raw_block(
+        &self,
+        block_hash: &bitcoin::BlockHash,
+    ) -> Result<bitcoin::Block, Error> {
+        let block = self
+            .call(true, || self.backend.get_raw_block(block_hash))
+            .await?;
+        let block = hex::decode(block)?;
+        Ok(bitcoin::Block::consensus_decode(
+            &mut std::io::Cursor::new(block),
+        )?)
+    }
+
+    fn bundle_payouts(&self) -> std::sync::MutexGuard<'_, HashMap<bitcoin::Txid, BundlePayout>> {
+        self.bundle_payouts
+            .lock()
+            .expect("bundle payout cache lock poisoned")
     }
 
+    /// Returns bundle statuses, and the mainchain blocks in which confirmed
+    /// bundles were spent.
     async fn get_withdrawal_bundle_statuses(
         &self,
-    ) -> Result<HashMap<bitcoin::Txid, WithdrawalBundleStatus>, Error> {
+    ) -> Result<
+        (
+            HashMap<bitcoin::Txid, WithdrawalBundleStatus>,
+            HashMap<bitcoin::Txid, bitcoin::BlockHash>,
+        ),
+        Error,
+    > {
         let mut statuses = HashMap::new();
-        for spent in &self.client.listspentwithdrawals().await? {
+        let mut spent_blocks = HashMap::new();
+        for pending in &self
+            .call(true, || {
+                self.backend.list_withdrawal_status(self.sidechain_number)
+            })
+            .await?
+        {
+            let status = WithdrawalBundleStatus::Pending {
+                work_score: pending.nworkscore as u32,
+                blocks_left: pending.nblocksleft as u32,
+            };
+            statuses.insert(pending.hash, status);
+        }
+        for spent in &self
+            .call(true, || self.backend.list_spent_withdrawals())
+            .await?
+        {
             if spent.nsidechain == self.sidechain_number {
                 statuses.insert(spent.hash, WithdrawalBundleStatus::Confirmed);
+                spent_blocks.insert(spent.hash, spent.hashblock);
             }
         }
-        for failed in &self.client.listfailedwithdrawals().await? {
-            statuses.insert(failed.hash, WithdrawalBundleStatus::Failed);
+        for failed in &self
+            .call(true, || self.backend.list_failed_withdrawals())
+            .await?
+        {
+            if failed.nsidechain == self.sidechain_number {
+                statuses.insert(failed.hash, WithdrawalBundleStatus::Failed);
+            }
         }
-        Ok(statuses)
+        Ok((statuses, spent_blocks))
     }
 
+    /// Connect to the mainchain JSON-RPC server at `main_addr` over plain
+    /// HTTP with basic auth.
     pub fn new(
         sidechain_number: u8,
         main_addr: SocketAddr,
         user: &str,
         password: &str,
     ) -> Result<Self, Error> {
-        let mut headers = HeaderMap::new();
-        let auth = format!("{user}:{password}");
-        let header_value = format!(
-            "Basic {}",
-            base64::engine::general_purpose::STANDARD_NO_PAD.encode(auth)
-        )
-        .parse()?;
-        headers.insert("authorization", header_value);
-        let client = HttpClientBuilder::default()
-            .set_headers(headers.clone())
-            .build(format!("http://{main_addr}"))?;
-        Ok(Drivechain {
+        let auth = MainchainAuth::UserPassword {
+            user: user.into(),
+            password: password.into(),
+        };
+        let backend = JsonRpcBackend::new(&format!("http://{main_addr}"), &auth)?;
+        Ok(Self::with_backend(sidechain_number, Arc::new(backend)))
+    }
+
+    pub fn with_backend(sidechain_number: u8, backend: Arc<dyn MainchainBackend>) -> Self {
+        Self::with_rpc_config(sidechain_number, backend, RpcConfig::default())
+    }
+
+    pub fn with_rpc_config(
+        sidechain_number: u8,
+        backend: Arc<dyn MainchainBackend>,
+        rpc_config: RpcConfig,
+    ) -> Self {
+        Drivechain {
             sidechain_number,
-            client,
+            backend,
+            circuit_breaker: retry::CircuitBreaker::new(rpc_config),
+            bundle_payouts: Arc::new(Mutex::new(HashMap::new())),
             _content: PhantomData::default(),
-        })
+        }
     }
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BmmState {
+    Included {
+        main_block_hash: bitcoin::BlockHash,
+    },
+    /// Not included yet, but mainchain may still include it.
+    Pending,
+    /// Not included in any of the blocks it could be included in.
+    Expired,
+}
+
+struct DepositScan<C> {
+    outputs: HashMap<OutPoint, Output<C>>,
+    heights: HashMap<OutPoint, u32>,
+    invalid_deposits: Vec<InvalidDeposit>,
+    /// Last scanned mainchain block.
+    last_block_hash: Option<bitcoin::BlockHash>,
+    ctip: Option<Ctip>,
+    block_heights: HashMap<bitcoin::BlockHash, u32>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("jsonrpsee error")]
@@ -168,4 +573,74 @@ pub enum Error {
     NoNextBlock { prev_main_hash: bitcoin::BlockHash },
     #[error("io error")]
     Io(#[from] std::io::Error),
+    #[error("deposit {txid} has no CTIP output")]
+    NoCtipOutput { txid: bitcoin::Txid },
+    #[error("mainchain call timed out after {timeout:?}")]
+    Timeout { timeout: std::time::Duration },
+    #[error("mainchain unavailable after {consecutive_failures} consecutive failures")]
+    CircuitOpen { consecutive_failures: u32 },
+    #[error("h* {critical_hash} not found in mainchain block {main_block_hash}")]
+    BmmNotFound {
+        critical_hash: bitcoin::BlockHash,
+        main_block_hash: bitcoin::BlockHash,
+    },
+    #[error("BMM request in mainchain block {main_block_hash} doesn't build on {prev_main_hash}")]
+    BmmPrevMismatch {
+        main_block_hash: bitcoin::BlockHash,
+        prev_main_hash: bitcoin::BlockHash,
+    },
+    #[error("block built on mainchain block {prev_main_hash} is not after its parent built on {parent_prev_main_hash}")]
+    BmmOutOfOrder {
+        prev_main_hash: bitcoin::BlockHash,
+        parent_prev_main_hash: bitcoin::BlockHash,
+    },
+}
+
+impl Error {
+    /// Whether the error is likely transient, so the same call may succeed
+    /// if it is retried.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Jsonrpsee(err) => matches!(
+                err,
+                jsonrpsee::core::Error::Transport(_)
+                    | jsonrpsee::core::Error::RequestTimeout
+                    | jsonrpsee::core::Error::RestartNeeded(_)
+                    | jsonrpsee::core::Error::MaxSlotsExceeded
+            ),
+            Self::Io(_) | Self::Timeout { .. } => true,
+            Self::InvalidHeaderValue(_)
+            | Self::AddressParse(_)
+            | Self::BitcoinConsensusEncode(_)
+            | Self::BitcoinHex(_)
+            | Self::Hex(_)
+            | Self::NoNextBlock { .. }
+            | Self::NoCtipOutput { .. }
+            | Self::CircuitOpen { .. }
+            | Self::BmmNotFound { .. }
+            | Self::BmmPrevMismatch { .. }
+            | Self::BmmOutOfOrder { .. } => false,
+        }
+    }
+
+    /// Whether the error is the `verifybmm` response for a block that
+    /// doesn't include the BMM request, as opposed to any other failure.
+    fn is_bmm_not_found(&self) -> bool {
+        match self {
+            Self::Jsonrpsee(jsonrpsee::core::Error::Call(CallError::Custom(error))) => {
+                error.code() == RPC_MISC_ERROR
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the error proves that a sidechain block is not BMMed
+    /// properly, as opposed to mainchain not knowing about it yet or being
+    /// unavailable.
+    pub fn is_invalid_bmm(&self) -> bool {
+        matches!(
+            self,
+            Self::BmmNotFound { .. } | Self::BmmPrevMismatch { .. } | Self::BmmOutOfOrder { .. }
+        )
+    }
 }
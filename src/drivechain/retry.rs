@@ -0,0 +1,298 @@
+//! Timeouts, retries and circuit breaking for mainchain calls.
+use super::Error;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+#[derive(Debug, Clone, Copy)]
+pub struct RpcConfig {
+    /// Timeout of a single attempt.
+    pub timeout: Duration,
+    /// Retries of idempotent calls after the first attempt.
+    pub max_retries: u32,
+    /// Delay before the first retry, doubled after every next one.
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    /// Consecutive retryable failures after which calls fail fast.
+    pub failure_threshold: u32,
+    /// How long calls fail fast before one is let through to probe the
+    /// mainchain again.
+    pub reset_timeout: Duration,
+}
+
+impl Default for RpcConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_secs(30),
+            max_retries: 5,
+            initial_backoff: Duration::from_millis(200),
+            max_backoff: Duration::from_secs(10),
+            failure_threshold: 10,
+            reset_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MainchainHealth {
+    Healthy,
+    /// Last calls failed, but fewer than `failure_threshold` in a row.
+    Degraded {
+        consecutive_failures: u32,
+    },
+    /// Calls fail fast with `Error::CircuitOpen`.
+    Unavailable {
+        consecutive_failures: u32,
+    },
+}
+
+#[derive(Debug, Default)]
+struct CircuitState {
+    consecutive_failures: u32,
+    opened_at: Option<Instant>,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct CircuitBreaker {
+    config: RpcConfig,
+    state: Arc<Mutex<CircuitState>>,
+}
+
+impl CircuitBreaker {
+    pub(super) fn new(config: RpcConfig) -> Self {
+        Self {
+            config,
+            state: Arc::new(Mutex::new(CircuitState::default())),
+        }
+    }
+
+    fn state(&self) -> std::sync::MutexGuard<'_, CircuitState> {
+        self.state.lock().expect("circuit breaker lock poisoned")
+    }
+
+    pub(super) fn health(&self) -> MainchainHealth {
+        let state = self.state();
+        match (state.consecutive_failures, state.opened_at) {
+            (0, _) => MainchainHealth::Healthy,
+            (consecutive_failures, None) => MainchainHealth::Degraded {
+                consecutive_failures,
+            },
+            (consecutive_failures, Some(_)) => MainchainHealth::Unavailable {
+                consecutive_failures,
+            },
+        }
+    }
+
+    fn check(&self) -> Result<(), Error> {
+        let mut state = self.state();
+        if let Some(opened_at) = state.opened_at {
+            if opened_at.elapsed() < self.config.reset_timeout {
+                return Err(Error::CircuitOpen {
+                    consecutive_failures: state.consecutive_failures,
+                });
+            }
+            // Half open, let this call probe the mainchain. If it fails the
+            // circuit opens again for another `reset_timeout`.
+            state.opened_at = Some(Instant::now());
+        }
+        Ok(())
+    }
+
+    fn record(&self, result: Result<(), &Error>) {
+        let mut state = self.state();
+        match result {
+            Ok(()) => *state = CircuitState::default(),
+            // Errors returned by a responsive mainchain say nothing about
+            // its availability.
+            Err(err) if !err.is_retryable() => {}
+            Err(_) => {
+                state.consecutive_failures += 1;
+                if state.consecutive_failures >= self.config.failure_threshold {
+                    state.opened_at = Some(Instant::now());
+                }
+            }
+        }
+    }
+
+    /// Run `call` with a timeout, retrying retryable errors with exponential
+    /// backoff if `idempotent` is set.
+    pub(super) async fn call<T, F, Fut>(&self, idempotent: bool, call: F) -> Result<T, Error>
+    where
+        F: Fn() -> Fut,
+        Fut: Future<Output = Result<T, Error>>,
+    {
+        let mut backoff = self.config.initial_backoff;
+        let mut retries = 0;
+        loop {
+            self.check()?;
+            let result = match tokio::time::timeout(self.config.timeout, call()).await {
+                Ok(result) => result,
+                Err(_) => Err(Error::Timeout {
+                    timeout: self.config.timeout,
+                }),
+            };
+            self.record(result.as_ref().map(|_| ()));
+            match result {
+                Err(err)
+                    if idempotent && err.is_retryable() && retries < self.config.max_retries =>
+                {
+                    println!("mainchain call failed, retrying in {backoff:?}: {err:?}");
+                }
+                result => return result,
+            }
+            tokio::time::sleep(backoff).await;
+            backoff = std::cmp::min(backoff * 2, self.config.max_backoff);
+            retries += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivechain::mock::{FaultyBackend, MockMainchain};
+    use crate::drivechain::{Drivechain, MainchainBackend};
+    use crate::types::bitcoin;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn drivechain(config: RpcConfig) -> (Drivechain<()>, Arc<FaultyBackend>) {
+        let mainchain = MockMainchain::new();
+        mainchain.mine(1);
+        let backend = Arc::new(FaultyBackend::new(Arc::new(mainchain)));
+        let drivechain = Drivechain::with_rpc_config(0, backend.clone(), config);
+        (drivechain, backend)
+    }
+
+    fn is_request_timeout(err: &Error) -> bool {
+        matches!(
+            err,
+            Error::Jsonrpsee(jsonrpsee::core::Error::RequestTimeout)
+        )
+    }
+
+    #[test]
+    fn idempotent_calls_retried_with_backoff() {
+        let (drivechain, backend) = drivechain(RpcConfig {
+            max_retries: 3,
+            initial_backoff: Duration::from_millis(20),
+            max_backoff: Duration::from_millis(30),
+            ..RpcConfig::default()
+        });
+        block_on(async {
+            backend.fail_next(3);
+            let start = Instant::now();
+            drivechain.get_mainchain_tip().await.unwrap();
+            // 20ms, then doubled up to the 30ms maximum.
+            assert!(start.elapsed() >= Duration::from_millis(80));
+            assert_eq!(drivechain.get_health(), MainchainHealth::Healthy);
+
+            backend.fail_next(4);
+            let err = drivechain.get_mainchain_tip().await.unwrap_err();
+            assert!(is_request_timeout(&err));
+            assert_eq!(
+                drivechain.get_health(),
+                MainchainHealth::Degraded {
+                    consecutive_failures: 4
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn non_idempotent_calls_not_retried() {
+        let (drivechain, backend) = drivechain(RpcConfig {
+            initial_backoff: Duration::ZERO,
+            ..RpcConfig::default()
+        });
+        block_on(async {
+            // A retry would reach the mock mainchain and fail differently.
+            backend.fail_next(1);
+            let bundle = bitcoin::Transaction {
+                version: 2,
+                lock_time: bitcoin::absolute::LockTime::ZERO,
+                input: vec![],
+                output: vec![],
+            };
+            let err = drivechain
+                .broadcast_withdrawal_bundle(bundle)
+                .await
+                .unwrap_err();
+            assert!(is_request_timeout(&err));
+        });
+    }
+
+    #[test]
+    fn circuit_opens_after_failure_threshold() {
+        let (drivechain, backend) = drivechain(RpcConfig {
+            max_retries: 0,
+            failure_threshold: 3,
+            ..RpcConfig::default()
+        });
+        block_on(async {
+            backend.set_offline(true);
+            for _ in 0..3 {
+                let err = drivechain.get_mainchain_tip().await.unwrap_err();
+                assert!(is_request_timeout(&err));
+            }
+            assert_eq!(
+                drivechain.get_health(),
+                MainchainHealth::Unavailable {
+                    consecutive_failures: 3
+                }
+            );
+            // Fails fast without calling the mainchain.
+            backend.set_offline(false);
+            let err = drivechain.get_mainchain_tip().await.unwrap_err();
+            assert!(matches!(
+                err,
+                Error::CircuitOpen {
+                    consecutive_failures: 3
+                }
+            ));
+        });
+    }
+
+    #[test]
+    fn half_open_circuit_lets_one_probe_through() {
+        let reset_timeout = Duration::from_millis(50);
+        let (drivechain, backend) = drivechain(RpcConfig {
+            max_retries: 0,
+            failure_threshold: 1,
+            reset_timeout,
+            ..RpcConfig::default()
+        });
+        block_on(async {
+            backend.set_offline(true);
+            drivechain.get_mainchain_tip().await.unwrap_err();
+
+            // The failed probe opens the circuit again.
+            tokio::time::sleep(reset_timeout).await;
+            let err = drivechain.get_mainchain_tip().await.unwrap_err();
+            assert!(is_request_timeout(&err));
+            let err = drivechain.get_mainchain_tip().await.unwrap_err();
+            assert!(matches!(err, Error::CircuitOpen { .. }));
+
+            // Calls made while the probe is running fail fast, the
+            // successful probe closes the circuit.
+            backend.set_offline(false);
+            tokio::time::sleep(reset_timeout).await;
+            drivechain
+                .call(true, || async {
+                    let err = drivechain.get_mainchain_tip().await.unwrap_err();
+                    assert!(matches!(err, Error::CircuitOpen { .. }));
+                    backend.get_best_block_hash().await
+                })
+                .await
+                .unwrap();
+            assert_eq!(drivechain.get_health(), MainchainHealth::Healthy);
+            drivechain.get_mainchain_tip().await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,366 @@
+//! Background tracking of the mainchain tip.
+use super::{retry::CircuitBreaker, Error, MainchainBackend};
+use crate::types::bitcoin;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{broadcast, Notify};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MainchainEvent {
+    /// Block connected to the mainchain tip.
+    NewBlock {
+        block_hash: bitcoin::BlockHash,
+        height: u32,
+    },
+    /// Blocks disconnected from the mainchain tip, newest first. Followed by
+    /// `NewBlock` events for the blocks of the new best chain.
+    Reorg {
+        disconnected: Vec<bitcoin::BlockHash>,
+    },
+    /// The new tip forks off below all of the remembered blocks, so the
+    /// disconnected blocks aren't known. Followed by a `NewBlock` event for
+    /// the new tip only.
+    DeepReorg {
+        /// Last tip seen before the reorg.
+        old_tip: bitcoin::BlockHash,
+        max_reorg_depth: usize,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct WatcherConfig {
+    pub poll_interval: Duration,
+    /// Polling interval is doubled after every failed poll up to this value.
+    pub max_backoff: Duration,
+    /// Number of recent blocks remembered for reorg detection. Deeper reorgs
+    /// are reported as `DeepReorg`.
+    pub max_reorg_depth: usize,
+    /// Events buffered for slow subscribers before they start lagging.
+    pub channel_capacity: usize,
+}
+
+impl Default for WatcherConfig {
+    fn default() -> Self {
+        Self {
+            poll_interval: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+            max_reorg_depth: 100,
+            channel_capacity: 256,
+        }
+    }
+}
+
+/// Polls `getbestblockhash` and broadcasts `MainchainEvent`s. Calls go
+/// through the timeouts and circuit breaker of the `Drivechain` it was
+/// created by.
+#[derive(Clone)]
+pub struct MainchainWatcher {
+    backend: Arc<dyn MainchainBackend>,
+    circuit_breaker: CircuitBreaker,
+    config: WatcherConfig,
+    events: broadcast::Sender<MainchainEvent>,
+    notify: Arc<Notify>,
+    spawned: Arc<AtomicBool>,
+}
+
+impl MainchainWatcher {
+    pub(super) fn new(
+        backend: Arc<dyn MainchainBackend>,
+        circuit_breaker: CircuitBreaker,
+        config: WatcherConfig,
+    ) -> Self {
+        let (events, _) = broadcast::channel(config.channel_capacity);
+        Self {
+            backend,
+            circuit_breaker,
+            config,
+            events,
+            notify: Arc::new(Notify::new()),
+            spawned: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<MainchainEvent> {
+        self.events.subscribe()
+    }
+
+    /// Poll right away instead of waiting for the next interval.
+    ///
+    /// There is no ZMQ support in ddk, an application subscribed to the
+    /// mainchain `hashblock` ZMQ topic can call this on every notification.
+    pub fn notify(&self) {
+        self.notify.notify_one();
+    }
+
+    /// Start polling in a background task. Clones share the task, returns
+    /// `None` if it was already started.
+    pub fn spawn(&self) -> Option<tokio::task::JoinHandle<()>> {
+        if self.spawned.swap(true, Ordering::SeqCst) {
+            return None;
+        }
+        let watcher = self.clone();
+        Some(tokio::spawn(async move { watcher.run().await }))
+    }
+
+    async fn run(self) {
+        // Recent blocks of the mainchain, oldest first.
+        let mut chain = vec![];
+        let mut delay = self.config.poll_interval;
+        loop {
+            match self.poll(&mut chain).await {
+                Ok(()) => delay = self.config.poll_interval,
+                Err(err) => {
+                    println!("failed to poll mainchain tip: {err:?}");
+                    delay = std::cmp::min(delay * 2, self.config.max_backoff);
+                }
+            }
+            let _ = tokio::time::timeout(delay, self.notify.notified()).await;
+        }
+    }
+
+    async fn poll(&self, chain: &mut Vec<(u32, bitcoin::BlockHash)>) -> Result<(), Error> {
+        let tip = self
+            .circuit_breaker
+            .call(true, || self.backend.get_best_block_hash())
+            .await?;
+        if chain.last().map(|(_, block_hash)| *block_hash) == Some(tip) {
+            return Ok(());
+        }
+        // Walk back from the new tip until a remembered block.
+        let mut connected = vec![];
+        let mut block_hash = tip;
+        let fork = loop {
+            if let Some(index) = chain.iter().rposition(|(_, known)| *known == block_hash) {
+                break Some(index);
+            }
+            let block = self
+                .circuit_breaker
+                .call(true, || self.backend.get_block(&block_hash))
+                .await?;
+            connected.push((block.height as u32, block_hash));
+            match block.previousblockhash {
+                Some(prev)
+                    if !chain.is_empty() && connected.len() < self.config.max_reorg_depth =>
+                {
+                    block_hash = prev
+                }
+                _ => break None,
+            }
+        };
+        let disconnected = match fork {
+            Some(index) => chain.split_off(index + 1),
+            None => {
+                // Lost track of the mainchain, start over from the new tip.
+                // Walking back past the old tip height without meeting a
+                // remembered block means the fork is below all of them,
+                // otherwise the tip just moved too far ahead since the last
+                // poll.
+                let lowest = connected.last().map(|(height, _)| *height);
+                if let (Some(&(old_height, old_tip)), Some(lowest)) = (chain.last(), lowest) {
+                    if lowest <= old_height {
+                        println!(
+                            "mainchain reorg deeper than {} blocks",
+                            self.config.max_reorg_depth
+                        );
+                        let _ = self.events.send(MainchainEvent::DeepReorg {
+                            old_tip,
+                            max_reorg_depth: self.config.max_reorg_depth,
+                        });
+                    }
+                }
+                chain.clear();
+                connected.truncate(1);
+                vec![]
+            }
+        };
+        if !disconnected.is_empty() {
+            let disconnected = disconnected
+                .into_iter()
+                .rev()
+                .map(|(_, block_hash)| block_hash)
+                .collect();
+            // Sending only fails if there are no subscribers.
+            let _ = self.events.send(MainchainEvent::Reorg { disconnected });
+        }
+        for (height, block_hash) in connected.into_iter().rev() {
+            chain.push((height, block_hash));
+            let _ = self
+                .events
+                .send(MainchainEvent::NewBlock { block_hash, height });
+        }
+        if chain.len() > self.config.max_reorg_depth {
+            chain.drain(..chain.len() - self.config.max_reorg_depth);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::drivechain::mock::{FaultyBackend, MockMainchain};
+    use crate::drivechain::{Drivechain, MainchainHealth, RpcConfig};
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    fn watcher(mainchain: &MockMainchain, max_reorg_depth: usize) -> MainchainWatcher {
+        let drivechain = Drivechain::<()>::with_backend(0, Arc::new(mainchain.clone()));
+        drivechain.watcher(WatcherConfig {
+            max_reorg_depth,
+            ..WatcherConfig::default()
+        })
+    }
+
+    fn received(events: &mut broadcast::Receiver<MainchainEvent>) -> Vec<MainchainEvent> {
+        std::iter::from_fn(|| events.try_recv().ok()).collect()
+    }
+
+    fn new_block(mainchain: &MockMainchain, block_hash: bitcoin::BlockHash) -> MainchainEvent {
+        let height = block_on(mainchain.get_block(&block_hash)).unwrap().height as u32;
+        MainchainEvent::NewBlock { block_hash, height }
+    }
+
+    #[test]
+    fn new_blocks() {
+        let mainchain = MockMainchain::new();
+        mainchain.mine(3);
+        let watcher = watcher(&mainchain, 10);
+        let mut events = watcher.subscribe();
+        let mut chain = vec![];
+        block_on(watcher.poll(&mut chain)).unwrap();
+        // Only the tip is known on the first poll.
+        assert_eq!(
+            received(&mut events),
+            vec![new_block(&mainchain, mainchain.get_tip())]
+        );
+        block_on(watcher.poll(&mut chain)).unwrap();
+        assert!(received(&mut events).is_empty());
+
+        let mined = mainchain.mine(2);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        let expected: Vec<_> = mined
+            .iter()
+            .map(|block_hash| new_block(&mainchain, *block_hash))
+            .collect();
+        assert_eq!(received(&mut events), expected);
+    }
+
+    #[test]
+    fn reorg_followed_by_new_chain() {
+        let mainchain = MockMainchain::new();
+        mainchain.mine(1);
+        let watcher = watcher(&mainchain, 10);
+        let mut chain = vec![];
+        block_on(watcher.poll(&mut chain)).unwrap();
+        mainchain.mine(3);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        let mut events = watcher.subscribe();
+
+        let disconnected = mainchain.reorg(2);
+        let mined = mainchain.mine(3);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        let mut expected = vec![MainchainEvent::Reorg {
+            disconnected: disconnected.into_iter().rev().collect(),
+        }];
+        expected.extend(
+            mined
+                .iter()
+                .map(|block_hash| new_block(&mainchain, *block_hash)),
+        );
+        assert_eq!(received(&mut events), expected);
+    }
+
+    #[test]
+    fn deep_reorg() {
+        let mainchain = MockMainchain::new();
+        mainchain.mine(5);
+        let watcher = watcher(&mainchain, 3);
+        let mut chain = vec![];
+        block_on(watcher.poll(&mut chain)).unwrap();
+        mainchain.mine(3);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        let old_tip = mainchain.get_tip();
+        let mut events = watcher.subscribe();
+
+        // Forks off below the 3 remembered blocks.
+        mainchain.reorg(4);
+        mainchain.mine(5);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        assert_eq!(
+            received(&mut events),
+            vec![
+                MainchainEvent::DeepReorg {
+                    old_tip,
+                    max_reorg_depth: 3,
+                },
+                new_block(&mainchain, mainchain.get_tip()),
+            ]
+        );
+
+        // Following blocks connect to the new tip again.
+        let mined = mainchain.mine(1);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        assert_eq!(received(&mut events), vec![new_block(&mainchain, mined[0])]);
+    }
+
+    #[test]
+    fn tip_far_ahead_is_not_a_reorg() {
+        let mainchain = MockMainchain::new();
+        mainchain.mine(1);
+        let watcher = watcher(&mainchain, 3);
+        let mut chain = vec![];
+        block_on(watcher.poll(&mut chain)).unwrap();
+        let mut events = watcher.subscribe();
+
+        mainchain.mine(10);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        assert_eq!(
+            received(&mut events),
+            vec![new_block(&mainchain, mainchain.get_tip())]
+        );
+    }
+
+    #[test]
+    fn poll_goes_through_circuit_breaker() {
+        let mainchain = MockMainchain::new();
+        let backend = Arc::new(FaultyBackend::new(Arc::new(mainchain.clone())));
+        let config = RpcConfig {
+            max_retries: 1,
+            initial_backoff: Duration::ZERO,
+            failure_threshold: 3,
+            ..RpcConfig::default()
+        };
+        let drivechain = Drivechain::<()>::with_rpc_config(0, backend.clone(), config);
+        let watcher = drivechain.watcher(WatcherConfig::default());
+        let mut events = watcher.subscribe();
+        let mut chain = vec![];
+
+        // Retried once.
+        backend.fail_next(1);
+        block_on(watcher.poll(&mut chain)).unwrap();
+        assert_eq!(received(&mut events).len(), 1);
+
+        backend.set_offline(true);
+        assert!(block_on(watcher.poll(&mut chain)).is_err());
+        assert!(block_on(watcher.poll(&mut chain)).is_err());
+        assert_eq!(
+            drivechain.get_health(),
+            MainchainHealth::Unavailable {
+                consecutive_failures: 3
+            }
+        );
+        // Fails fast while the circuit is open.
+        backend.set_offline(false);
+        assert!(matches!(
+            block_on(watcher.poll(&mut chain)),
+            Err(Error::CircuitOpen { .. })
+        ));
+    }
+}
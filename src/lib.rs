@@ -12,11 +12,8 @@ pub use bitcoin;
 pub use heed;
 pub use jsonrpsee;
 
-/// Format `str_dest` with the proper `s{sidechain_number}_` prefix and a
+/// Format `address` with the proper `s{sidechain_number}_` prefix and a
 /// checksum postfix for calling createsidechaindeposit on mainchain.
-pub fn format_deposit_address(this_sidechain: u8, str_dest: &str) -> String {
-    let deposit_address: String = format!("s{}_{}_", this_sidechain, str_dest);
-    let hash = sha256::digest(deposit_address.as_bytes()).to_string();
-    let hash: String = hash[..6].into();
-    format!("{}{}", deposit_address, hash)
+pub fn format_deposit_address(this_sidechain: u8, address: types::Address) -> String {
+    types::DepositAddress::new(this_sidechain, address).to_string()
 }
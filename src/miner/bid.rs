@@ -0,0 +1,91 @@
+//! How much to pay mainchain miners for including a BMM request.
+
+/// What a strategy knows when picking a bid, all amounts are in sats.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BidContext {
+    /// Fees of the transactions in the sidechain block, as returned by
+    /// `Node::get_transactions`.
+    pub block_fees: u64,
+    /// Bids of other BMM requests for this sidechain seen on mainchain since
+    /// the last attempt, see `Miner::observe_main_block`.
+    pub observed_bids: Vec<u64>,
+    /// Number of attempts in a row that were not included by mainchain.
+    pub failed_attempts: u32,
+}
+
+pub trait BmmBidStrategy: Send + Sync {
+    fn bid(&self, context: &BidContext) -> u64;
+}
+
+/// Always bid the same amount.
+#[derive(Debug, Clone, Copy)]
+pub struct FixedBid(pub u64);
+
+impl BmmBidStrategy for FixedBid {
+    fn bid(&self, _context: &BidContext) -> u64 {
+        self.0
+    }
+}
+
+/// Bid a share of the block fees, so the miner keeps the rest.
+#[derive(Debug, Clone, Copy)]
+pub struct PercentageOfFees {
+    pub percent: u64,
+}
+
+impl BmmBidStrategy for PercentageOfFees {
+    fn bid(&self, context: &BidContext) -> u64 {
+        context.block_fees.saturating_mul(self.percent) / 100
+    }
+}
+
+/// Outbid the highest observed bid by `increment`, bid `floor` if that is
+/// more or nothing was observed.
+#[derive(Debug, Clone, Copy)]
+pub struct CompetitiveBid {
+    pub increment: u64,
+    pub floor: u64,
+}
+
+impl BmmBidStrategy for CompetitiveBid {
+    fn bid(&self, context: &BidContext) -> u64 {
+        let highest = context.observed_bids.iter().max().copied().unwrap_or(0);
+        std::cmp::max(highest.saturating_add(self.increment), self.floor)
+    }
+}
+
+/// Raise the bid of `strategy` by `step_percent` for every failed attempt in
+/// a row, up to `max_steps` times.
+#[derive(Debug, Clone, Copy)]
+pub struct EscalatingBid<S> {
+    pub strategy: S,
+    pub step_percent: u64,
+    pub max_steps: u32,
+}
+
+impl<S: BmmBidStrategy> BmmBidStrategy for EscalatingBid<S> {
+    fn bid(&self, context: &BidContext) -> u64 {
+        let steps = std::cmp::min(context.failed_attempts, self.max_steps) as u64;
+        let percent = 100 + self.step_percent.saturating_mul(steps);
+        self.strategy.bid(context).saturating_mul(percent) / 100
+    }
+}
+
+/// Upper bounds on BMM spending, checked before every attempt.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BidLimits {
+    /// Larger bids are lowered to this amount.
+    pub max_bid: u64,
+    /// Attempts that would push the bids of included and pending attempts
+    /// over this amount fail with `Error::BidLimitExceeded`.
+    pub max_total_spend: u64,
+}
+
+impl Default for BidLimits {
+    fn default() -> Self {
+        Self {
+            max_bid: u64::MAX,
+            max_total_spend: u64::MAX,
+        }
+    }
+}
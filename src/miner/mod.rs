@@ -0,0 +1,668 @@
+mod bid;
+mod service;
+use crate::drivechain::{BmmState, Drivechain};
+use crate::types::*;
+pub use bid::{
+    BidContext, BidLimits, BmmBidStrategy, CompetitiveBid, EscalatingBid, FixedBid,
+    PercentageOfFees,
+};
+use bitcoin::hashes::Hash as _;
+use heed::types::{OwnedType, SerdeBincode};
+use heed::Database;
+use serde::{Deserialize, Serialize};
+pub use service::{MiningConfig, MiningService, MiningStatus};
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::path::Path;
+use std::str::FromStr as _;
+use std::sync::Arc;
+
+pub use crate::drivechain::{MainClient, MainchainBackend};
+
+/// Block waiting for mainchain to include its BMM request.
+#[derive(Debug, Clone)]
+pub struct BmmAttempt<A, C> {
+    pub header: Header,
+    pub body: Body<A, C>,
+    /// Bid in sats.
+    pub amount: u64,
+}
+
+#[derive(Debug, Clone)]
+pub enum BmmOutcome<A, C> {
+    /// Mainchain included the BMM request, the block can be submitted.
+    Confirmed {
+        attempt: BmmAttempt<A, C>,
+        main_block_hash: bitcoin::BlockHash,
+    },
+    /// Mainchain didn't include the BMM request in time.
+    Expired { attempt: BmmAttempt<A, C> },
+    /// Another attempt on top of the same sidechain block was confirmed.
+    Stale { attempt: BmmAttempt<A, C> },
+    /// The mainchain block the attempt builds on was disconnected.
+    Orphaned { attempt: BmmAttempt<A, C> },
+}
+
+/// All amounts are in sats.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BmmStats {
+    pub attempts: u64,
+    pub confirmed: u64,
+    pub expired: u64,
+    pub stale: u64,
+    pub orphaned: u64,
+    /// Bids of the attempts included by mainchain, bids of requests that are
+    /// not included are never paid.
+    pub total_spent: u64,
+}
+
+/// What `Miner::check_bmm_attempts` found out.
+#[derive(Debug)]
+pub struct BmmCheck<A, C> {
+    pub outcomes: Vec<BmmOutcome<A, C>>,
+    /// Critical hashes of the attempts that couldn't be checked, they stay
+    /// pending.
+    pub errors: Vec<(BlockHash, Error)>,
+}
+
+#[derive(Clone)]
+pub struct Miner<A, C> {
+    pub drivechain: Drivechain<C>,
+    /// Pending attempts by critical hash.
+    attempts: HashMap<BlockHash, BmmAttempt<A, C>>,
+    sidechain_number: u8,
+    bid_strategy: Arc<dyn BmmBidStrategy>,
+    bid_limits: BidLimits,
+    observed_bids: Vec<u64>,
+    failed_attempts: u32,
+    /// Number of mainchain blocks after `prev_main_hash` checked for the BMM
+    /// request before an attempt expires.
+    bmm_expiry: u32,
+    stats: BmmStats,
+    /// Where `stats` are stored, see `open_stats`.
+    stats_db: Option<(heed::Env, Database<OwnedType<u32>, SerdeBincode<BmmStats>>)>,
+}
+
+impl<A: Clone, C: Clone + GetValue + Serialize> Miner<A, C> {
+    pub const DEFAULT_BMM_EXPIRY: u32 = 6;
+    pub const NUM_DBS: u32 = 1;
+
+    pub fn new(
+        sidechain_number: u8,
+        main_addr: SocketAddr,
+        user: &str,
+        password: &str,
+    ) -> Result<Self, Error> {
+        let drivechain = Drivechain::new(sidechain_number, main_addr, user, password)?;
+        Ok(Self::with_drivechain(drivechain))
+    }
+
+    pub fn with_drivechain(drivechain: Drivechain<C>) -> Self {
+        Self {
+            sidechain_number: drivechain.sidechain_number,
+            drivechain,
+            attempts: HashMap::new(),
+            bid_strategy: Arc::new(PercentageOfFees { percent: 50 }),
+            bid_limits: BidLimits::default(),
+            observed_bids: vec![],
+            failed_attempts: 0,
+            bmm_expiry: Self::DEFAULT_BMM_EXPIRY,
+            stats: BmmStats::default(),
+            stats_db: None,
+        }
+    }
+
+    /// Store BMM stats in `datadir`, so `BidLimits::max_total_spend` holds
+    /// across restarts. Stats stored by an earlier run are loaded.
+    pub fn open_stats(&mut self, datadir: &Path) -> Result<(), Error> {
+        let env_path = datadir.join("miner.mdb");
+        std::fs::create_dir_all(&env_path)?;
+        let env = heed::EnvOpenOptions::new()
+            .map_size(1024 * 1024) // 1MB
+            .max_dbs(Self::NUM_DBS)
+            .open(env_path)?;
+        let stats_db = env.create_database(Some("bmm_stats"))?;
+        let txn = env.read_txn()?;
+        if let Some(stats) = stats_db.get(&txn, &0)? {
+            self.stats = stats;
+        }
+        drop(txn);
+        self.stats_db = Some((env, stats_db));
+        Ok(())
+    }
+
+    fn save_stats(&self) -> Result<(), Error> {
+        let Some((env, stats_db)) = &self.stats_db else {
+            return Ok(());
+        };
+        let mut txn = env.write_txn()?;
+        stats_db.put(&mut txn, &0, &self.stats)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Stats are updated after the mainchain calls succeed, failing to store
+    /// them must not lose track of attempts.
+    fn try_save_stats(&self) {
+        if let Err(err) = self.save_stats() {
+            println!("failed to store BMM stats: {err:?}");
+        }
+    }
+
+    pub fn set_bmm_expiry(&mut self, bmm_expiry: u32) {
+        self.bmm_expiry = bmm_expiry;
+    }
+
+    pub fn get_bmm_attempts(&self) -> &HashMap<BlockHash, BmmAttempt<A, C>> {
+        &self.attempts
+    }
+
+    pub fn get_bmm_stats(&self) -> BmmStats {
+        self.stats
+    }
+
+    /// Strategy used by `attempt_bmm_with_fees`, bids half of the block fees
+    /// by default.
+    pub fn set_bid_strategy(&mut self, bid_strategy: Arc<dyn BmmBidStrategy>) {
+        self.bid_strategy = bid_strategy;
+    }
+
+    pub fn set_bid_limits(&mut self, bid_limits: BidLimits) {
+        self.bid_limits = bid_limits;
+    }
+
+    /// Record the bid of a competing BMM request for this sidechain, used by
+    /// `CompetitiveBid`. Observed bids are forgotten once an attempt is
+    /// confirmed or expires.
+    pub fn observe_bmm_bid(&mut self, amount: u64) {
+        self.observed_bids.push(amount);
+    }
+
+    /// Observe the bids of the BMM requests for this sidechain that other
+    /// miners got included in `main_block_hash`.
+    pub async fn observe_main_block(
+        &mut self,
+        main_block_hash: &bitcoin::BlockHash,
+    ) -> Result<(), Error> {
+        let requests = self.drivechain.get_bmm_requests(main_block_hash).await?;
+        for request in requests {
+            let critical_hash = BlockHash::from(request.critical_hash.to_byte_array());
+            if !self.attempts.contains_key(&critical_hash) {
+                self.observe_bmm_bid(request.amount);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_total_spent(&self) -> u64 {
+        self.stats.total_spent
+    }
+
+    /// Bid for a block with `block_fees` in fees, lowered to
+    /// `BidLimits::max_bid`.
+    pub fn get_bid(&self, block_fees: u64) -> u64 {
+        let context = BidContext {
+            block_fees,
+            observed_bids: self.observed_bids.clone(),
+            failed_attempts: self.failed_attempts,
+        };
+        std::cmp::min(self.bid_strategy.bid(&context), self.bid_limits.max_bid)
+    }
+
+    /// Like `attempt_bmm`, with the amount picked by the bid strategy.
+    pub async fn attempt_bmm_with_fees(
+        &mut self,
+        block_fees: u64,
+        height: u32,
+        header: Header,
+        body: Body<A, C>,
+    ) -> Result<u64, Error> {
+        let amount = self.get_bid(block_fees);
+        self.attempt_bmm(amount, height, header, body).await?;
+        Ok(amount)
+    }
+
+    pub async fn generate(&self) -> Result<(), Error> {
+        self.drivechain
+            .call(false, || self.drivechain.backend.generate(1))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn attempt_bmm(
+        &mut self,
+        amount: u64,
+        height: u32,
+        header: Header,
+        body: Body<A, C>,
+    ) -> Result<(), Error> {
+        let body_merkle_root = body.compute_merkle_root();
+        if header.merkle_root != body_merkle_root {
+            return Err(Error::WrongMerkleRoot {
+                header_merkle_root: header.merkle_root,
+                body_merkle_root,
+            });
+        }
+        // Pending attempts may still be included, so their bids count
+        // against the limit.
+        let committed = self
+            .attempts
+            .values()
+            .fold(self.stats.total_spent, |committed, attempt| {
+                committed.saturating_add(attempt.amount)
+            });
+        if committed.saturating_add(amount) > self.bid_limits.max_total_spend {
+            return Err(Error::BidLimitExceeded {
+                amount,
+                committed,
+                max_total_spend: self.bid_limits.max_total_spend,
+            });
+        }
+        let str_hash_prev = header.prev_main_hash.to_string();
+        let critical_hash: [u8; 32] = header.hash().into();
+        let critical_hash = bitcoin::BlockHash::from_byte_array(critical_hash);
+        let value = self
+            .drivechain
+            .call(false, || {
+                self.drivechain.backend.create_bmm_critical_data_tx(
+                    bitcoin::Amount::from_sat(amount),
+                    height,
+                    &critical_hash,
+                    self.sidechain_number,
+                    &str_hash_prev[str_hash_prev.len() - 8..],
+                )
+            })
+            .await?;
+        bitcoin::Txid::from_str(value["txid"]["txid"].as_str().ok_or(Error::InvalidJson)?)
+            .map_err(crate::drivechain::Error::from)?;
+        self.stats.attempts += 1;
+        let attempt = BmmAttempt {
+            header,
+            body,
+            amount,
+        };
+        self.attempts.insert(attempt.header.hash(), attempt);
+        self.try_save_stats();
+        Ok(())
+    }
+
+    /// Check every pending attempt against mainchain and remove the ones
+    /// that are confirmed or expired. When an attempt is confirmed the other
+    /// attempts on top of the same sidechain block are stale, since only one
+    /// of them can extend the sidechain. Attempts are only removed once
+    /// their outcome is known, an error checking one attempt doesn't stop
+    /// the others from being checked.
+    pub async fn check_bmm_attempts(&mut self) -> BmmCheck<A, C> {
+        let mut outcomes = vec![];
+        let mut errors = vec![];
+        let critical_hashes: Vec<BlockHash> = self.attempts.keys().copied().collect();
+        for critical_hash in critical_hashes {
+            let Some(attempt) = self.attempts.get(&critical_hash) else {
+                // Removed as stale.
+                continue;
+            };
+            let state = match self
+                .drivechain
+                .get_bmm_state(&attempt.header, self.bmm_expiry)
+                .await
+            {
+                Ok(state) => state,
+                Err(err) => {
+                    errors.push((critical_hash, err.into()));
+                    continue;
+                }
+            };
+            let outcome = match state {
+                BmmState::Pending => continue,
+                BmmState::Included { main_block_hash } => {
+                    let attempt = self
+                        .attempts
+                        .remove(&critical_hash)
+                        .expect("attempt exists");
+                    self.stats.confirmed += 1;
+                    self.stats.total_spent += attempt.amount;
+                    self.failed_attempts = 0;
+                    let prev_side_hash = attempt.header.prev_side_hash;
+                    let stale: Vec<BlockHash> = self
+                        .attempts
+                        .iter()
+                        .filter(|(_, other)| other.header.prev_side_hash == prev_side_hash)
+                        .map(|(critical_hash, _)| *critical_hash)
+                        .collect();
+                    for critical_hash in stale {
+                        let attempt = self
+                            .attempts
+                            .remove(&critical_hash)
+                            .expect("attempt exists");
+                        self.stats.stale += 1;
+                        outcomes.push(BmmOutcome::Stale { attempt });
+                    }
+                    BmmOutcome::Confirmed {
+                        attempt,
+                        main_block_hash,
+                    }
+                }
+                BmmState::Expired => {
+                    let attempt = self
+                        .attempts
+                        .remove(&critical_hash)
+                        .expect("attempt exists");
+                    self.stats.expired += 1;
+                    self.failed_attempts += 1;
+                    BmmOutcome::Expired { attempt }
+                }
+            };
+            self.observed_bids.clear();
+            outcomes.push(outcome);
+        }
+        if !outcomes.is_empty() {
+            self.try_save_stats();
+        }
+        BmmCheck { outcomes, errors }
+    }
+
+    /// Drop the attempts building on `disconnected` mainchain blocks, their
+    /// BMM requests can't be included in the new best chain. New attempts
+    /// are made on top of the new tip.
+    pub fn drop_orphaned_attempts(
+        &mut self,
+        disconnected: &[bitcoin::BlockHash],
+    ) -> Vec<BmmOutcome<A, C>> {
+        let orphaned: Vec<BlockHash> = self
+            .attempts
+            .iter()
+            .filter(|(_, attempt)| disconnected.contains(&attempt.header.prev_main_hash))
+            .map(|(critical_hash, _)| *critical_hash)
+            .collect();
+        let outcomes: Vec<_> = orphaned
+            .into_iter()
+            .map(|critical_hash| {
+                let attempt = self
+                    .attempts
+                    .remove(&critical_hash)
+                    .expect("attempt exists");
+                self.stats.orphaned += 1;
+                BmmOutcome::Orphaned { attempt }
+            })
+            .collect();
+        if !outcomes.is_empty() {
+            self.try_save_stats();
+        }
+        outcomes
+    }
+
+    /// Check pending attempts and return the block of a confirmed one, if
+    /// any. Use `check_bmm_attempts` to see every outcome.
+    /// Fails only if no attempt is confirmed and some couldn't be checked.
+    pub async fn confirm_bmm(&mut self) -> Result<Option<(Header, Body<A, C>)>, Error> {
+        let BmmCheck { outcomes, errors } = self.check_bmm_attempts().await;
+        let confirmed = outcomes.into_iter().find_map(|outcome| match outcome {
+            BmmOutcome::Confirmed { attempt, .. } => Some((attempt.header, attempt.body)),
+            _ => None,
+        });
+        match (confirmed, errors.into_iter().next()) {
+            (None, Some((_, err))) => Err(err),
+            (confirmed, _) => Ok(confirmed),
+        }
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("drivechain error")]
+    Drivechain(#[from] crate::drivechain::Error),
+    #[error("invalid json")]
+    InvalidJson,
+    #[error("heed error")]
+    Heed(#[from] heed::Error),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("bid of {amount} would exceed total BMM spend limit {max_total_spend}, already spent or bid {committed}")]
+    BidLimitExceeded {
+        amount: u64,
+        /// Spent on included attempts and bid on pending ones.
+        committed: u64,
+        max_total_spend: u64,
+    },
+    #[error(
+        "header merkle root {header_merkle_root} doesn't match body merkle root {body_merkle_root}"
+    )]
+    WrongMerkleRoot {
+        header_merkle_root: MerkleRoot,
+        body_merkle_root: MerkleRoot,
+    },
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::Authorization;
+    use crate::drivechain::mock::{FaultyBackend, MockMainchain};
+    use crate::drivechain::RpcConfig;
+
+    fn block_on<F: std::future::Future>(future: F) -> F::Output {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(future)
+    }
+
+    #[test]
+    fn competing_bids_observed_and_only_included_bids_spent() {
+        let path = std::env::temp_dir().join(format!("ddk-miner-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        let mainchain = MockMainchain::new();
+        let drivechain = Drivechain::with_backend(0, Arc::new(mainchain.clone()));
+        let body = Body::<Authorization, ()>::new(vec![], vec![]);
+        let header = |prev_side_hash: u8, prev_main_hash| Header {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash: BlockHash::from([prev_side_hash; 32]),
+            prev_main_hash,
+        };
+        let stats = block_on(async {
+            let mut miner = Miner::with_drivechain(drivechain.clone());
+            miner.open_stats(&path).unwrap();
+            miner.set_bid_strategy(Arc::new(CompetitiveBid {
+                increment: 1,
+                floor: 0,
+            }));
+            miner.set_bid_limits(BidLimits {
+                max_bid: u64::MAX,
+                max_total_spend: 6_000,
+            });
+
+            // Another miner gets a request for this sidechain included.
+            let tip = drivechain.get_mainchain_tip().await.unwrap().to_string();
+            mainchain
+                .create_bmm_critical_data_tx(
+                    bitcoin::Amount::from_sat(5_000),
+                    1,
+                    &bitcoin::BlockHash::from_byte_array([1; 32]),
+                    0,
+                    &tip[tip.len() - 8..],
+                )
+                .await
+                .unwrap();
+            let main_block_hash = mainchain.mine(1)[0];
+            miner.observe_main_block(&main_block_hash).await.unwrap();
+            assert_eq!(miner.get_bid(0), 5_001);
+
+            let amount = miner
+                .attempt_bmm_with_fees(0, 2, header(2, main_block_hash), body.clone())
+                .await
+                .unwrap();
+            assert_eq!(amount, 5_001);
+            assert_eq!(miner.get_total_spent(), 0);
+            // The pending bid counts against the limit.
+            assert!(matches!(
+                miner
+                    .attempt_bmm(1_000, 2, header(3, main_block_hash), body.clone())
+                    .await,
+                Err(Error::BidLimitExceeded {
+                    committed: 5_001,
+                    ..
+                })
+            ));
+
+            mainchain.mine(1);
+            let check = miner.check_bmm_attempts().await;
+            assert!(check.errors.is_empty());
+            assert!(matches!(check.outcomes[..], [BmmOutcome::Confirmed { .. }]));
+            assert_eq!(miner.get_total_spent(), 5_001);
+            miner.get_bmm_stats()
+        });
+
+        // Stats survive a restart.
+        let mut miner = Miner::<Authorization, ()>::with_drivechain(drivechain);
+        miner.open_stats(&path).unwrap();
+        assert_eq!(miner.get_bmm_stats(), stats);
+        drop(miner);
+        let _ = std::fs::remove_dir_all(&path);
+    }
+
+    #[test]
+    fn failed_check_keeps_attempt_pending() {
+        let mainchain = MockMainchain::new();
+        let backend = Arc::new(FaultyBackend::new(Arc::new(mainchain.clone())));
+        let rpc_config = RpcConfig {
+            max_retries: 0,
+            ..RpcConfig::default()
+        };
+        let drivechain = Drivechain::with_rpc_config(0, backend.clone(), rpc_config);
+        let mut miner = Miner::<Authorization, ()>::with_drivechain(drivechain.clone());
+        let body = Body::new(vec![], vec![]);
+        block_on(async {
+            let mut critical_hashes = vec![];
+            for prev_side_hash in [1, 2] {
+                let header = Header {
+                    merkle_root: body.compute_merkle_root(),
+                    prev_side_hash: BlockHash::from([prev_side_hash; 32]),
+                    prev_main_hash: drivechain.get_mainchain_tip().await.unwrap(),
+                };
+                critical_hashes.push(header.hash());
+                miner
+                    .attempt_bmm(1_000, 1, header, body.clone())
+                    .await
+                    .unwrap();
+                mainchain.mine(1);
+            }
+            // Both attempts are included, checking the first one fails.
+            backend.fail_next(1);
+            let check = miner.check_bmm_attempts().await;
+            assert_eq!(check.errors.len(), 1);
+            assert_eq!(check.outcomes.len(), 1);
+            let failed = check.errors[0].0;
+            assert_eq!(
+                miner.get_bmm_attempts().keys().collect::<Vec<_>>(),
+                vec![&failed]
+            );
+            let check = miner.check_bmm_attempts().await;
+            assert!(check.errors.is_empty());
+            assert!(matches!(
+                &check.outcomes[..],
+                [BmmOutcome::Confirmed { attempt, .. }] if attempt.header.hash() == failed
+            ));
+            assert_eq!(miner.get_bmm_stats().total_spent, 2_000);
+        });
+    }
+
+    #[test]
+    fn wrong_merkle_root_rejected_before_request() {
+        let mainchain = MockMainchain::new();
+        let drivechain = Drivechain::with_backend(0, Arc::new(mainchain.clone()));
+        let mut miner = Miner::<Authorization, ()>::with_drivechain(drivechain.clone());
+        let body = Body::new(vec![], vec![]);
+        block_on(async {
+            let header = Header {
+                merkle_root: MerkleRoot::default(),
+                prev_side_hash: BlockHash::from([1; 32]),
+                prev_main_hash: drivechain.get_mainchain_tip().await.unwrap(),
+            };
+            let err = miner.attempt_bmm(1_000, 1, header, body).await.unwrap_err();
+            assert!(matches!(err, Error::WrongMerkleRoot { .. }));
+            assert!(miner.get_bmm_attempts().is_empty());
+            mainchain.mine(1);
+            let requests = drivechain
+                .get_bmm_requests(&mainchain.get_tip())
+                .await
+                .unwrap();
+            assert!(requests.is_empty());
+        });
+    }
+
+    #[test]
+    fn failed_bmm_request_not_retried() {
+        let mainchain = MockMainchain::new();
+        let backend = Arc::new(FaultyBackend::new(Arc::new(mainchain.clone())));
+        let rpc_config = RpcConfig {
+            initial_backoff: std::time::Duration::ZERO,
+            ..RpcConfig::default()
+        };
+        let drivechain = Drivechain::with_rpc_config(0, backend.clone(), rpc_config);
+        let mut miner = Miner::<Authorization, ()>::with_drivechain(drivechain.clone());
+        let body = Body::new(vec![], vec![]);
+        block_on(async {
+            let header = Header {
+                merkle_root: body.compute_merkle_root(),
+                prev_side_hash: BlockHash::from([1; 32]),
+                prev_main_hash: drivechain.get_mainchain_tip().await.unwrap(),
+            };
+            // Retrying could pay for the same request twice.
+            backend.fail_next(1);
+            let err = miner.attempt_bmm(1_000, 1, header, body).await.unwrap_err();
+            assert!(matches!(
+                err,
+                Error::Drivechain(crate::drivechain::Error::Jsonrpsee(
+                    jsonrpsee::core::Error::RequestTimeout
+                ))
+            ));
+            assert!(miner.get_bmm_attempts().is_empty());
+            // Nor did the mainchain get the request.
+            mainchain.mine(1);
+            let requests = drivechain
+                .get_bmm_requests(&mainchain.get_tip())
+                .await
+                .unwrap();
+            assert!(requests.is_empty());
+        });
+    }
+
+    #[test]
+    fn reorg_drops_orphaned_attempts() {
+        let mainchain = MockMainchain::new();
+        let drivechain = Drivechain::with_backend(0, Arc::new(mainchain.clone()));
+        let mut miner = Miner::<Authorization, ()>::with_drivechain(drivechain);
+        miner.set_bid_limits(BidLimits {
+            max_bid: u64::MAX,
+            max_total_spend: 1_000,
+        });
+        let body = Body::new(vec![], vec![]);
+        block_on(async {
+            let orphaned_tip = mainchain.mine(1)[0];
+            let header = Header {
+                merkle_root: body.compute_merkle_root(),
+                prev_side_hash: BlockHash::from([0; 32]),
+                prev_main_hash: orphaned_tip,
+            };
+            miner
+                .attempt_bmm(1_000, 2, header, body.clone())
+                .await
+                .unwrap();
+            let disconnected = mainchain.reorg(1);
+            assert_eq!(disconnected, vec![orphaned_tip]);
+            let new_tip = mainchain.mine(2)[1];
+            let outcomes = miner.drop_orphaned_attempts(&disconnected);
+            assert!(matches!(outcomes[..], [BmmOutcome::Orphaned { .. }]));
+            assert!(miner.get_bmm_attempts().is_empty());
+            assert_eq!(miner.get_bmm_stats().orphaned, 1);
+            // The orphaned bid no longer counts against the limit.
+            let header = Header {
+                merkle_root: body.compute_merkle_root(),
+                prev_side_hash: BlockHash::from([0; 32]),
+                prev_main_hash: new_tip,
+            };
+            miner.attempt_bmm(1_000, 3, header, body).await.unwrap();
+        });
+    }
+}
@@ -0,0 +1,198 @@
+//! Mining loop driven by mainchain tip notifications.
+use super::{BmmAttempt, BmmOutcome, BmmStats, Miner};
+use crate::drivechain::MainchainEvent;
+use crate::node::{Node, State};
+use crate::types::*;
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast::error::RecvError;
+
+#[derive(Debug, Clone)]
+pub struct MiningConfig {
+    /// Coinbase outputs pay the block fees to this address.
+    pub coinbase_address: Address,
+    /// Maximum number of mempool transactions in a block.
+    pub max_transactions: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MiningStatus {
+    /// Critical hashes and bids of the attempts waiting for mainchain.
+    pub pending_attempts: Vec<(BlockHash, u64)>,
+    pub blocks_mined: u64,
+    /// Confirmed blocks rejected by the node.
+    pub rejected_blocks: u64,
+    pub bmm_stats: BmmStats,
+    pub last_error: Option<String>,
+}
+
+/// Mines a block on top of every new mainchain tip: takes transactions from
+/// the mempool, bids for BMM and submits the block to the node once
+/// mainchain includes the BMM request.
+///
+/// Tip notifications come from the node mainchain watcher, so `Node::run`
+/// must be called for the service to do anything.
+#[derive(Clone)]
+pub struct MiningService<A, C, S> {
+    node: Node<A, C, S>,
+    miner: Arc<tokio::sync::Mutex<Miner<A, C>>>,
+    config: MiningConfig,
+    status: Arc<Mutex<MiningStatus>>,
+}
+
+impl<
+        A: Verify<C>
+            + GetAddress
+            + Clone
+            + Debug
+            + Sync
+            + Send
+            + Serialize
+            + for<'de> Deserialize<'de>
+            + 'static,
+        C: Clone
+            + Debug
+            + Eq
+            + Serialize
+            + for<'de> Deserialize<'de>
+            + Sync
+            + Send
+            + GetValue
+            + 'static,
+        S: Clone + State<A, C> + Send + Sync + 'static,
+    > MiningService<A, C, S>
+{
+    pub fn new(node: Node<A, C, S>, miner: Miner<A, C>, config: MiningConfig) -> Self {
+        Self {
+            node,
+            miner: Arc::new(tokio::sync::Mutex::new(miner)),
+            config,
+            status: Arc::new(Mutex::new(MiningStatus::default())),
+        }
+    }
+
+    fn status(&self) -> std::sync::MutexGuard<'_, MiningStatus> {
+        self.status.lock().expect("mining status lock poisoned")
+    }
+
+    pub fn get_status(&self) -> MiningStatus {
+        self.status().clone()
+    }
+
+    /// Start mining in a background task.
+    pub fn spawn(&self) -> tokio::task::JoinHandle<()> {
+        let service = self.clone();
+        tokio::spawn(async move { service.run().await })
+    }
+
+    async fn run(self) {
+        let mut events = self.node.subscribe_mainchain_events();
+        loop {
+            let (block_hash, height) = match events.recv().await {
+                Ok(MainchainEvent::NewBlock { block_hash, height }) => (block_hash, height),
+                // The new best chain follows as `NewBlock` events, new
+                // attempts are made on top of it.
+                Ok(MainchainEvent::Reorg { disconnected }) => {
+                    self.on_reorg(&disconnected).await;
+                    continue;
+                }
+                // Orphaned attempts aren't known, they expire as the new
+                // best chain grows past them.
+                Ok(MainchainEvent::DeepReorg { .. }) => continue,
+                Err(RecvError::Lagged(skipped)) => {
+                    println!("mining service skipped {skipped} mainchain events");
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            self.on_new_tip(block_hash, height).await;
+        }
+    }
+
+    async fn on_new_tip(&self, main_block_hash: bitcoin::BlockHash, main_height: u32) {
+        let mut miner = self.miner.lock().await;
+        let check = miner.check_bmm_attempts().await;
+        for outcome in check.outcomes {
+            if let BmmOutcome::Confirmed { attempt, .. } = outcome {
+                self.submit(attempt).await;
+            }
+        }
+        let mut result = match check.errors.first() {
+            Some((critical_hash, err)) => Err(format!(
+                "failed to check BMM attempt {critical_hash}: {err:?}"
+            )),
+            None => Ok(()),
+        };
+        // Bid without the competing bids rather than not at all.
+        if let Err(err) = miner.observe_main_block(&main_block_hash).await {
+            println!("failed to observe BMM bids: {err:?}");
+        }
+        if result.is_ok() {
+            result = self.attempt(&mut miner, main_block_hash, main_height).await;
+        }
+        let mut status = self.status();
+        Self::update_status(&mut status, &miner);
+        if let Err(err) = result {
+            println!("{err}");
+            status.last_error = Some(err);
+        }
+    }
+
+    async fn on_reorg(&self, disconnected: &[bitcoin::BlockHash]) {
+        let mut miner = self.miner.lock().await;
+        let orphaned = miner.drop_orphaned_attempts(disconnected);
+        if !orphaned.is_empty() {
+            println!(
+                "dropped {} BMM attempts orphaned by a mainchain reorg",
+                orphaned.len()
+            );
+        }
+        Self::update_status(&mut self.status(), &miner);
+    }
+
+    fn update_status(status: &mut MiningStatus, miner: &Miner<A, C>) {
+        status.bmm_stats = miner.get_bmm_stats();
+        status.pending_attempts = miner
+            .get_bmm_attempts()
+            .iter()
+            .map(|(critical_hash, attempt)| (*critical_hash, attempt.amount))
+            .collect();
+    }
+
+    async fn submit(&self, attempt: BmmAttempt<A, C>) {
+        match self.node.submit_block(&attempt.header, &attempt.body).await {
+            Ok(()) => self.status().blocks_mined += 1,
+            Err(err) => {
+                let err = format!("failed to submit block: {err:?}");
+                println!("{err}");
+                let mut status = self.status();
+                status.rejected_blocks += 1;
+                status.last_error = Some(err);
+            }
+        }
+    }
+
+    async fn attempt(
+        &self,
+        miner: &mut Miner<A, C>,
+        main_block_hash: bitcoin::BlockHash,
+        main_height: u32,
+    ) -> Result<(), String> {
+        let (mut header, body) = self
+            .node
+            .get_block_template(self.config.coinbase_address, self.config.max_transactions)
+            .await
+            .map_err(|err| format!("failed to get block template: {err:?}"))?;
+        // Build on the tip this notification is about, even if mainchain
+        // moved on since.
+        header.prev_main_hash = main_block_hash;
+        let fees = body.get_coinbase_value();
+        // The BMM request must be included in the next mainchain block.
+        miner
+            .attempt_bmm_with_fees(fees, main_height + 1, header, body)
+            .await
+            .map_err(|err| format!("failed to attempt BMM: {err:?}"))?;
+        Ok(())
+    }
+}
@@ -4,10 +4,18 @@ use serde::{Deserialize, Serialize};
 use tokio::sync::RwLock;
 
 pub use quinn;
-use std::collections::HashMap;
-use std::{net::SocketAddr, sync::Arc};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 
-pub const READ_LIMIT: usize = 1024;
+/// Room for the header and the message envelope on top of the body.
+const MESSAGE_OVERHEAD: usize = 64 * 1024;
+
+/// Largest message accepted from peers, enough for a block whose body is
+/// `max_body_size` bytes, see `state::ConsensusParams`.
+pub fn read_limit(max_body_size: u64) -> usize {
+    (max_body_size as usize).saturating_add(MESSAGE_OVERHEAD)
+}
 
 // State.
 // Archive.
@@ -27,12 +35,17 @@ pub struct Net {
     pub client: Endpoint,
     pub server: Endpoint,
     pub peers: Arc<RwLock<HashMap<usize, Peer>>>,
+    /// Addresses of peers that sent invalid blocks.
+    pub banned: Arc<RwLock<HashSet<IpAddr>>>,
+    /// See `read_limit`.
+    pub read_limit: usize,
 }
 
 #[derive(Clone)]
 pub struct Peer {
     pub state: Arc<RwLock<Option<PeerState>>>,
     pub connection: Connection,
+    pub read_limit: usize,
 }
 
 impl Peer {
@@ -53,7 +66,7 @@ impl Peer {
         let message = bincode::serialize(message)?;
         send.write_all(&message).await?;
         send.finish().await?;
-        let response = recv.read_to_end(READ_LIMIT).await?;
+        let response = recv.read_to_end(self.read_limit).await?;
         let response: Response<A, C> = bincode::deserialize(&response)?;
         Ok(response)
     }
@@ -89,17 +102,23 @@ impl Default for PeerState {
 }
 
 impl Net {
-    pub fn new(bind_addr: SocketAddr) -> Result<Self, Error> {
+    pub fn new(bind_addr: SocketAddr, read_limit: usize) -> Result<Self, Error> {
         let (server, _) = make_server_endpoint(bind_addr)?;
         let client = make_client_endpoint("0.0.0.0:0".parse()?)?;
         let peers = Arc::new(RwLock::new(HashMap::new()));
+        let banned = Arc::new(RwLock::new(HashSet::new()));
         Ok(Net {
             server,
             client,
             peers,
+            banned,
+            read_limit,
         })
     }
     pub async fn connect(&self, addr: SocketAddr) -> Result<Peer, Error> {
+        if self.is_banned(addr).await {
+            return Err(Error::Banned(addr));
+        }
         for peer in self.peers.read().await.values() {
             if peer.connection.remote_address() == addr {
                 return Err(Error::AlreadyConnected(addr));
@@ -109,6 +128,7 @@ impl Net {
         let peer = Peer {
             state: Arc::new(RwLock::new(None)),
             connection,
+            read_limit: self.read_limit,
         };
         self.peers
             .write()
@@ -121,6 +141,21 @@ impl Net {
         let peer = self.peers.write().await.remove(&stable_id);
         Ok(peer)
     }
+
+    /// Disconnect the peer and refuse connections from and to its address
+    /// from now on.
+    pub async fn ban(&self, stable_id: usize) -> Option<Peer> {
+        let peer = self.peers.write().await.remove(&stable_id)?;
+        let addr = peer.connection.remote_address();
+        self.banned.write().await.insert(addr.ip());
+        peer.connection.close(quinn::VarInt::from_u32(2), b"banned");
+        println!("banned peer {addr}");
+        Some(peer)
+    }
+
+    pub async fn is_banned(&self, addr: SocketAddr) -> bool {
+        self.banned.read().await.contains(&addr.ip())
+    }
 }
 
 #[allow(unused)]
@@ -225,4 +260,6 @@ pub enum Error {
     Bincode(#[from] bincode::Error),
     #[error("already connected to peer at {0}")]
     AlreadyConnected(SocketAddr),
+    #[error("peer at {0} is banned")]
+    Banned(SocketAddr),
 }
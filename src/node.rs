@@ -19,6 +19,8 @@ pub struct Node<A, C, S> {
     archive: crate::archive::Archive<A, C>,
     mempool: crate::mempool::MemPool<A, C>,
     drivechain: crate::drivechain::Drivechain<C>,
+    mainchain_watcher: crate::drivechain::MainchainWatcher,
+    withdrawal_bundle_alerts: tokio::sync::broadcast::Sender<WithdrawalBundleAlert>,
     env: heed::Env,
 }
 
@@ -50,6 +52,21 @@ impl<
         main_addr: SocketAddr,
         user: &str,
         password: &str,
+    ) -> Result<Self, Error<<S as State<A, C>>::Error>> {
+        let drivechain = crate::drivechain::Drivechain::new(
+            <S as State<A, C>>::THIS_SIDECHAIN,
+            main_addr,
+            user,
+            password,
+        )?;
+        Self::with_drivechain(datadir, bind_addr, drivechain)
+    }
+
+    /// Use a custom mainchain backend, for example a mock mainchain.
+    pub fn with_drivechain(
+        datadir: &Path,
+        bind_addr: SocketAddr,
+        drivechain: crate::drivechain::Drivechain<C>,
     ) -> Result<Self, Error<<S as State<A, C>>::Error>> {
         let env_path = datadir.join("data.mdb");
         // let _ = std::fs::remove_dir_all(&env_path);
@@ -63,17 +80,25 @@ impl<
                     + crate::mempool::MemPool::<A, C>::NUM_DBS,
             )
             .open(env_path)?;
-        let state = crate::state::State::new(&env)?;
+        let state = crate::state::State::new(&env, <S as State<A, C>>::CONSENSUS_PARAMS)?;
         let archive = crate::archive::Archive::new(&env)?;
         let mempool = crate::mempool::MemPool::new(&env)?;
-        let drivechain = crate::drivechain::Drivechain::new(
-            <S as State<A, C>>::THIS_SIDECHAIN,
-            main_addr,
-            user,
-            password,
-        )?;
-        let net = crate::net::Net::new(bind_addr)?;
+        let read_limit = crate::net::read_limit(<S as State<A, C>>::CONSENSUS_PARAMS.max_body_size);
+        let net = crate::net::Net::new(bind_addr, read_limit)?;
         let custom_state = State::new(&env)?;
+        {
+            let mut txn = env.write_txn()?;
+            let height = archive.get_height(&txn)?;
+            let backfilled = state.backfill_utxo_heights(&mut txn, height)?;
+            if backfilled > 0 {
+                println!(
+                    "recorded heights of {backfilled} utxos created before heights were tracked"
+                );
+            }
+            txn.commit()?;
+        }
+        let mainchain_watcher = drivechain.watcher(crate::drivechain::WatcherConfig::default());
+        let (withdrawal_bundle_alerts, _) = tokio::sync::broadcast::channel(16);
         Ok(Self {
             net,
             state,
@@ -81,10 +106,31 @@ impl<
             archive,
             mempool,
             drivechain,
+            mainchain_watcher,
+            withdrawal_bundle_alerts,
             env,
         })
     }
 
+    /// Mainchain block and reorg notifications, sent once `run` is called.
+    pub fn subscribe_mainchain_events(
+        &self,
+    ) -> tokio::sync::broadcast::Receiver<crate::drivechain::MainchainEvent> {
+        self.mainchain_watcher.subscribe()
+    }
+
+    /// Alerts raised by blocks connected from now on, every alert is also
+    /// kept, see `get_withdrawal_bundle_alerts`.
+    pub fn subscribe_withdrawal_bundle_alerts(
+        &self,
+    ) -> tokio::sync::broadcast::Receiver<WithdrawalBundleAlert> {
+        self.withdrawal_bundle_alerts.subscribe()
+    }
+
+    pub fn get_mainchain_health(&self) -> crate::drivechain::MainchainHealth {
+        self.drivechain.get_health()
+    }
+
     pub fn get_height(&self) -> Result<u32, Error<<S as State<A, C>>::Error>> {
         let txn = self.env.read_txn()?;
         Ok(self.archive.get_height(&txn)?)
@@ -102,6 +148,15 @@ impl<
         txn: &RoTxn,
         transaction: &AuthorizedTransaction<A, C>,
     ) -> Result<u64, Error<<S as State<A, C>>::Error>> {
+        self.state
+            .validate_transaction_limits(&transaction.transaction)?;
+        self.state
+            .validate_unique_inputs(&transaction.transaction)?;
+        validate_authorization_count(
+            std::iter::once(&transaction.transaction),
+            transaction.authorizations.len(),
+        )
+        .map_err(crate::state::Error::from)?;
         let filled_transaction = self.state.fill_transaction(txn, &transaction.transaction)?;
         for (authorization, spent_utxo) in transaction
             .authorizations
@@ -116,6 +171,11 @@ impl<
             return Err(crate::state::Error::AuthorizationError.into());
         }
         let height = self.archive.get_height(&txn)?;
+        for input in &transaction.transaction.inputs {
+            self.state.validate_maturity(txn, height, input)?;
+        }
+        self.state
+            .validate_transaction_locks(txn, height, &transaction.transaction)?;
         self.custom_state.validate_filled_transaction(
             txn,
             height,
@@ -161,6 +221,24 @@ impl<
         Ok(spent)
     }
 
+    /// Return outpoints that can't be spent in the next block yet.
+    pub fn get_immature_utxos(
+        &self,
+        outpoints: &[OutPoint],
+    ) -> Result<Vec<OutPoint>, Error<<S as State<A, C>>::Error>> {
+        let txn = self.env.read_txn()?;
+        let height = self.archive.get_height(&txn)?;
+        let mut immature = vec![];
+        for outpoint in outpoints {
+            match self.state.validate_maturity(&txn, height, outpoint) {
+                Ok(()) => {}
+                Err(crate::state::Error::ImmatureUtxo { .. }) => immature.push(*outpoint),
+                Err(err) => return Err(err.into()),
+            }
+        }
+        Ok(immature)
+    }
+
     pub fn get_utxos_by_addresses(
         &self,
         addresses: &HashSet<Address>,
@@ -194,16 +272,31 @@ impl<
         Ok(transactions)
     }
 
+    /// Take up to `number` valid transactions from the mempool, staying within
+    /// the consensus body size and authorization limits. The size estimate
+    /// doesn't include the coinbase, so leave room for it.
     pub fn get_transactions(
         &self,
         number: usize,
     ) -> Result<(Vec<AuthorizedTransaction<A, C>>, u64), Error<<S as State<A, C>>::Error>> {
         let mut txn = self.env.write_txn()?;
         let transactions = self.mempool.take(&txn, number)?;
+        let params = &self.state.params;
         let mut fee: u64 = 0;
         let mut returned_transactions = vec![];
         let mut spent_utxos = HashSet::new();
+        let mut body_size = bincode::serialized_size(&Body::<A, C>::new(vec![], vec![]))?;
+        let mut num_authorizations = 0;
         for transaction in &transactions {
+            // Serialized size of an authorized transaction is an upper bound on
+            // how much it adds to the size of a body.
+            let transaction_size = bincode::serialized_size(transaction)?;
+            if body_size + transaction_size > params.max_body_size
+                || num_authorizations + transaction.authorizations.len()
+                    > params.max_body_authorizations
+            {
+                continue;
+            }
             let inputs: HashSet<_> = transaction.transaction.inputs.iter().copied().collect();
             if !spent_utxos.is_disjoint(&inputs) {
                 println!("UTXO double spent");
@@ -231,6 +324,8 @@ impl<
                 .map(GetValue::get_value)
                 .sum();
             fee += value_in - value_out;
+            body_size += transaction_size;
+            num_authorizations += transaction.authorizations.len();
             returned_transactions.push(transaction.clone());
             spent_utxos.extend(transaction.transaction.inputs.clone());
         }
@@ -238,11 +333,193 @@ impl<
         Ok((returned_transactions, fee))
     }
 
-    pub fn get_pending_withdrawal_bundle(
+    /// Like `validate_transaction`, against the UTXO set updated by the
+    /// transactions already in a block template. Returns the fee.
+    fn validate_template_transaction(
+        &self,
+        txn: &RoTxn,
+        height: u32,
+        body_utxos: &crate::state::BodyUtxos<C>,
+        transaction: &AuthorizedTransaction<A, C>,
+    ) -> Result<u64, Error<<S as State<A, C>>::Error>> {
+        self.state
+            .validate_transaction_limits(&transaction.transaction)?;
+        validate_authorization_count(
+            std::iter::once(&transaction.transaction),
+            transaction.authorizations.len(),
+        )
+        .map_err(crate::state::Error::from)?;
+        let (filled_transaction, fee) = self.state.validate_body_transaction(
+            txn,
+            height,
+            body_utxos,
+            &transaction.transaction,
+        )?;
+        for (authorization, spent_utxo) in transaction
+            .authorizations
+            .iter()
+            .zip(filled_transaction.spent_utxos.iter())
+        {
+            if authorization.get_address() != spent_utxo.address {
+                return Err(crate::state::Error::WrongPubKeyForAddress.into());
+            }
+        }
+        if A::verify_transaction(transaction).is_err() {
+            return Err(crate::state::Error::AuthorizationError.into());
+        }
+        self.custom_state.validate_filled_transaction(
+            txn,
+            height,
+            &self.state,
+            &filled_transaction,
+        )?;
+        Ok(fee)
+    }
+
+    /// Build a block on top of the current sidechain and mainchain tips,
+    /// ready for BMM, with at most `max_transactions` mempool transactions.
+    /// Transactions are added in mempool order, skipping the ones that are
+    /// not valid on top of the ones before them or don't fit in the body
+    /// limits. The coinbase pays all fees to `coinbase_address`.
+    ///
+    /// Custom state body rules are checked once for the whole body, so a
+    /// body they reject fails the template.
+    pub async fn get_block_template(
+        &self,
+        coinbase_address: Address,
+        max_transactions: usize,
+    ) -> Result<(Header, Body<A, C>), Error<<S as State<A, C>>::Error>> {
+        let prev_main_hash = self.drivechain.get_mainchain_tip().await?;
+        let txn = self.env.read_txn()?;
+        let height = self.archive.get_height(&txn)?;
+        let prev_side_hash = self.archive.get_best_hash(&txn)?;
+        let params = &self.state.params;
+        let mut body_utxos = self.state.get_body_utxos(&txn, height)?;
+        let mut transactions = vec![];
+        let mut fees: u64 = 0;
+        // The coinbase output has the same serialized size whatever its value.
+        let mut body_size = bincode::serialized_size(&Body::<A, C>::new(
+            vec![],
+            vec![Output {
+                address: coinbase_address,
+                content: Content::Value(0),
+            }],
+        ))?;
+        let mut num_authorizations = 0;
+        for transaction in self.mempool.take_all(&txn)? {
+            if transactions.len() >= max_transactions {
+                break;
+            }
+            let transaction_size = bincode::serialized_size(&transaction)?;
+            if body_size + transaction_size > params.max_body_size
+                || num_authorizations + transaction.authorizations.len()
+                    > params.max_body_authorizations
+            {
+                continue;
+            }
+            let Ok(fee) =
+                self.validate_template_transaction(&txn, height, &body_utxos, &transaction)
+            else {
+                continue;
+            };
+            body_utxos.connect_transaction(&transaction.transaction);
+            fees += fee;
+            body_size += transaction_size;
+            num_authorizations += transaction.authorizations.len();
+            transactions.push(transaction);
+        }
+        let coinbase = match fees {
+            0 => vec![],
+            _ => vec![Output {
+                address: coinbase_address,
+                content: Content::Value(fees),
+            }],
+        };
+        let body = Body::new(transactions, coinbase);
+        self.state.validate_body(&txn, height, &body)?;
+        self.custom_state
+            .validate_body(&txn, height, &self.state, &body)?;
+        let header = Header {
+            merkle_root: body.compute_merkle_root(),
+            prev_side_hash,
+            prev_main_hash,
+        };
+        Ok((header, body))
+    }
+
+    /// Bundles that are neither confirmed nor failed yet.
+    pub fn get_pending_withdrawal_bundles(
+        &self,
+    ) -> Result<HashMap<bitcoin::Txid, WithdrawalBundleRecord<C>>, Error<<S as State<A, C>>::Error>>
+    {
+        let txn = self.env.read_txn()?;
+        Ok(self.state.get_pending_withdrawal_bundles(&txn)?)
+    }
+
+    /// Last mainchain status of every pending bundle, `None` until mainchain
+    /// knows about the bundle. While upvotes are collected the status is
+    /// `WithdrawalBundleStatus::Pending` with the current work score and the
+    /// number of mainchain blocks left.
+    pub fn get_pending_withdrawal_bundle_statuses(
+        &self,
+    ) -> Result<
+        HashMap<bitcoin::Txid, Option<WithdrawalBundleStatus>>,
+        Error<<S as State<A, C>>::Error>,
+    > {
+        let txn = self.env.read_txn()?;
+        let statuses = self
+            .state
+            .get_pending_withdrawal_bundles(&txn)?
+            .into_iter()
+            .map(|(txid, record)| (txid, record.status))
+            .collect();
+        Ok(statuses)
+    }
+
+    /// Every withdrawal bundle ever created, with its status history.
+    pub fn get_withdrawal_bundles(
+        &self,
+    ) -> Result<HashMap<bitcoin::Txid, WithdrawalBundleRecord<C>>, Error<<S as State<A, C>>::Error>>
+    {
+        let txn = self.env.read_txn()?;
+        Ok(self.state.get_withdrawal_bundles(&txn)?)
+    }
+
+    pub fn get_withdrawal_bundle(
+        &self,
+        txid: &bitcoin::Txid,
+    ) -> Result<Option<WithdrawalBundleRecord<C>>, Error<<S as State<A, C>>::Error>> {
+        let txn = self.env.read_txn()?;
+        Ok(self.state.get_withdrawal_bundle(&txn, txid)?)
+    }
+
+    /// Bundles mainchain reports as confirmed, but paid out differently
+    /// than they were built. Any alert means mainchain and sidechain disagree
+    /// about withdrawals and needs to be investigated.
+    pub fn get_withdrawal_bundle_alerts(
+        &self,
+    ) -> Result<Vec<WithdrawalBundleAlert>, Error<<S as State<A, C>>::Error>> {
+        let txn = self.env.read_txn()?;
+        Ok(self.state.get_withdrawal_bundle_alerts(&txn)?)
+    }
+
+    /// Mainchain deposits whose destination isn't a valid address of this
+    /// sidechain.
+    pub fn get_invalid_deposits(
+        &self,
+    ) -> Result<Vec<InvalidDeposit>, Error<<S as State<A, C>>::Error>> {
+        let txn = self.env.read_txn()?;
+        Ok(self.state.get_invalid_deposits(&txn)?)
+    }
+
+    /// Deposits, withdrawal bundle spends and refunds connected with the
+    /// block at `height`, which are not in its body.
+    pub fn get_peg_utxo_changes(
         &self,
-    ) -> Result<Option<WithdrawalBundle<C>>, Error<<S as State<A, C>>::Error>> {
+        height: u32,
+    ) -> Result<crate::state::PegUtxoChanges<C>, Error<<S as State<A, C>>::Error>> {
         let txn = self.env.read_txn()?;
-        Ok(self.state.get_pending_withdrawal_bundle(&txn)?)
+        Ok(self.state.get_peg_utxo_changes(&txn, height)?)
     }
 
     pub async fn submit_block(
@@ -250,39 +527,89 @@ impl<
         header: &Header,
         body: &Body<A, C>,
     ) -> Result<(), Error<<S as State<A, C>>::Error>> {
-        let last_deposit_block_hash = {
+        let (last_deposit_block_hash, ctip, pending_bundles, parent) = {
             let txn = self.env.read_txn()?;
-            self.state.get_last_deposit_block_hash(&txn)?
+            let height = self.archive.get_height(&txn)?;
+            (
+                self.state.get_last_deposit_block_hash(&txn)?,
+                self.state.get_ctip(&txn)?,
+                self.state.get_pending_withdrawal_bundles(&txn)?,
+                self.archive.get_header(&txn, height)?,
+            )
         };
-        let bundle = {
+        self.drivechain
+            .verify_bmm_chain(header, parent.as_ref())
+            .await?;
+        let alerts = {
             let two_way_peg_data = self
                 .drivechain
-                .get_two_way_peg_data(header.prev_main_hash, last_deposit_block_hash)
+                .get_two_way_peg_data(
+                    header.prev_main_hash,
+                    last_deposit_block_hash,
+                    ctip,
+                    &pending_bundles,
+                )
                 .await?;
             let mut txn = self.env.write_txn()?;
-            self.state.validate_body(&txn, &body)?;
             let height = self.archive.get_height(&txn)?;
+            self.state.validate_body(&txn, height, &body)?;
             self.custom_state
                 .validate_body(&txn, height, &self.state, &body)?;
-            self.state.connect_body(&mut txn, &body)?;
+            self.state.set_main_tip(
+                &mut txn,
+                two_way_peg_data.main_block_height,
+                two_way_peg_data.main_block_time,
+            )?;
+            self.state.connect_body(&mut txn, height, &body)?;
             self.custom_state
                 .connect_body(&mut txn, height, &self.state, &body)?;
-            self.state
-                .connect_two_way_peg_data(&mut txn, &two_way_peg_data, height)?;
-            let bundle = self.state.get_pending_withdrawal_bundle(&txn)?;
+            let alerts =
+                self.state
+                    .connect_two_way_peg_data(&mut txn, &two_way_peg_data, height)?;
             self.archive.append_header(&mut txn, &header)?;
             self.archive.put_body(&mut txn, &header, &body)?;
             for transaction in &body.transactions {
                 self.mempool.delete(&mut txn, &transaction.txid())?;
             }
             txn.commit()?;
-            bundle
+            alerts
+        };
+        for alert in alerts {
+            // Sending only fails if there are no subscribers.
+            let _ = self.withdrawal_bundle_alerts.send(alert);
+        }
+        self.broadcast_withdrawal_bundles().await
+    }
+
+    /// Send pending withdrawal bundles to mainchain. Bundles are sent until
+    /// mainchain reports a status for them.
+    pub async fn broadcast_withdrawal_bundles(
+        &self,
+    ) -> Result<(), Error<<S as State<A, C>>::Error>> {
+        let (height, bundles) = {
+            let txn = self.env.read_txn()?;
+            (
+                self.archive.get_height(&txn)?,
+                self.state.get_pending_withdrawal_bundles(&txn)?,
+            )
         };
-        if let Some(bundle) = bundle {
-            let _ = self
+        for (txid, record) in bundles {
+            if record.status.is_some() {
+                continue;
+            }
+            match self
                 .drivechain
-                .broadcast_withdrawal_bundle(bundle.transaction)
-                .await;
+                .broadcast_withdrawal_bundle(record.bundle.transaction)
+                .await
+            {
+                Ok(()) => {
+                    let mut txn = self.env.write_txn()?;
+                    self.state
+                        .record_withdrawal_bundle_broadcast(&mut txn, &txid, height)?;
+                    txn.commit()?;
+                }
+                Err(err) => println!("failed to broadcast withdrawal bundle {txid}: {err:?}"),
+            }
         }
         Ok(())
     }
@@ -350,7 +677,7 @@ impl<
             .await
             .map_err(crate::net::Error::from)?;
         let data = recv
-            .read_to_end(crate::net::READ_LIMIT)
+            .read_to_end(peer.read_limit)
             .await
             .map_err(crate::net::Error::from)?;
         let message: Request<A, C> = bincode::deserialize(&data)?;
@@ -418,12 +745,39 @@ impl<
     }
 
     pub fn run(&mut self) -> Result<(), Error<<S as State<A, C>>::Error>> {
+        // Broadcast withdrawal bundles as soon as mainchain moves, instead of
+        // waiting for the next sidechain block.
+        if self.mainchain_watcher.spawn().is_some() {
+            let node = self.clone();
+            let mut events = self.mainchain_watcher.subscribe();
+            tokio::spawn(async move {
+                loop {
+                    match events.recv().await {
+                        Ok(crate::drivechain::MainchainEvent::NewBlock { .. })
+                        | Err(tokio::sync::broadcast::error::RecvError::Lagged(_)) => {
+                            if let Err(err) = node.broadcast_withdrawal_bundles().await {
+                                println!("failed to broadcast withdrawal bundles: {err:?}");
+                            }
+                        }
+                        // New blocks of the new best chain follow.
+                        Ok(crate::drivechain::MainchainEvent::Reorg { .. })
+                        | Ok(crate::drivechain::MainchainEvent::DeepReorg { .. }) => {}
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => break,
+                    }
+                }
+            });
+        }
+
         // Listening to connections.
         let node = self.clone();
         tokio::spawn(async move {
             loop {
                 let incoming_conn = node.net.server.accept().await.unwrap();
                 let connection = incoming_conn.await.unwrap();
+                if node.net.is_banned(connection.remote_address()).await {
+                    connection.close(crate::net::quinn::VarInt::from_u32(2), b"banned");
+                    continue;
+                }
                 for peer in node.net.peers.read().await.values() {
                     if peer.connection.remote_address() == connection.remote_address() {
                         println!(
@@ -445,6 +799,7 @@ impl<
                 let peer = crate::net::Peer {
                     state: Arc::new(RwLock::new(None)),
                     connection,
+                    read_limit: node.net.read_limit,
                 };
                 let node0 = node.clone();
                 let peer0 = peer.clone();
@@ -500,6 +855,7 @@ impl<
         let node = self.clone();
         tokio::spawn(async move {
             loop {
+                let mut misbehaving = vec![];
                 for peer in node.net.peers.read().await.values() {
                     if let Some(state) = &peer.state.read().await.as_ref() {
                         let height = {
@@ -514,7 +870,17 @@ impl<
                             match response {
                                 Response::Block { header, body } => {
                                     println!("got new header {:?}", &header);
-                                    node.submit_block(&header, &body).await.unwrap();
+                                    // Failed blocks are requested again on the
+                                    // next iteration, unless they are not
+                                    // BMMed, then the peer is banned.
+                                    match node.submit_block(&header, &body).await {
+                                        Ok(()) => {}
+                                        Err(Error::Drivechain(err)) if err.is_invalid_bmm() => {
+                                            println!("peer sent block with invalid BMM: {err:?}");
+                                            misbehaving.push(peer.connection.stable_id());
+                                        }
+                                        Err(err) => println!("failed to submit block: {err:?}"),
+                                    }
                                 }
                                 Response::NoBlock => {}
                                 Response::TransactionAccepted => {}
@@ -523,6 +889,9 @@ impl<
                         }
                     }
                 }
+                for stable_id in misbehaving {
+                    node.net.ban(stable_id).await;
+                }
                 tokio::time::sleep(std::time::Duration::from_secs(1)).await;
             }
         });
@@ -530,6 +899,67 @@ impl<
     }
 }
 
+impl<
+        A: Verify<C>
+            + GetAddress
+            + Clone
+            + Debug
+            + Sync
+            + Send
+            + Serialize
+            + for<'de> Deserialize<'de>
+            + 'static,
+        C: Clone
+            + Debug
+            + Eq
+            + Serialize
+            + for<'de> Deserialize<'de>
+            + Sync
+            + Send
+            + GetValue
+            + 'static,
+        S: Clone + State<A, C> + Send + Sync + 'static,
+    > crate::wallet::ChainSource<A, C> for Node<A, C, S>
+where
+    <S as State<A, C>>::Error: std::error::Error + Send + Sync + 'static,
+{
+    type Error = Error<<S as State<A, C>>::Error>;
+
+    fn get_height(&self) -> Result<u32, Self::Error> {
+        Node::get_height(self)
+    }
+
+    fn get_header(&self, height: u32) -> Result<Option<Header>, Self::Error> {
+        Node::get_header(self, height)
+    }
+
+    fn get_body(&self, height: u32) -> Result<Option<Body<A, C>>, Self::Error> {
+        Node::get_body(self, height)
+    }
+
+    fn get_peg_utxo_changes(
+        &self,
+        height: u32,
+    ) -> Result<crate::state::PegUtxoChanges<C>, Self::Error> {
+        Node::get_peg_utxo_changes(self, height)
+    }
+
+    fn get_utxos_by_addresses(
+        &self,
+        addresses: &HashSet<Address>,
+    ) -> Result<HashMap<OutPoint, Output<C>>, Self::Error> {
+        Node::get_utxos_by_addresses(self, addresses)
+    }
+
+    fn get_all_transactions(&self) -> Result<Vec<AuthorizedTransaction<A, C>>, Self::Error> {
+        Node::get_all_transactions(self)
+    }
+
+    fn get_immature_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<OutPoint>, Self::Error> {
+        Node::get_immature_utxos(self, outpoints)
+    }
+}
+
 pub trait CustomError {}
 
 #[derive(Debug, thiserror::Error)]
@@ -560,6 +990,7 @@ pub trait State<A, C>: Sized {
     type Error: CustomError + Debug + Send + Sync;
     const NUM_DBS: u32;
     const THIS_SIDECHAIN: u8;
+    const CONSENSUS_PARAMS: crate::state::ConsensusParams = crate::state::ConsensusParams::DEFAULT;
     fn new(env: &heed::Env) -> Result<Self, Self::Error>;
     fn validate_filled_transaction(
         &self,
@@ -8,35 +8,187 @@ use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::marker::PhantomData;
 
+/// Consensus limits on block bodies and transactions.
+///
+/// Every node of a sidechain must use the same parameters, they are set
+/// through `node::State::CONSENSUS_PARAMS`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConsensusParams {
+    /// Maximum size of a bincode serialized body in bytes, the network
+    /// message size limit is derived from it, see `net::read_limit`.
+    pub max_body_size: u64,
+    /// Maximum number of inputs in a single transaction.
+    pub max_transaction_inputs: usize,
+    /// Maximum number of outputs in a single transaction.
+    pub max_transaction_outputs: usize,
+    /// Maximum number of authorizations in a body. Every authorization is a
+    /// signature check, so this bounds body verification time.
+    pub max_body_authorizations: usize,
+    /// Number of sidechain blocks that must be connected on top of a block
+    /// (including the block itself) before its coinbase outputs can be spent.
+    pub coinbase_maturity: u32,
+    /// Number of mainchain confirmations a deposit needs before it can be
+    /// spent.
+    pub deposit_confirmations: u32,
+    /// Maximum number of withdrawal bundles waiting for mainchain at the same
+    /// time. Must not exceed what mainchain accepts for one sidechain.
+    pub max_pending_withdrawal_bundles: usize,
+    /// Number of failed bundles a withdrawal can be in before it is refunded
+    /// to its owner as a value output.
+    pub max_withdrawal_bundle_failures: u32,
+}
+
+impl ConsensusParams {
+    pub const DEFAULT: Self = Self {
+        max_body_size: 1024 * 1024,
+        max_transaction_inputs: 1000,
+        max_transaction_outputs: 1000,
+        max_body_authorizations: 10_000,
+        coinbase_maturity: 100,
+        deposit_confirmations: 6,
+        max_pending_withdrawal_bundles: 1,
+        max_withdrawal_bundle_failures: 3,
+    };
+}
+
+impl Default for ConsensusParams {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
+/// Number of confirmations at chain tip `tip` of a utxo created at
+/// `created`, the block that created it counts as the first one.
+fn confirmations(tip: u32, created: u32) -> u32 {
+    (tip + 1).saturating_sub(created)
+}
+
+/// Sidechain and mainchain heights at which a utxo was created.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UtxoHeights {
+    /// Height of the sidechain block that connected the utxo.
+    pub height: u32,
+    /// Height of the mainchain block with the deposit for deposits, and the
+    /// last known mainchain height at the time of connection for other utxos.
+    pub main_height: u32,
+}
+
+/// UTXO set changes of the transactions of a body validated so far, see
+/// `State::validate_body_transaction`.
+#[derive(Debug, Clone)]
+pub struct BodyUtxos<C> {
+    spent: HashSet<OutPoint>,
+    /// Outputs created by transactions earlier in the body.
+    created: HashMap<OutPoint, Output<C>>,
+    /// Heights of the outputs created by the body.
+    heights: UtxoHeights,
+}
+
+impl<C: Clone> BodyUtxos<C> {
+    /// Add a transaction validated with `State::validate_body_transaction`.
+    pub fn connect_transaction(&mut self, transaction: &Transaction<C>) {
+        for input in &transaction.inputs {
+            self.spent.insert(*input);
+            self.created.remove(input);
+        }
+        let txid = transaction.txid();
+        for (vout, output) in transaction.outputs.iter().enumerate() {
+            let outpoint = OutPoint::Regular {
+                txid,
+                vout: vout as u32,
+            };
+            self.created.insert(outpoint, output.clone());
+        }
+    }
+}
+
+/// Utxo set changes connected with a block that are not in its body:
+/// deposits, withdrawals spent by a new bundle, and withdrawals restored or
+/// refunded after their bundle fails, see `State::connect_two_way_peg_data`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct PegUtxoChanges<C> {
+    /// Removed before `created` are added.
+    pub spent: Vec<OutPoint>,
+    pub created: Vec<(OutPoint, Output<C>)>,
+}
+
+impl<C> Default for PegUtxoChanges<C> {
+    fn default() -> Self {
+        Self {
+            spent: vec![],
+            created: vec![],
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct State<A, C> {
+    pub params: ConsensusParams,
     pub utxos: Database<SerdeBincode<OutPoint>, SerdeBincode<Output<C>>>,
-    pub pending_withdrawal_bundle: Database<OwnedType<u32>, SerdeBincode<WithdrawalBundle<C>>>,
+    pub utxo_heights: Database<SerdeBincode<OutPoint>, SerdeBincode<UtxoHeights>>,
+    pub last_main_height: Database<OwnedType<u32>, OwnedType<u32>>,
+    pub last_main_time: Database<OwnedType<u32>, OwnedType<u32>>,
+    /// Every withdrawal bundle ever created.
+    pub withdrawal_bundles:
+        Database<SerdeBincode<bitcoin::Txid>, SerdeBincode<WithdrawalBundleRecord<C>>>,
+    /// Bundles that are neither confirmed nor failed yet.
+    pub pending_withdrawal_bundles: Database<SerdeBincode<bitcoin::Txid>, Unit>,
     pub last_withdrawal_bundle_failure_height: Database<OwnedType<u32>, OwnedType<u32>>,
+    /// Number of failed bundles each withdrawal utxo was in.
+    pub withdrawal_bundle_failures: Database<SerdeBincode<OutPoint>, OwnedType<u32>>,
+    /// Bundles reported confirmed whose payout doesn't match the bundle.
+    pub withdrawal_bundle_alerts:
+        Database<SerdeBincode<bitcoin::Txid>, SerdeBincode<WithdrawalBundleAlert>>,
+    /// Last mainchain block scanned for deposits.
     pub last_deposit_block: Database<OwnedType<u32>, SerdeBincode<bitcoin::BlockHash>>,
+    pub ctip: Database<OwnedType<u32>, SerdeBincode<Ctip>>,
+    /// Deposits that can't be credited to any address, kept for recovery.
+    pub invalid_deposits: Database<SerdeBincode<bitcoin::OutPoint>, SerdeBincode<InvalidDeposit>>,
+    /// Changes of `connect_two_way_peg_data` by block height, so wallets can
+    /// follow them without scanning the utxo set.
+    pub peg_utxo_changes: Database<OwnedType<u32>, SerdeBincode<PegUtxoChanges<C>>>,
     pub _body: PhantomData<A>,
 }
 
 impl<
-        A: crate::types::Verify<C> + GetAddress,
+        A: crate::types::Verify<C> + GetAddress + Serialize,
         C: GetValue + Debug + Clone + Eq + Serialize + for<'de> Deserialize<'de> + 'static,
     > State<A, C>
 {
-    pub const NUM_DBS: u32 = 5;
+    pub const NUM_DBS: u32 = 14;
     pub const WITHDRAWAL_BUNDLE_FAILURE_GAP: u32 = 4;
 
-    pub fn new(env: &heed::Env) -> Result<Self, Error> {
+    pub fn new(env: &heed::Env, params: ConsensusParams) -> Result<Self, Error> {
         let utxos = env.create_database(Some("utxos"))?;
+        let utxo_heights = env.create_database(Some("utxo_heights"))?;
+        let last_main_height = env.create_database(Some("last_main_height"))?;
+        let last_main_time = env.create_database(Some("last_main_time"))?;
 
-        let pending_withdrawal_bundle = env.create_database(Some("pending_withdrawal_bundle"))?;
+        let withdrawal_bundles = env.create_database(Some("withdrawal_bundles"))?;
+        let pending_withdrawal_bundles = env.create_database(Some("pending_withdrawal_bundles"))?;
         let last_withdrawal_bundle_failure_height =
             env.create_database(Some("last_withdrawal_bundle_failure_height"))?;
+        let withdrawal_bundle_failures = env.create_database(Some("withdrawal_bundle_failures"))?;
+        let withdrawal_bundle_alerts = env.create_database(Some("withdrawal_bundle_alerts"))?;
         let last_deposit_block = env.create_database(Some("last_deposit_block"))?;
+        let ctip = env.create_database(Some("ctip"))?;
+        let invalid_deposits = env.create_database(Some("invalid_deposits"))?;
+        let peg_utxo_changes = env.create_database(Some("peg_utxo_changes"))?;
         Ok(Self {
+            params,
             utxos,
-            pending_withdrawal_bundle,
+            utxo_heights,
+            last_main_height,
+            last_main_time,
+            withdrawal_bundles,
+            pending_withdrawal_bundles,
             last_withdrawal_bundle_failure_height,
+            withdrawal_bundle_failures,
+            withdrawal_bundle_alerts,
             last_deposit_block,
+            ctip,
+            invalid_deposits,
+            peg_utxo_changes,
             _body: PhantomData::default(),
         })
     }
@@ -84,27 +236,196 @@ impl<
         })
     }
 
+    /// Last known mainchain height, updated every time two way peg data is
+    /// connected.
+    pub fn get_last_main_height(&self, txn: &RoTxn) -> Result<u32, Error> {
+        Ok(self.last_main_height.get(txn, &0)?.unwrap_or(0))
+    }
+
+    /// Last known mainchain median time past.
+    pub fn get_last_main_time(&self, txn: &RoTxn) -> Result<u32, Error> {
+        Ok(self.last_main_time.get(txn, &0)?.unwrap_or(0))
+    }
+
+    /// Set the mainchain block a sidechain block builds on. Call it before
+    /// validating the block, so maturity and lock checks use the mainchain
+    /// height and time the block commits to.
+    pub fn set_main_tip(
+        &self,
+        txn: &mut RwTxn,
+        main_height: u32,
+        main_time: u32,
+    ) -> Result<(), Error> {
+        self.last_main_height.put(txn, &0, &main_height)?;
+        self.last_main_time.put(txn, &0, &main_time)?;
+        Ok(())
+    }
+
+    /// Record heights for utxos created before heights were tracked, as if
+    /// they were created in the block at `height`, so they go through the
+    /// full maturity period from now on. Returns the number of utxos updated.
+    pub fn backfill_utxo_heights(&self, txn: &mut RwTxn, height: u32) -> Result<usize, Error> {
+        let heights = UtxoHeights {
+            height,
+            main_height: self.get_last_main_height(txn)?,
+        };
+        let mut missing = vec![];
+        for item in self.utxos.iter(txn)? {
+            let (outpoint, _) = item?;
+            if self.utxo_heights.get(txn, &outpoint)?.is_none() {
+                missing.push(outpoint);
+            }
+        }
+        for outpoint in &missing {
+            self.utxo_heights.put(txn, outpoint, &heights)?;
+        }
+        Ok(missing.len())
+    }
+
+    /// Check that the lock time of `transaction` allows including it in the
+    /// block at `height + 1`.
+    pub fn validate_lock_time(
+        &self,
+        txn: &RoTxn,
+        height: u32,
+        transaction: &Transaction<C>,
+    ) -> Result<(), Error> {
+        if !transaction.relative_locks.is_empty()
+            && transaction.relative_locks.len() != transaction.inputs.len()
+        {
+            return Err(Error::WrongNumberOfRelativeLocks {
+                inputs: transaction.inputs.len(),
+                relative_locks: transaction.relative_locks.len(),
+            });
+        }
+        let reached = match transaction.lock_time {
+            None => true,
+            Some(LockTime::Height(lock_height)) => height + 1 >= lock_height,
+            Some(LockTime::MainHeight(main_height)) => {
+                self.get_last_main_height(txn)? >= main_height
+            }
+            Some(LockTime::MainTime(main_time)) => self.get_last_main_time(txn)? >= main_time,
+        };
+        if !reached {
+            return Err(Error::LockTimeNotReached {
+                lock_time: transaction.lock_time,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check that a utxo created at `heights` is old enough to be spent by an
+    /// input with `relative_lock` in the block at `height + 1`.
+    pub fn validate_relative_lock(
+        &self,
+        txn: &RoTxn,
+        height: u32,
+        outpoint: &OutPoint,
+        relative_lock: &RelativeLock,
+        heights: &UtxoHeights,
+    ) -> Result<(), Error> {
+        let reached = match relative_lock {
+            RelativeLock::Blocks(blocks) => confirmations(height, heights.height) >= *blocks,
+            RelativeLock::MainBlocks(main_blocks) => {
+                confirmations(self.get_last_main_height(txn)?, heights.main_height) >= *main_blocks
+            }
+        };
+        if !reached {
+            return Err(Error::RelativeLockNotReached {
+                outpoint: *outpoint,
+                relative_lock: *relative_lock,
+            });
+        }
+        Ok(())
+    }
+
+    /// Check absolute and relative locks of a transaction spending utxos that
+    /// are already in the UTXO set.
+    pub fn validate_transaction_locks(
+        &self,
+        txn: &RoTxn,
+        height: u32,
+        transaction: &Transaction<C>,
+    ) -> Result<(), Error> {
+        self.validate_lock_time(txn, height, transaction)?;
+        for (input, relative_lock) in transaction.inputs.iter().zip(&transaction.relative_locks) {
+            if let Some(relative_lock) = relative_lock {
+                // Utxos without recorded heights are old enough.
+                if let Some(heights) = self.utxo_heights.get(txn, input)? {
+                    self.validate_relative_lock(txn, height, input, relative_lock, &heights)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Check that `outpoint` can be spent in the block at `height + 1`.
+    ///
+    /// Coinbase outputs need `coinbase_maturity` sidechain blocks and deposits
+    /// need `deposit_confirmations` mainchain confirmations. Coinbase outputs
+    /// and deposits without recorded heights are immature, see
+    /// `backfill_utxo_heights`.
+    pub fn validate_maturity(
+        &self,
+        txn: &RoTxn,
+        height: u32,
+        outpoint: &OutPoint,
+    ) -> Result<(), Error> {
+        let maturity = match outpoint {
+            OutPoint::Regular { .. } | OutPoint::Refund { .. } => return Ok(()),
+            OutPoint::Coinbase { .. } => self.params.coinbase_maturity,
+            OutPoint::Deposit(_) => self.params.deposit_confirmations,
+        };
+        let heights = match self.utxo_heights.get(txn, outpoint)? {
+            Some(heights) => heights,
+            None => {
+                return Err(Error::ImmatureUtxo {
+                    outpoint: *outpoint,
+                    confirmations: 0,
+                    maturity,
+                })
+            }
+        };
+        let confirmations = match outpoint {
+            OutPoint::Deposit(_) => {
+                confirmations(self.get_last_main_height(txn)?, heights.main_height)
+            }
+            _ => confirmations(height, heights.height),
+        };
+        if confirmations < maturity {
+            return Err(Error::ImmatureUtxo {
+                outpoint: *outpoint,
+                confirmations,
+                maturity,
+            });
+        }
+        Ok(())
+    }
+
+    /// Build a withdrawal bundle from all withdrawal utxos.
+    ///
+    /// Withdrawals are aggregated by mainchain output script and added to the
+    /// bundle in `AggregatedWithdrawal` order until the next one would push
+    /// the bundle over the standard transaction weight. Selection depends
+    /// only on the utxo set and `block_height`, so all nodes build the same
+    /// bundle.
+    ///
+    /// Selection stops at the first withdrawal that doesn't fit, smaller
+    /// withdrawals after it wait for a later bundle even if they would fit.
+    /// This is intended, a lower priority withdrawal never goes out before a
+    /// higher priority one.
     fn collect_withdrawal_bundle(
         &self,
         txn: &RoTxn,
         block_height: u32,
     ) -> Result<Option<WithdrawalBundle<C>>, Error> {
         use bitcoin::blockdata::{opcodes, script};
-        // Weight of a bundle with 0 outputs.
-        const BUNDLE_0_WEIGHT: u64 = 504;
-        // Weight of a single output.
-        const OUTPUT_WEIGHT: u64 = 128;
-        // Turns out to be 3121.
-        const MAX_BUNDLE_OUTPUTS: usize = ((bitcoin::policy::MAX_STANDARD_TX_WEIGHT as u64
-            - BUNDLE_0_WEIGHT)
-            / OUTPUT_WEIGHT) as usize;
+        use bitcoin::consensus::encode::VarInt;
+        const MAX_WEIGHT: u64 = bitcoin::policy::MAX_STANDARD_TX_WEIGHT as u64;
 
         // Aggregate all outputs by destination.
-        // destination -> (value, mainchain fee, spent_utxos)
-        let mut address_to_aggregated_withdrawal = HashMap::<
-            bitcoin::Address<bitcoin::address::NetworkUnchecked>,
-            AggregatedWithdrawal<C>,
-        >::new();
+        let mut script_to_aggregated_withdrawal =
+            HashMap::<bitcoin::ScriptBuf, AggregatedWithdrawal<C>>::new();
         for item in self.utxos.iter(txn)? {
             let (outpoint, output) = item?;
             if let Content::Withdrawal {
@@ -113,8 +434,8 @@ impl<
                 main_fee,
             } = output.content
             {
-                let aggregated = address_to_aggregated_withdrawal
-                    .entry(main_address.clone())
+                let aggregated = script_to_aggregated_withdrawal
+                    .entry(main_address.payload.script_pubkey())
                     .or_insert(AggregatedWithdrawal {
                         spent_utxos: HashMap::new(),
                         main_address: main_address.clone(),
@@ -130,27 +451,14 @@ impl<
                 aggregated.spent_utxos.insert(outpoint, output);
             }
         }
-        if address_to_aggregated_withdrawal.is_empty() {
+        if script_to_aggregated_withdrawal.is_empty() {
             return Ok(None);
         }
         let mut aggregated_withdrawals: Vec<_> =
-            address_to_aggregated_withdrawal.into_values().collect();
-        aggregated_withdrawals.sort_by_key(|a| std::cmp::Reverse(a.clone()));
-        let mut fee = 0;
-        let mut spent_utxos = HashMap::<OutPoint, Output<C>>::new();
-        let mut bundle_outputs = vec![];
-        for aggregated in &aggregated_withdrawals {
-            if bundle_outputs.len() > MAX_BUNDLE_OUTPUTS {
-                break;
-            }
-            let bundle_output = bitcoin::TxOut {
-                value: aggregated.value,
-                script_pubkey: aggregated.main_address.payload.script_pubkey(),
-            };
-            spent_utxos.extend(aggregated.spent_utxos.clone());
-            bundle_outputs.push(bundle_output);
-            fee += aggregated.main_fee;
-        }
+            script_to_aggregated_withdrawal.into_values().collect();
+        // Highest priority first.
+        aggregated_withdrawals.sort_by(|a, b| b.cmp(a));
+
         let txin = bitcoin::TxIn {
             script_sig: script::Builder::new()
                 // OP_FALSE == OP_0
@@ -168,53 +476,55 @@ impl<
             value: 0,
             script_pubkey: script,
         };
-        // Create mainchain fee output.
-        let script = script::Builder::new()
-            .push_opcode(opcodes::all::OP_RETURN)
-            .push_slice(fee.to_le_bytes())
-            .into_script();
-        let mainchain_fee_txout = bitcoin::TxOut {
+        let mainchain_fee_txout = |fee: u64| bitcoin::TxOut {
             value: 0,
-            script_pubkey: script,
-        };
-        // Create inputs commitment.
-        let inputs: Vec<OutPoint> = [
-            // Commit to inputs.
-            spent_utxos.keys().copied().collect(),
-            // Commit to block height.
-            vec![OutPoint::Regular {
-                txid: [0; 32].into(),
-                vout: block_height,
-            }],
-        ]
-        .concat();
-        let commitment = crate::types::hash(&inputs);
-        let script = script::Builder::new()
-            .push_opcode(opcodes::all::OP_RETURN)
-            .push_slice(&commitment)
-            .into_script();
-        let inputs_commitment_txout = bitcoin::TxOut {
-            value: 0,
-            script_pubkey: script,
+            script_pubkey: script::Builder::new()
+                .push_opcode(opcodes::all::OP_RETURN)
+                .push_slice(fee.to_le_bytes())
+                .into_script(),
         };
-        let transaction = bitcoin::Transaction {
+        let mut transaction = bitcoin::Transaction {
             version: 2,
             lock_time: bitcoin::blockdata::locktime::absolute::LockTime::ZERO,
             input: vec![txin],
-            output: [
-                vec![
-                    return_dest_txout,
-                    mainchain_fee_txout,
-                    inputs_commitment_txout,
-                ],
-                bundle_outputs,
-            ]
-            .concat(),
+            output: vec![
+                return_dest_txout,
+                mainchain_fee_txout(0),
+                inputs_commitment_txout(&[0; 32]),
+            ],
         };
-        if transaction.weight().to_wu() > bitcoin::policy::MAX_STANDARD_TX_WEIGHT as u64 {
+        // Fee and commitment outputs have a fixed size, so their final
+        // values don't change the weight.
+        let mut weight = transaction.weight().to_wu();
+        let mut fee = 0;
+        let mut spent_utxos = HashMap::<OutPoint, Output<C>>::new();
+        for aggregated in &aggregated_withdrawals {
+            let bundle_output = bitcoin::TxOut {
+                value: aggregated.value,
+                script_pubkey: aggregated.main_address.payload.script_pubkey(),
+            };
+            let num_outputs = transaction.output.len() as u64;
+            let output_count_growth =
+                VarInt(num_outputs + 1).len() as u64 - VarInt(num_outputs).len() as u64;
+            // Outputs are not witness data, every byte weighs 4 units.
+            let output_size = bitcoin::consensus::encode::serialize(&bundle_output).len() as u64;
+            let output_weight = (output_size + output_count_growth) * 4;
+            // Don't skip ahead to smaller withdrawals, see above.
+            if weight + output_weight > MAX_WEIGHT {
+                break;
+            }
+            weight += output_weight;
+            spent_utxos.extend(aggregated.spent_utxos.clone());
+            transaction.output.push(bundle_output);
+            fee += aggregated.main_fee;
+        }
+        let commitment = inputs_commitment(spent_utxos.keys(), block_height);
+        transaction.output[1] = mainchain_fee_txout(fee);
+        transaction.output[2] = inputs_commitment_txout(&commitment);
+        if transaction.weight().to_wu() > MAX_WEIGHT {
             Err(Error::BundleTooHeavy {
                 weight: transaction.weight().to_wu(),
-                max_weight: bitcoin::policy::MAX_STANDARD_TX_WEIGHT as u64,
+                max_weight: MAX_WEIGHT,
             })?;
         }
         Ok(Some(WithdrawalBundle {
@@ -223,11 +533,145 @@ impl<
         }))
     }
 
-    pub fn get_pending_withdrawal_bundle(
+    /// Check that `payout` pays out the withdrawal outputs of the bundle in
+    /// `record` unchanged, with the committed fee and inputs commitment.
+    /// Mainchain adds the CTIP output and may rewrite the return destination
+    /// placeholder, so neither is compared.
+    fn verify_bundle_payout(
+        record: &WithdrawalBundleRecord<C>,
+        payout: Option<&BundlePayout>,
+    ) -> Result<(), PayoutMismatch> {
+        let payout = payout
+            .and_then(|payout| payout.transaction.as_ref())
+            .ok_or(PayoutMismatch::NoPayout)?;
+        let [_return_dest, fee_txout, commitment_txout, withdrawals @ ..] =
+            record.bundle.transaction.output.as_slice()
+        else {
+            return Err(PayoutMismatch::WrongOutputs);
+        };
+        if !payout.output.contains(commitment_txout) {
+            return Err(PayoutMismatch::NoPayout);
+        }
+        if !payout.output.contains(fee_txout) {
+            return Err(PayoutMismatch::WrongFee);
+        }
+        // Withdrawals are aggregated by script, so every output is unique.
+        if withdrawals.is_empty()
+            || withdrawals
+                .iter()
+                .any(|withdrawal| !payout.output.contains(withdrawal))
+        {
+            return Err(PayoutMismatch::WrongOutputs);
+        }
+        Ok(())
+    }
+
+    /// Refund the withdrawals spent by bundle `txid` to their owners, under
+    /// new `OutPoint::Refund` outpoints. The withdrawal utxos stay spent.
+    /// The main fee was paid to sidechain miners when a withdrawal was
+    /// created, so only the value is refunded. Refunds are added to
+    /// `changes`.
+    fn refund_withdrawals<'a>(
+        &self,
+        txn: &mut RwTxn,
+        txid: &bitcoin::Txid,
+        withdrawals: impl IntoIterator<Item = (usize, &'a OutPoint, &'a Output<C>)>,
+        heights: &UtxoHeights,
+        changes: &mut PegUtxoChanges<C>,
+    ) -> Result<(), Error>
+    where
+        C: 'a,
+    {
+        for (vout, outpoint, output) in withdrawals {
+            self.withdrawal_bundle_failures.delete(txn, outpoint)?;
+            self.utxo_heights.delete(txn, outpoint)?;
+            let refund_outpoint = OutPoint::Refund {
+                bundle: *txid,
+                vout: vout as u32,
+            };
+            let refund = Output {
+                address: output.address,
+                content: Content::Value(output.get_value()),
+            };
+            self.utxos.put(txn, &refund_outpoint, &refund)?;
+            self.utxo_heights.put(txn, &refund_outpoint, heights)?;
+            changes.created.push((refund_outpoint, refund));
+        }
+        Ok(())
+    }
+
+    pub fn get_withdrawal_bundle_alerts(
         &self,
         txn: &RoTxn,
-    ) -> Result<Option<WithdrawalBundle<C>>, Error> {
-        Ok(self.pending_withdrawal_bundle.get(txn, &0)?)
+    ) -> Result<Vec<WithdrawalBundleAlert>, Error> {
+        let mut alerts = vec![];
+        for item in self.withdrawal_bundle_alerts.iter(txn)? {
+            let (_, alert) = item?;
+            alerts.push(alert);
+        }
+        Ok(alerts)
+    }
+
+    pub fn get_withdrawal_bundle(
+        &self,
+        txn: &RoTxn,
+        txid: &bitcoin::Txid,
+    ) -> Result<Option<WithdrawalBundleRecord<C>>, Error> {
+        Ok(self.withdrawal_bundles.get(txn, txid)?)
+    }
+
+    pub fn get_withdrawal_bundles(
+        &self,
+        txn: &RoTxn,
+    ) -> Result<HashMap<bitcoin::Txid, WithdrawalBundleRecord<C>>, Error> {
+        let mut bundles = HashMap::new();
+        for item in self.withdrawal_bundles.iter(txn)? {
+            let (txid, record) = item?;
+            bundles.insert(txid, record);
+        }
+        Ok(bundles)
+    }
+
+    pub fn get_pending_withdrawal_bundles(
+        &self,
+        txn: &RoTxn,
+    ) -> Result<HashMap<bitcoin::Txid, WithdrawalBundleRecord<C>>, Error> {
+        let mut bundles = HashMap::new();
+        for item in self.pending_withdrawal_bundles.iter(txn)? {
+            let (txid, ()) = item?;
+            let record = self
+                .withdrawal_bundles
+                .get(txn, &txid)?
+                .ok_or(Error::NoWithdrawalBundle { txid })?;
+            bundles.insert(txid, record);
+        }
+        Ok(bundles)
+    }
+
+    /// Record that this node sent the bundle to mainchain. Only the first
+    /// broadcast is recorded.
+    pub fn record_withdrawal_bundle_broadcast(
+        &self,
+        txn: &mut RwTxn,
+        txid: &bitcoin::Txid,
+        height: u32,
+    ) -> Result<(), Error> {
+        let mut record = self
+            .withdrawal_bundles
+            .get(txn, txid)?
+            .ok_or(Error::NoWithdrawalBundle { txid: *txid })?;
+        if record
+            .history
+            .iter()
+            .any(|event| matches!(event, WithdrawalBundleEvent::Broadcast { .. }))
+        {
+            return Ok(());
+        }
+        record
+            .history
+            .push(WithdrawalBundleEvent::Broadcast { height });
+        self.withdrawal_bundles.put(txn, txid, &record)?;
+        Ok(())
     }
 
     pub fn validate_filled_transaction(
@@ -248,30 +692,130 @@ impl<
         Ok(value_in - value_out)
     }
 
-    pub fn validate_body(&self, txn: &RoTxn, body: &Body<A, C>) -> Result<u64, Error> {
+    pub fn validate_transaction_limits(&self, transaction: &Transaction<C>) -> Result<(), Error> {
+        if transaction.inputs.len() > self.params.max_transaction_inputs {
+            return Err(Error::TooManyInputs {
+                inputs: transaction.inputs.len(),
+                max_inputs: self.params.max_transaction_inputs,
+            });
+        }
+        if transaction.outputs.len() > self.params.max_transaction_outputs {
+            return Err(Error::TooManyOutputs {
+                outputs: transaction.outputs.len(),
+                max_outputs: self.params.max_transaction_outputs,
+            });
+        }
+        Ok(())
+    }
+
+    pub fn validate_body_limits(&self, body: &Body<A, C>) -> Result<(), Error> {
+        let size = bincode::serialized_size(body)?;
+        if size > self.params.max_body_size {
+            return Err(Error::BodyTooLarge {
+                size,
+                max_size: self.params.max_body_size,
+            });
+        }
+        if body.authorizations.len() > self.params.max_body_authorizations {
+            return Err(Error::TooManyAuthorizations {
+                authorizations: body.authorizations.len(),
+                max_authorizations: self.params.max_body_authorizations,
+            });
+        }
+        for transaction in &body.transactions {
+            self.validate_transaction_limits(transaction)?;
+        }
+        Ok(())
+    }
+
+    pub fn validate_unique_inputs(&self, transaction: &Transaction<C>) -> Result<(), Error> {
+        let mut inputs = HashSet::with_capacity(transaction.inputs.len());
+        for input in &transaction.inputs {
+            if !inputs.insert(input) {
+                return Err(Error::DuplicateInput { outpoint: *input });
+            }
+        }
+        Ok(())
+    }
+
+    /// UTXO set changes of an empty body connected at `height + 1`.
+    pub fn get_body_utxos(&self, txn: &RoTxn, height: u32) -> Result<BodyUtxos<C>, Error> {
+        Ok(BodyUtxos {
+            spent: HashSet::new(),
+            created: HashMap::new(),
+            heights: UtxoHeights {
+                height: height + 1,
+                main_height: self.get_last_main_height(txn)?,
+            },
+        })
+    }
+
+    /// Validate a transaction against the UTXO set updated by `body_utxos`,
+    /// without authorizations. Returns the filled transaction and its fee,
+    /// `body_utxos` are left as they are.
+    pub fn validate_body_transaction(
+        &self,
+        txn: &RoTxn,
+        height: u32,
+        body_utxos: &BodyUtxos<C>,
+        transaction: &Transaction<C>,
+    ) -> Result<(FilledTransaction<C>, u64), Error> {
+        self.validate_unique_inputs(transaction)?;
+        self.validate_lock_time(txn, height, transaction)?;
+        let mut spent_utxos = Vec::with_capacity(transaction.inputs.len());
+        for (index, input) in transaction.inputs.iter().enumerate() {
+            if body_utxos.spent.contains(input) {
+                return Err(Error::UtxoDoubleSpent);
+            }
+            let (utxo, heights) = match body_utxos.created.get(input) {
+                Some(utxo) => (utxo.clone(), Some(body_utxos.heights)),
+                None => {
+                    self.validate_maturity(txn, height, input)?;
+                    let utxo = self
+                        .utxos
+                        .get(txn, input)?
+                        .ok_or(Error::NoUtxo { outpoint: *input })?;
+                    (utxo, self.utxo_heights.get(txn, input)?)
+                }
+            };
+            if let (Some(Some(relative_lock)), Some(heights)) =
+                (transaction.relative_locks.get(index), heights)
+            {
+                self.validate_relative_lock(txn, height, input, relative_lock, &heights)?;
+            }
+            spent_utxos.push(utxo);
+        }
+        let filled_transaction = FilledTransaction {
+            spent_utxos,
+            transaction: transaction.clone(),
+        };
+        let fee = self.validate_filled_transaction(&filled_transaction)?;
+        Ok((filled_transaction, fee))
+    }
+
+    /// Transactions in a body are validated in order, against the UTXO set
+    /// updated by every transaction before them. So a transaction can spend
+    /// outputs of earlier transactions in the same body, but not of later ones.
+    pub fn validate_body(&self, txn: &RoTxn, height: u32, body: &Body<A, C>) -> Result<u64, Error> {
+        self.validate_body_limits(body)?;
         let mut coinbase_value: u64 = 0;
         for output in &body.coinbase {
             coinbase_value += output.get_value();
         }
         let mut total_fees: u64 = 0;
-        let mut spent_utxos = HashSet::new();
-        let filled_transactions: Vec<_> = body
-            .transactions
-            .iter()
-            .map(|t| self.fill_transaction(txn, t))
-            .collect::<Result<_, _>>()?;
-        for filled_transaction in &filled_transactions {
-            for input in &filled_transaction.transaction.inputs {
-                if spent_utxos.contains(input) {
-                    return Err(Error::UtxoDoubleSpent);
-                }
-                spent_utxos.insert(*input);
-            }
-            total_fees += self.validate_filled_transaction(filled_transaction)?;
+        let mut body_utxos = self.get_body_utxos(txn, height)?;
+        let mut filled_transactions = Vec::with_capacity(body.transactions.len());
+        for transaction in &body.transactions {
+            let (filled_transaction, fee) =
+                self.validate_body_transaction(txn, height, &body_utxos, transaction)?;
+            body_utxos.connect_transaction(transaction);
+            total_fees += fee;
+            filled_transactions.push(filled_transaction);
         }
         if coinbase_value > total_fees {
             return Err(Error::NotEnoughFees);
         }
+        validate_authorization_count(&body.transactions, body.authorizations.len())?;
         let spent_utxos = filled_transactions
             .iter()
             .flat_map(|t| t.spent_utxos.iter());
@@ -293,18 +837,63 @@ impl<
         Ok(self.last_deposit_block.get(&txn, &0)?)
     }
 
+    pub fn get_ctip(&self, txn: &RoTxn) -> Result<Option<Ctip>, Error> {
+        Ok(self.ctip.get(txn, &0)?)
+    }
+
+    pub fn get_invalid_deposits(&self, txn: &RoTxn) -> Result<Vec<InvalidDeposit>, Error> {
+        let mut invalid_deposits = vec![];
+        for item in self.invalid_deposits.iter(txn)? {
+            let (_, invalid_deposit) = item?;
+            invalid_deposits.push(invalid_deposit);
+        }
+        Ok(invalid_deposits)
+    }
+
+    /// Utxo changes of the two way peg data connected with the block at
+    /// `height`.
+    pub fn get_peg_utxo_changes(
+        &self,
+        txn: &RoTxn,
+        height: u32,
+    ) -> Result<PegUtxoChanges<C>, Error> {
+        Ok(self.peg_utxo_changes.get(txn, &height)?.unwrap_or_default())
+    }
+
+    /// Connect deposits and withdrawal bundle updates with the block at
+    /// `block_height + 1`. The main tip is set by the caller with
+    /// `set_main_tip` once the block body is valid.
     pub fn connect_two_way_peg_data(
         &self,
         txn: &mut RwTxn,
         two_way_peg_data: &TwoWayPegData<C>,
         block_height: u32,
-    ) -> Result<(), Error> {
+    ) -> Result<Vec<WithdrawalBundleAlert>, Error> {
         // Handle deposits.
         if let Some(deposit_block_hash) = two_way_peg_data.deposit_block_hash {
             self.last_deposit_block.put(txn, &0, &deposit_block_hash)?;
         }
+        if let Some(ctip) = &two_way_peg_data.ctip {
+            self.ctip.put(txn, &0, ctip)?;
+        }
+        let mut changes = PegUtxoChanges::default();
         for (outpoint, deposit) in &two_way_peg_data.deposits {
             self.utxos.put(txn, outpoint, deposit)?;
+            changes.created.push((*outpoint, deposit.clone()));
+            let main_height = two_way_peg_data
+                .deposit_heights
+                .get(outpoint)
+                .copied()
+                .unwrap_or(two_way_peg_data.main_block_height);
+            let heights = UtxoHeights {
+                height: block_height + 1,
+                main_height,
+            };
+            self.utxo_heights.put(txn, outpoint, &heights)?;
+        }
+        for invalid_deposit in &two_way_peg_data.invalid_deposits {
+            self.invalid_deposits
+                .put(txn, &invalid_deposit.outpoint, invalid_deposit)?;
         }
 
         // Handle withdrawals.
@@ -314,42 +903,127 @@ impl<
             .unwrap_or(0);
         if (block_height + 1) - last_withdrawal_bundle_failure_height
             > Self::WITHDRAWAL_BUNDLE_FAILURE_GAP
-            && self.pending_withdrawal_bundle.get(txn, &0)?.is_none()
+            && self.pending_withdrawal_bundles.iter(txn)?.count()
+                < self.params.max_pending_withdrawal_bundles
         {
             if let Some(bundle) = self.collect_withdrawal_bundle(txn, block_height + 1)? {
                 for outpoint in bundle.spent_utxos.keys() {
                     self.utxos.delete(txn, outpoint)?;
+                    changes.spent.push(*outpoint);
                 }
-                self.pending_withdrawal_bundle.put(txn, &0, &bundle)?;
+                let txid = bundle.transaction.txid();
+                let record = WithdrawalBundleRecord {
+                    bundle,
+                    status: None,
+                    history: vec![WithdrawalBundleEvent::Created {
+                        height: block_height + 1,
+                    }],
+                };
+                self.withdrawal_bundles.put(txn, &txid, &record)?;
+                self.pending_withdrawal_bundles.put(txn, &txid, &())?;
             }
         }
+        let main_height = two_way_peg_data.main_block_height;
+        let refund_heights = UtxoHeights {
+            height: block_height + 1,
+            main_height,
+        };
+        let mut alerts = vec![];
         for (txid, status) in &two_way_peg_data.bundle_statuses {
-            if let Some(bundle) = self.pending_withdrawal_bundle.get(txn, &0)? {
-                if bundle.transaction.txid() != *txid {
-                    continue;
+            if self.pending_withdrawal_bundles.get(txn, txid)?.is_none() {
+                continue;
+            }
+            let mut record = self
+                .withdrawal_bundles
+                .get(txn, txid)?
+                .ok_or(Error::NoWithdrawalBundle { txid: *txid })?;
+            match status {
+                WithdrawalBundleStatus::Pending { .. } => {
+                    if record.status.is_none() {
+                        record
+                            .history
+                            .push(WithdrawalBundleEvent::Voting { main_height });
+                    }
                 }
-                match status {
-                    WithdrawalBundleStatus::Failed => {
-                        self.last_withdrawal_bundle_failure_height.put(
-                            txn,
-                            &0,
-                            &(block_height + 1),
-                        )?;
-                        self.pending_withdrawal_bundle.delete(txn, &0)?;
-                        for (outpoint, output) in &bundle.spent_utxos {
+                WithdrawalBundleStatus::Failed => {
+                    self.last_withdrawal_bundle_failure_height
+                        .put(txn, &0, &(block_height + 1))?;
+                    self.pending_withdrawal_bundles.delete(txn, txid)?;
+                    let mut refunds = vec![];
+                    for (vout, (outpoint, output)) in
+                        sorted_spent_utxos(&record.bundle).into_iter().enumerate()
+                    {
+                        let failures = self
+                            .withdrawal_bundle_failures
+                            .get(txn, outpoint)?
+                            .unwrap_or(0)
+                            + 1;
+                        if failures < self.params.max_withdrawal_bundle_failures {
+                            self.withdrawal_bundle_failures
+                                .put(txn, outpoint, &failures)?;
                             self.utxos.put(txn, outpoint, output)?;
+                            changes.created.push((*outpoint, output.clone()));
+                            continue;
                         }
+                        refunds.push((vout, outpoint, output));
                     }
-                    WithdrawalBundleStatus::Confirmed => {
-                        self.pending_withdrawal_bundle.delete(txn, &0)?;
+                    self.refund_withdrawals(txn, txid, refunds, &refund_heights, &mut changes)?;
+                    record
+                        .history
+                        .push(WithdrawalBundleEvent::Failed { main_height });
+                }
+                WithdrawalBundleStatus::Confirmed => {
+                    self.pending_withdrawal_bundles.delete(txn, txid)?;
+                    let payout = two_way_peg_data.bundle_payouts.get(txid);
+                    match Self::verify_bundle_payout(&record, payout) {
+                        Ok(()) => {
+                            for outpoint in record.bundle.spent_utxos.keys() {
+                                self.utxo_heights.delete(txn, outpoint)?;
+                                self.withdrawal_bundle_failures.delete(txn, outpoint)?;
+                            }
+                            record
+                                .history
+                                .push(WithdrawalBundleEvent::Confirmed { main_height });
+                        }
+                        // Mainchain reports the bundle as spent, so the
+                        // withdrawals may have been paid out anyway. They stay
+                        // spent, releasing them is up to the operator.
+                        Err(reason) => {
+                            let alert = WithdrawalBundleAlert {
+                                txid: *txid,
+                                main_block_hash: payout.map(|payout| payout.main_block_hash),
+                                main_height,
+                                reason,
+                            };
+                            self.withdrawal_bundle_alerts.put(txn, txid, &alert)?;
+                            alerts.push(alert);
+                            record
+                                .history
+                                .push(WithdrawalBundleEvent::PayoutMismatch { main_height });
+                        }
                     }
                 }
             }
+            record.status = Some(*status);
+            self.withdrawal_bundles.put(txn, txid, &record)?;
         }
-        Ok(())
+        if changes != PegUtxoChanges::default() {
+            self.peg_utxo_changes
+                .put(txn, &(block_height + 1), &changes)?;
+        }
+        Ok(alerts)
     }
 
-    pub fn connect_body(&self, txn: &mut RwTxn, body: &Body<A, C>) -> Result<(), Error> {
+    pub fn connect_body(
+        &self,
+        txn: &mut RwTxn,
+        height: u32,
+        body: &Body<A, C>,
+    ) -> Result<(), Error> {
+        let heights = UtxoHeights {
+            height: height + 1,
+            main_height: self.get_last_main_height(txn)?,
+        };
         let merkle_root = body.compute_merkle_root();
         for (vout, output) in body.coinbase.iter().enumerate() {
             let outpoint = OutPoint::Coinbase {
@@ -357,11 +1031,16 @@ impl<
                 vout: vout as u32,
             };
             self.utxos.put(txn, &outpoint, output)?;
+            self.utxo_heights.put(txn, &outpoint, &heights)?;
         }
         for transaction in &body.transactions {
             let txid = transaction.txid();
             for input in &transaction.inputs {
                 self.utxos.delete(txn, input)?;
+                self.utxo_heights.delete(txn, input)?;
+                // Owners can spend withdrawals that are not in a bundle,
+                // which cancels them.
+                self.withdrawal_bundle_failures.delete(txn, input)?;
             }
             for (vout, output) in transaction.outputs.iter().enumerate() {
                 let outpoint = OutPoint::Regular {
@@ -369,12 +1048,49 @@ impl<
                     vout: vout as u32,
                 };
                 self.utxos.put(txn, &outpoint, output)?;
+                self.utxo_heights.put(txn, &outpoint, &heights)?;
             }
         }
         Ok(())
     }
 }
 
+/// Withdrawals spent by `bundle` in outpoint order, so refund outpoints are
+/// the same on every node.
+fn sorted_spent_utxos<C>(bundle: &WithdrawalBundle<C>) -> Vec<(&OutPoint, &Output<C>)> {
+    let mut spent_utxos: Vec<_> = bundle.spent_utxos.iter().collect();
+    spent_utxos.sort_by_key(|(outpoint, _)| **outpoint);
+    spent_utxos
+}
+
+/// Commitment of a withdrawal bundle to the utxos it spends and the sidechain
+/// height it was collected at.
+fn inputs_commitment<'a>(
+    spent_utxos: impl Iterator<Item = &'a OutPoint>,
+    block_height: u32,
+) -> [u8; 32] {
+    let mut inputs: Vec<OutPoint> = spent_utxos.copied().collect();
+    // Commit to inputs in a canonical order.
+    inputs.sort();
+    // Commit to block height.
+    inputs.push(OutPoint::Regular {
+        txid: [0; 32].into(),
+        vout: block_height,
+    });
+    crate::types::hash(&inputs)
+}
+
+fn inputs_commitment_txout(commitment: &[u8; 32]) -> bitcoin::TxOut {
+    use bitcoin::blockdata::{opcodes, script};
+    bitcoin::TxOut {
+        value: 0,
+        script_pubkey: script::Builder::new()
+            .push_opcode(opcodes::all::OP_RETURN)
+            .push_slice(commitment)
+            .into_script(),
+    }
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("failed to verify authorization")]
@@ -388,10 +1104,793 @@ pub enum Error {
     NotEnoughValueIn,
     #[error("total fees less than coinbase value")]
     NotEnoughFees,
+    #[error("withdrawal bundle {txid} doesn't exist")]
+    NoWithdrawalBundle { txid: bitcoin::Txid },
     #[error("utxo double spent")]
     UtxoDoubleSpent,
+    #[error("utxo {outpoint} is immature {confirmations} < {maturity} confirmations")]
+    ImmatureUtxo {
+        outpoint: OutPoint,
+        confirmations: u32,
+        maturity: u32,
+    },
+    #[error("lock time {lock_time:?} not reached")]
+    LockTimeNotReached { lock_time: Option<LockTime> },
+    #[error("relative lock {relative_lock:?} on {outpoint} not reached")]
+    RelativeLockNotReached {
+        outpoint: OutPoint,
+        relative_lock: RelativeLock,
+    },
+    #[error("wrong number of relative locks {relative_locks} for {inputs} inputs")]
+    WrongNumberOfRelativeLocks {
+        inputs: usize,
+        relative_locks: usize,
+    },
+    #[error("transaction spends {outpoint} more than once")]
+    DuplicateInput { outpoint: OutPoint },
     #[error("wrong public key for address")]
     WrongPubKeyForAddress,
+    #[error("{0}")]
+    WrongNumberOfAuthorizations(#[from] WrongNumberOfAuthorizations),
     #[error("bundle too heavy {weight} > {max_weight}")]
     BundleTooHeavy { weight: u64, max_weight: u64 },
+    #[error("body too large {size} > {max_size}")]
+    BodyTooLarge { size: u64, max_size: u64 },
+    #[error("too many authorizations in body {authorizations} > {max_authorizations}")]
+    TooManyAuthorizations {
+        authorizations: usize,
+        max_authorizations: usize,
+    },
+    #[error("too many transaction inputs {inputs} > {max_inputs}")]
+    TooManyInputs { inputs: usize, max_inputs: usize },
+    #[error("too many transaction outputs {outputs} > {max_outputs}")]
+    TooManyOutputs { outputs: usize, max_outputs: usize },
+    #[error("bincode error")]
+    Bincode(#[from] bincode::Error),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::{authorize, get_address, Authorization, Keypair};
+    use bitcoin::hashes::Hash as _;
+
+    struct TestState {
+        env: heed::Env,
+        state: State<Authorization, ()>,
+        path: std::path::PathBuf,
+    }
+
+    impl TestState {
+        fn new(name: &str) -> Self {
+            Self::with_params(name, ConsensusParams::DEFAULT)
+        }
+
+        fn with_params(name: &str, params: ConsensusParams) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("ddk-state-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            std::fs::create_dir_all(&path).unwrap();
+            let env = heed::EnvOpenOptions::new()
+                .map_size(10 * 1024 * 1024)
+                .max_dbs(State::<Authorization, ()>::NUM_DBS)
+                .open(&path)
+                .unwrap();
+            let state = State::new(&env, params).unwrap();
+            Self { env, state, path }
+        }
+
+        fn put_utxo(&self, outpoint: OutPoint, output: Output<()>) {
+            let mut txn = self.env.write_txn().unwrap();
+            self.state.utxos.put(&mut txn, &outpoint, &output).unwrap();
+            txn.commit().unwrap();
+        }
+
+        fn put_utxos(&self, utxos: &[(OutPoint, Output<()>)]) {
+            let mut txn = self.env.write_txn().unwrap();
+            for (outpoint, output) in utxos {
+                self.state.utxos.put(&mut txn, outpoint, output).unwrap();
+            }
+            txn.commit().unwrap();
+        }
+
+        fn set_main_tip(&self, main_height: u32, main_time: u32) {
+            let mut txn = self.env.write_txn().unwrap();
+            self.state
+                .set_main_tip(&mut txn, main_height, main_time)
+                .unwrap();
+            txn.commit().unwrap();
+        }
+
+        fn validate_body(&self, body: &Body<Authorization, ()>) -> Result<u64, Error> {
+            let txn = self.env.read_txn().unwrap();
+            self.state.validate_body(&txn, 1, body)
+        }
+    }
+
+    impl Drop for TestState {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    fn keypair(seed: u8) -> Keypair {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[seed; 32]).unwrap();
+        let public = (&secret).into();
+        Keypair { secret, public }
+    }
+
+    fn outpoint(n: u8) -> OutPoint {
+        OutPoint::Regular {
+            txid: Txid([n; 32]),
+            vout: 0,
+        }
+    }
+
+    /// Utxo `n` is worth 100 and belongs to `keypair(n)`.
+    fn fund(state: &TestState, ns: &[u8]) {
+        for n in ns {
+            let output = Output {
+                address: get_address(&keypair(*n).public),
+                content: Content::Value(100),
+            };
+            state.put_utxo(outpoint(*n), output);
+        }
+    }
+
+    /// Transaction spending utxos `ns`, signed by their owners.
+    fn spend(ns: &[u8]) -> AuthorizedTransaction<Authorization, ()> {
+        spend_locked(ns, None, vec![])
+    }
+
+    fn spend_locked(
+        ns: &[u8],
+        lock_time: Option<LockTime>,
+        relative_locks: Vec<Option<RelativeLock>>,
+    ) -> AuthorizedTransaction<Authorization, ()> {
+        let transaction = Transaction {
+            inputs: ns.iter().map(|n| outpoint(*n)).collect(),
+            outputs: vec![Output {
+                address: get_address(&keypair(0).public),
+                content: Content::Value(50 * ns.len() as u64),
+            }],
+            lock_time,
+            relative_locks,
+        };
+        let keypairs: Vec<_> = ns.iter().map(|n| keypair(*n)).collect();
+        let addresses_keypairs: Vec<_> = keypairs
+            .iter()
+            .map(|keypair| (get_address(&keypair.public), keypair))
+            .collect();
+        authorize(&addresses_keypairs, transaction).unwrap()
+    }
+
+    #[test]
+    fn valid_body() {
+        let state = TestState::new("valid_body");
+        fund(&state, &[1, 2, 3]);
+        let body = Body::new(vec![spend(&[1, 2]), spend(&[3])], vec![]);
+        assert_eq!(state.validate_body(&body).unwrap(), 150);
+    }
+
+    #[test]
+    fn empty_body() {
+        let state = TestState::new("empty_body");
+        let body = Body::<Authorization, ()>::new(vec![], vec![]);
+        assert_eq!(state.validate_body(&body).unwrap(), 0);
+    }
+
+    #[test]
+    fn missing_authorization() {
+        let state = TestState::new("missing_authorization");
+        fund(&state, &[1, 2]);
+        let mut body = Body::new(vec![spend(&[1, 2])], vec![]);
+        body.authorizations.pop();
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::WrongNumberOfAuthorizations(
+                WrongNumberOfAuthorizations {
+                    inputs: 2,
+                    authorizations: 1
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn no_authorizations() {
+        let state = TestState::new("no_authorizations");
+        fund(&state, &[1]);
+        let mut body = Body::new(vec![spend(&[1])], vec![]);
+        body.authorizations.clear();
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::WrongNumberOfAuthorizations(
+                WrongNumberOfAuthorizations {
+                    inputs: 1,
+                    authorizations: 0
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn extra_authorization() {
+        let state = TestState::new("extra_authorization");
+        fund(&state, &[1]);
+        let mut body = Body::new(vec![spend(&[1])], vec![]);
+        let extra = body.authorizations[0].clone();
+        body.authorizations.push(extra);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::WrongNumberOfAuthorizations(
+                WrongNumberOfAuthorizations {
+                    inputs: 1,
+                    authorizations: 2
+                }
+            ))
+        ));
+    }
+
+    #[test]
+    fn authorizations_shifted_between_transactions() {
+        // The body total matches, but the first transaction is missing an
+        // authorization and the second one has an extra one.
+        let state = TestState::new("authorizations_shifted");
+        fund(&state, &[1, 2, 3]);
+        let first = spend(&[1, 2]);
+        let mut second = spend(&[3]);
+        second.authorizations.push(second.authorizations[0].clone());
+        let body = Body {
+            coinbase: vec![],
+            transactions: vec![first.transaction, second.transaction],
+            authorizations: vec![
+                first.authorizations[0].clone(),
+                second.authorizations[0].clone(),
+                second.authorizations[1].clone(),
+            ],
+        };
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::WrongPubKeyForAddress)
+        ));
+    }
+
+    #[test]
+    fn authorizations_out_of_order() {
+        let state = TestState::new("authorizations_out_of_order");
+        fund(&state, &[1, 2]);
+        let mut body = Body::new(vec![spend(&[1, 2])], vec![]);
+        body.authorizations.swap(0, 1);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::WrongPubKeyForAddress)
+        ));
+    }
+
+    #[test]
+    fn authorization_for_another_transaction() {
+        // Right key, but the signature is over a different transaction.
+        let state = TestState::new("authorization_for_another_transaction");
+        fund(&state, &[1]);
+        let mut transaction = spend(&[1]);
+        transaction.transaction.outputs[0].content = Content::Value(10);
+        let body = Body::new(vec![transaction], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::AuthorizationError)
+        ));
+    }
+
+    #[test]
+    fn duplicate_input_in_transaction() {
+        let state = TestState::new("duplicate_input_in_transaction");
+        fund(&state, &[1]);
+        let body = Body::new(vec![spend(&[1, 1])], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::DuplicateInput { .. })
+        ));
+    }
+
+    #[test]
+    fn double_spend_in_body() {
+        let state = TestState::new("double_spend_in_body");
+        fund(&state, &[1]);
+        let body = Body::new(vec![spend(&[1]), spend(&[1])], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::UtxoDoubleSpent)
+        ));
+    }
+
+    #[test]
+    fn body_transactions_validated_one_at_a_time() {
+        let state = TestState::new("body_transactions_validated_one_at_a_time");
+        fund(&state, &[1]);
+        let txn = state.env.read_txn().unwrap();
+        let mut body_utxos = state.state.get_body_utxos(&txn, 1).unwrap();
+        let first = spend(&[1]).transaction;
+        let (_, fee) = state
+            .state
+            .validate_body_transaction(&txn, 1, &body_utxos, &first)
+            .unwrap();
+        assert_eq!(fee, 50);
+        body_utxos.connect_transaction(&first);
+        assert!(matches!(
+            state
+                .state
+                .validate_body_transaction(&txn, 1, &body_utxos, &first),
+            Err(Error::UtxoDoubleSpent)
+        ));
+        // Outputs of earlier transactions can be spent.
+        let second = Transaction {
+            inputs: vec![OutPoint::Regular {
+                txid: first.txid(),
+                vout: 0,
+            }],
+            outputs: vec![],
+            lock_time: None,
+            relative_locks: vec![],
+        };
+        let (filled, fee) = state
+            .state
+            .validate_body_transaction(&txn, 1, &body_utxos, &second)
+            .unwrap();
+        assert_eq!(fee, 50);
+        assert_eq!(filled.spent_utxos.len(), 1);
+    }
+
+    #[test]
+    fn body_too_large() {
+        let body = Body::new(vec![spend(&[1, 2]), spend(&[3])], vec![]);
+        let size = bincode::serialized_size(&body).unwrap();
+        let params = ConsensusParams {
+            max_body_size: size,
+            ..ConsensusParams::DEFAULT
+        };
+        let mut state = TestState::with_params("body_too_large", params);
+        fund(&state, &[1, 2, 3]);
+        assert_eq!(state.validate_body(&body).unwrap(), 150);
+        state.state.params.max_body_size = size - 1;
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::BodyTooLarge { size: s, max_size }) if s == size && max_size == size - 1
+        ));
+    }
+
+    #[test]
+    fn too_many_authorizations() {
+        let params = ConsensusParams {
+            max_body_authorizations: 2,
+            ..ConsensusParams::DEFAULT
+        };
+        let state = TestState::with_params("too_many_authorizations", params);
+        fund(&state, &[1, 2, 3]);
+        let body = Body::new(vec![spend(&[1, 2])], vec![]);
+        assert_eq!(state.validate_body(&body).unwrap(), 100);
+        let body = Body::new(vec![spend(&[1, 2]), spend(&[3])], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::TooManyAuthorizations {
+                authorizations: 3,
+                max_authorizations: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn too_many_inputs() {
+        let params = ConsensusParams {
+            max_transaction_inputs: 2,
+            ..ConsensusParams::DEFAULT
+        };
+        let state = TestState::with_params("too_many_inputs", params);
+        fund(&state, &[1, 2, 3]);
+        let body = Body::new(vec![spend(&[1, 2])], vec![]);
+        assert_eq!(state.validate_body(&body).unwrap(), 100);
+        let body = Body::new(vec![spend(&[1, 2, 3])], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::TooManyInputs {
+                inputs: 3,
+                max_inputs: 2
+            })
+        ));
+    }
+
+    #[test]
+    fn too_many_outputs() {
+        let params = ConsensusParams {
+            max_transaction_outputs: 1,
+            ..ConsensusParams::DEFAULT
+        };
+        let state = TestState::with_params("too_many_outputs", params);
+        fund(&state, &[1]);
+        let body = Body::new(vec![spend(&[1])], vec![]);
+        assert_eq!(state.validate_body(&body).unwrap(), 50);
+        // Limits are checked before authorizations.
+        let mut transaction = spend(&[1]);
+        let output = transaction.transaction.outputs[0].clone();
+        transaction.transaction.outputs.push(output);
+        let body = Body::new(vec![transaction], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::TooManyOutputs {
+                outputs: 2,
+                max_outputs: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn missing_utxo() {
+        let state = TestState::new("missing_utxo");
+        let body = Body::new(vec![spend(&[1])], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::NoUtxo { .. })
+        ));
+    }
+
+    #[test]
+    fn maturity_without_heights() {
+        let state = TestState::new("maturity_without_heights");
+        let outpoint = OutPoint::Coinbase {
+            merkle_root: [1; 32].into(),
+            vout: 0,
+        };
+        state.put_utxo(
+            outpoint,
+            Output {
+                address: get_address(&keypair(1).public),
+                content: Content::Value(100),
+            },
+        );
+        let maturity = state.state.params.coinbase_maturity;
+        let txn = state.env.read_txn().unwrap();
+        assert!(matches!(
+            state.state.validate_maturity(&txn, 1000, &outpoint),
+            Err(Error::ImmatureUtxo {
+                confirmations: 0,
+                ..
+            })
+        ));
+        drop(txn);
+
+        let mut txn = state.env.write_txn().unwrap();
+        assert_eq!(state.state.backfill_utxo_heights(&mut txn, 10).unwrap(), 1);
+        txn.commit().unwrap();
+        let txn = state.env.read_txn().unwrap();
+        assert!(state
+            .state
+            .validate_maturity(&txn, 10 + maturity - 2, &outpoint)
+            .is_err());
+        assert!(state
+            .state
+            .validate_maturity(&txn, 10 + maturity - 1, &outpoint)
+            .is_ok());
+    }
+
+    #[test]
+    fn lock_time_not_reached() {
+        let state = TestState::new("lock_time_not_reached");
+        fund(&state, &[1]);
+        state.set_main_tip(10, 1_000);
+        // Bodies are validated for the block at height 2.
+        for (reached, not_reached) in [
+            (LockTime::Height(2), LockTime::Height(3)),
+            (LockTime::MainHeight(10), LockTime::MainHeight(11)),
+            (LockTime::MainTime(1_000), LockTime::MainTime(1_001)),
+        ] {
+            let body = Body::new(vec![spend_locked(&[1], Some(reached), vec![])], vec![]);
+            assert_eq!(state.validate_body(&body).unwrap(), 50);
+            let body = Body::new(vec![spend_locked(&[1], Some(not_reached), vec![])], vec![]);
+            assert!(matches!(
+                state.validate_body(&body),
+                Err(Error::LockTimeNotReached { lock_time: Some(lock_time) })
+                    if lock_time == not_reached
+            ));
+        }
+    }
+
+    #[test]
+    fn relative_lock_not_reached() {
+        let state = TestState::new("relative_lock_not_reached");
+        fund(&state, &[1]);
+        state.set_main_tip(5, 0);
+        let mut txn = state.env.write_txn().unwrap();
+        let heights = UtxoHeights {
+            height: 1,
+            main_height: 5,
+        };
+        state
+            .state
+            .utxo_heights
+            .put(&mut txn, &outpoint(1), &heights)
+            .unwrap();
+        txn.commit().unwrap();
+        // One confirmation on both chains.
+        for (reached, not_reached) in [
+            (RelativeLock::Blocks(1), RelativeLock::Blocks(2)),
+            (RelativeLock::MainBlocks(1), RelativeLock::MainBlocks(2)),
+        ] {
+            let body = Body::new(vec![spend_locked(&[1], None, vec![Some(reached)])], vec![]);
+            assert_eq!(state.validate_body(&body).unwrap(), 50);
+            let body = Body::new(
+                vec![spend_locked(&[1], None, vec![Some(not_reached)])],
+                vec![],
+            );
+            assert!(matches!(
+                state.validate_body(&body),
+                Err(Error::RelativeLockNotReached { outpoint: o, relative_lock })
+                    if o == outpoint(1) && relative_lock == not_reached
+            ));
+        }
+    }
+
+    #[test]
+    fn wrong_number_of_relative_locks() {
+        let state = TestState::new("wrong_number_of_relative_locks");
+        fund(&state, &[1, 2]);
+        let body = Body::new(vec![spend_locked(&[1, 2], None, vec![None])], vec![]);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::WrongNumberOfRelativeLocks {
+                inputs: 2,
+                relative_locks: 1
+            })
+        ));
+    }
+
+    #[test]
+    fn zero_relative_lock_spends_output_of_same_block() {
+        let state = TestState::new("zero_relative_lock_spends_output_of_same_block");
+        fund(&state, &[1]);
+        let first = spend(&[1]);
+        let spend_first = |relative_lock| {
+            let transaction = Transaction {
+                inputs: vec![OutPoint::Regular {
+                    txid: first.transaction.txid(),
+                    vout: 0,
+                }],
+                outputs: vec![],
+                lock_time: None,
+                relative_locks: vec![Some(relative_lock)],
+            };
+            let keypair = keypair(0);
+            authorize(&[(get_address(&keypair.public), &keypair)], transaction).unwrap()
+        };
+        let body = Body::new(
+            vec![first.clone(), spend_first(RelativeLock::Blocks(0))],
+            vec![],
+        );
+        assert_eq!(state.validate_body(&body).unwrap(), 100);
+        let body = Body::new(
+            vec![first.clone(), spend_first(RelativeLock::Blocks(1))],
+            vec![],
+        );
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::RelativeLockNotReached { .. })
+        ));
+    }
+
+    #[test]
+    fn coinbase_above_fees() {
+        let state = TestState::new("coinbase_above_fees");
+        fund(&state, &[1]);
+        let coinbase = vec![Output {
+            address: get_address(&keypair(0).public),
+            content: Content::Value(51),
+        }];
+        let body = Body::new(vec![spend(&[1])], coinbase);
+        assert!(matches!(
+            state.validate_body(&body),
+            Err(Error::NotEnoughFees)
+        ));
+    }
+
+    /// Withdrawal `n` paying `value` to a mainchain address derived from
+    /// `main_address`.
+    fn withdrawal(n: u32, main_address: u32, value: u64, main_fee: u64) -> (OutPoint, Output<()>) {
+        let mut pubkey_hash = [0; 20];
+        pubkey_hash[..4].copy_from_slice(&main_address.to_le_bytes());
+        let main_address = bitcoin::Address::new(
+            bitcoin::Network::Regtest,
+            bitcoin::address::Payload::PubkeyHash(bitcoin::PubkeyHash::from_byte_array(
+                pubkey_hash,
+            )),
+        );
+        let outpoint = OutPoint::Regular {
+            txid: Txid([0; 32]),
+            vout: n,
+        };
+        let output = Output {
+            address: get_address(&keypair(0).public),
+            content: Content::Withdrawal {
+                value,
+                main_fee,
+                main_address: main_address.as_unchecked().clone(),
+            },
+        };
+        (outpoint, output)
+    }
+
+    /// Fisher-Yates shuffle driven by xorshift, so failures are reproducible
+    /// from the seed.
+    fn shuffle<T>(items: &mut [T], seed: u64) {
+        let mut x = seed | 1;
+        for i in (1..items.len()).rev() {
+            x ^= x << 13;
+            x ^= x >> 7;
+            x ^= x << 17;
+            items.swap(i, (x % (i as u64 + 1)) as usize);
+        }
+    }
+
+    fn collect_bundle(name: &str, withdrawals: &[(OutPoint, Output<()>)]) -> WithdrawalBundle<()> {
+        let state = TestState::new(name);
+        state.put_utxos(withdrawals);
+        let txn = state.env.read_txn().unwrap();
+        state
+            .state
+            .collect_withdrawal_bundle(&txn, 1)
+            .unwrap()
+            .unwrap()
+    }
+
+    /// Every permutation of the same withdrawals gives the same bundle.
+    fn assert_same_bundle(name: &str, mut withdrawals: Vec<(OutPoint, Output<()>)>) {
+        let expected = collect_bundle(&format!("{name}-0"), &withdrawals);
+        for seed in 1..=8 {
+            shuffle(&mut withdrawals, seed);
+            let bundle = collect_bundle(&format!("{name}-{seed}"), &withdrawals);
+            assert_eq!(
+                bundle.transaction.txid(),
+                expected.transaction.txid(),
+                "seed {seed}"
+            );
+            assert_eq!(bundle.spent_utxos, expected.spent_utxos, "seed {seed}");
+        }
+    }
+
+    #[test]
+    fn withdrawal_bundle_ignores_insertion_order() {
+        // Ties on fee and value, and several withdrawals to one address.
+        let withdrawals = (0..60)
+            .map(|n| withdrawal(n, n % 7, 1_000 + (n % 3) as u64 * 10, (n % 2) as u64))
+            .collect();
+        assert_same_bundle("bundle_order", withdrawals);
+    }
+
+    #[test]
+    fn withdrawal_bundle_over_weight_ignores_insertion_order() {
+        const MAX_WEIGHT: u64 = bitcoin::policy::MAX_STANDARD_TX_WEIGHT as u64;
+        // More distinct addresses than fit in one bundle.
+        let withdrawals: Vec<_> = (0..4_000)
+            .map(|n| withdrawal(n, n, 1_000 + (n % 13) as u64, (n % 5) as u64))
+            .collect();
+        let bundle = collect_bundle("bundle_weight", &withdrawals);
+        assert!(bundle.spent_utxos.len() < withdrawals.len());
+        assert!(bundle.transaction.weight().to_wu() <= MAX_WEIGHT);
+        assert_same_bundle("bundle_weight_order", withdrawals);
+    }
+
+    fn two_way_peg_data(
+        bundle_statuses: HashMap<bitcoin::Txid, WithdrawalBundleStatus>,
+    ) -> TwoWayPegData<()> {
+        TwoWayPegData {
+            deposits: HashMap::new(),
+            deposit_heights: HashMap::new(),
+            deposit_block_hash: None,
+            invalid_deposits: vec![],
+            ctip: None,
+            main_block_height: 1,
+            main_block_time: 0,
+            bundle_statuses,
+            bundle_payouts: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn refund_after_repeated_failures() {
+        let state = TestState::new("refund_after_repeated_failures");
+        let (outpoint, output) = withdrawal(0, 0, 1_000, 10);
+        state.put_utxos(&[(outpoint, output.clone())]);
+        let mut height = 0;
+        let mut txid = None;
+        for _ in 0..ConsensusParams::DEFAULT.max_withdrawal_bundle_failures {
+            height += State::<Authorization, ()>::WITHDRAWAL_BUNDLE_FAILURE_GAP + 1;
+            let mut txn = state.env.write_txn().unwrap();
+            state
+                .state
+                .connect_two_way_peg_data(&mut txn, &two_way_peg_data(HashMap::new()), height)
+                .unwrap();
+            let pending = state.state.get_pending_withdrawal_bundles(&txn).unwrap();
+            let bundle_txid = *pending.keys().next().expect("bundle collected");
+            let statuses = HashMap::from([(bundle_txid, WithdrawalBundleStatus::Failed)]);
+            state
+                .state
+                .connect_two_way_peg_data(&mut txn, &two_way_peg_data(statuses), height)
+                .unwrap();
+            txn.commit().unwrap();
+            txid = Some(bundle_txid);
+        }
+        let txn = state.env.read_txn().unwrap();
+        let utxos = state.state.get_utxos(&txn).unwrap();
+        assert!(!utxos.contains_key(&outpoint));
+        let refund_outpoint = OutPoint::Refund {
+            bundle: txid.unwrap(),
+            vout: 0,
+        };
+        let refund = Output {
+            address: output.address,
+            content: Content::Value(1_000),
+        };
+        assert_eq!(utxos.get(&refund_outpoint), Some(&refund));
+        let changes = state.state.get_peg_utxo_changes(&txn, height + 1).unwrap();
+        assert_eq!(changes.created, vec![(refund_outpoint, refund)]);
+        assert!(state
+            .state
+            .utxo_heights
+            .get(&txn, &refund_outpoint)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn payout_mismatch_keeps_withdrawals_spent() {
+        let state = TestState::new("payout_mismatch_keeps_withdrawals_spent");
+        let (outpoint, output) = withdrawal(0, 0, 1_000, 10);
+        state.put_utxos(&[(outpoint, output)]);
+        let height = State::<Authorization, ()>::WITHDRAWAL_BUNDLE_FAILURE_GAP + 1;
+        let mut txn = state.env.write_txn().unwrap();
+        state
+            .state
+            .connect_two_way_peg_data(&mut txn, &two_way_peg_data(HashMap::new()), height)
+            .unwrap();
+        let pending = state.state.get_pending_withdrawal_bundles(&txn).unwrap();
+        let (txid, record) = pending.into_iter().next().expect("bundle collected");
+        // Pays out everything but the withdrawal.
+        let mut payout = record.bundle.transaction.clone();
+        payout.output.pop();
+        let main_block_hash = bitcoin::BlockHash::all_zeros();
+        let mut data = two_way_peg_data(HashMap::from([(txid, WithdrawalBundleStatus::Confirmed)]));
+        data.bundle_payouts.insert(
+            txid,
+            BundlePayout {
+                main_block_hash,
+                transaction: Some(payout),
+            },
+        );
+        let alerts = state
+            .state
+            .connect_two_way_peg_data(&mut txn, &data, height + 1)
+            .unwrap();
+        assert_eq!(
+            alerts,
+            vec![WithdrawalBundleAlert {
+                txid,
+                main_block_hash: Some(main_block_hash),
+                main_height: 1,
+                reason: PayoutMismatch::WrongOutputs,
+            }]
+        );
+        assert!(state
+            .state
+            .get_pending_withdrawal_bundles(&txn)
+            .unwrap()
+            .is_empty());
+        assert_eq!(
+            state.state.get_withdrawal_bundle_alerts(&txn).unwrap(),
+            alerts
+        );
+        let utxos = state.state.get_utxos(&txn).unwrap();
+        assert!(utxos.is_empty());
+        assert_eq!(
+            state.state.get_peg_utxo_changes(&txn, height + 2).unwrap(),
+            PegUtxoChanges::default()
+        );
+    }
 }
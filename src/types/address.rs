@@ -48,3 +48,113 @@ pub enum AddressParseError {
     #[error("wrong address length {0} != 20")]
     WrongLength(usize),
 }
+
+/// Destination of a mainchain deposit, `s{sidechain_number}_{address}_{checksum}`.
+#[derive(Clone, Copy, Eq, PartialEq, Hash, Debug, serde::Serialize, serde::Deserialize)]
+pub struct DepositAddress {
+    pub sidechain_number: u8,
+    pub address: Address,
+}
+
+impl DepositAddress {
+    pub fn new(sidechain_number: u8, address: Address) -> Self {
+        Self {
+            sidechain_number,
+            address,
+        }
+    }
+
+    /// First 6 hex characters of the sha256 of `s{sidechain_number}_{address}_`.
+    fn checksum(sidechain_number: u8, address: &str) -> String {
+        let hash = sha256::digest(format!("s{sidechain_number}_{address}_").as_bytes());
+        hash[..6].into()
+    }
+}
+
+impl std::fmt::Display for DepositAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let address = self.address.to_base58();
+        let checksum = Self::checksum(self.sidechain_number, &address);
+        write!(f, "s{}_{address}_{checksum}", self.sidechain_number)
+    }
+}
+
+impl std::str::FromStr for DepositAddress {
+    type Err = DepositAddressParseError;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (sidechain_number_str, rest) = s
+            .strip_prefix('s')
+            .and_then(|s| s.split_once('_'))
+            .ok_or(DepositAddressParseError::MissingPrefix)?;
+        let (address_str, checksum) = rest
+            .rsplit_once('_')
+            .ok_or(DepositAddressParseError::MissingChecksum)?;
+        let sidechain_number: u8 = sidechain_number_str.parse()?;
+        // Mainchain computes the checksum over the text as given, so `s01_`
+        // or `s+1_` could carry a valid checksum for a different string.
+        if sidechain_number.to_string() != sidechain_number_str {
+            return Err(DepositAddressParseError::NonCanonicalSidechainNumber);
+        }
+        let address = address_str.parse()?;
+        if Self::checksum(sidechain_number, address_str) != checksum {
+            return Err(DepositAddressParseError::WrongChecksum);
+        }
+        Ok(Self {
+            sidechain_number,
+            address,
+        })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum DepositAddressParseError {
+    #[error("address parse error")]
+    Address(#[from] AddressParseError),
+    #[error("missing s{{sidechain_number}}_ prefix")]
+    MissingPrefix,
+    #[error("missing checksum")]
+    MissingChecksum,
+    #[error("invalid sidechain number")]
+    SidechainNumber(#[from] std::num::ParseIntError),
+    #[error("sidechain number is not in canonical form")]
+    NonCanonicalSidechainNumber,
+    #[error("wrong checksum")]
+    WrongChecksum,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn deposit_address_round_trip() {
+        let deposit_address = DepositAddress::new(7, Address([3; 20]));
+        let parsed: DepositAddress = deposit_address.to_string().parse().unwrap();
+        assert_eq!(parsed, deposit_address);
+    }
+
+    #[test]
+    fn deposit_address_wrong_checksum() {
+        let deposit_address = DepositAddress::new(7, Address([3; 20])).to_string();
+        let (prefix, _) = deposit_address.rsplit_once('_').unwrap();
+        let err = format!("{prefix}_000000")
+            .parse::<DepositAddress>()
+            .unwrap_err();
+        assert!(matches!(err, DepositAddressParseError::WrongChecksum));
+    }
+
+    #[test]
+    fn deposit_address_leading_zero() {
+        let address = Address([3; 20]).to_base58();
+        // Checksum is valid for the text, but the sidechain number isn't
+        // canonical.
+        let prefix = format!("s01_{address}_");
+        let checksum = sha256::digest(prefix.as_bytes());
+        let deposit_address = format!("{prefix}{}", &checksum[..6]);
+        let err = deposit_address.parse::<DepositAddress>().unwrap_err();
+        assert!(matches!(
+            err,
+            DepositAddressParseError::NonCanonicalSidechainNumber
+        ));
+    }
+}
@@ -3,7 +3,9 @@ use bitcoin::hashes::Hash as _;
 const BLAKE3_LENGTH: usize = 32;
 pub type Hash = [u8; BLAKE3_LENGTH];
 
-#[derive(Default, Clone, Copy, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, serde::Serialize, serde::Deserialize,
+)]
 pub struct BlockHash(pub Hash);
 
 impl From<Hash> for BlockHash {
@@ -43,7 +45,9 @@ impl std::fmt::Debug for BlockHash {
     }
 }
 
-#[derive(Default, Clone, Copy, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, serde::Serialize, serde::Deserialize,
+)]
 pub struct MerkleRoot(Hash);
 
 impl From<Hash> for MerkleRoot {
@@ -70,7 +74,9 @@ impl std::fmt::Debug for MerkleRoot {
     }
 }
 
-#[derive(Default, Clone, Copy, Eq, PartialEq, Hash, serde::Serialize, serde::Deserialize)]
+#[derive(
+    Default, Clone, Copy, Eq, PartialEq, Ord, PartialOrd, Hash, serde::Serialize, serde::Deserialize,
+)]
 pub struct Txid(pub Hash);
 
 impl Txid {
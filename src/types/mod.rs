@@ -36,6 +36,8 @@ impl Header {
 
 #[derive(Debug, Clone, Copy, serde::Serialize, serde::Deserialize)]
 pub enum WithdrawalBundleStatus {
+    /// Bundle is collecting upvotes on mainchain.
+    Pending { work_score: u32, blocks_left: u32 },
     Failed,
     Confirmed,
 }
@@ -46,11 +48,107 @@ pub struct WithdrawalBundle<C> {
     pub transaction: bitcoin::Transaction,
 }
 
+/// Critical transaction index pair, the mainchain output holding all funds
+/// of a sidechain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Ctip {
+    pub outpoint: bitcoin::OutPoint,
+    pub value: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum InvalidDepositReason {
+    /// Destination is neither a deposit address nor a sidechain address.
+    Malformed,
+    WrongChecksum,
+    /// Deposit address is for another sidechain.
+    WrongSidechain { sidechain_number: u8 },
+}
+
+/// Deposit that can't be credited to any sidechain address.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct InvalidDeposit {
+    pub outpoint: bitcoin::OutPoint,
+    pub main_block_hash: bitcoin::BlockHash,
+    pub strdest: String,
+    pub value: u64,
+    pub reason: InvalidDepositReason,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum WithdrawalBundleEvent {
+    /// Collected at sidechain `height`.
+    Created { height: u32 },
+    /// First sent to mainchain by this node at sidechain `height`.
+    Broadcast { height: u32 },
+    /// First seen collecting upvotes at mainchain `main_height`.
+    Voting { main_height: u32 },
+    Confirmed { main_height: u32 },
+    Failed { main_height: u32 },
+    /// Reported confirmed at mainchain `main_height`, but the payout doesn't
+    /// match the bundle, see `WithdrawalBundleAlert`.
+    PayoutMismatch { main_height: u32 },
+}
+
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct WithdrawalBundleRecord<C> {
+    pub bundle: WithdrawalBundle<C>,
+    /// Last status reported by mainchain, `None` until mainchain knows
+    /// about the bundle. Updated on every block while the bundle is pending,
+    /// so `Pending` has the latest work score and blocks left.
+    pub status: Option<WithdrawalBundleStatus>,
+    /// Oldest event first.
+    pub history: Vec<WithdrawalBundleEvent>,
+}
+
+/// Mainchain transaction paying out a withdrawal bundle.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+pub struct BundlePayout {
+    /// Block in which mainchain reports the bundle as spent.
+    pub main_block_hash: bitcoin::BlockHash,
+    /// `None` if no transaction in the block pays out the bundle.
+    pub transaction: Option<bitcoin::Transaction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum PayoutMismatch {
+    /// No transaction in the reported block pays out the bundle.
+    NoPayout,
+    /// The payout doesn't have the fee output of the bundle.
+    WrongFee,
+    /// Payout outputs or amounts differ from the bundle withdrawal outputs.
+    WrongOutputs,
+}
+
+/// Mainchain reported a withdrawal bundle as confirmed, but its payout
+/// doesn't match the bundle. The bundle is no longer pending and its
+/// withdrawals stay spent, the operator has to check the payout and decide
+/// whether to release them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct WithdrawalBundleAlert {
+    pub txid: bitcoin::Txid,
+    pub main_block_hash: Option<bitcoin::BlockHash>,
+    pub main_height: u32,
+    pub reason: PayoutMismatch,
+}
+
 #[derive(Default, Debug, Clone, serde::Serialize, serde::Deserialize)]
 pub struct TwoWayPegData<C> {
     pub deposits: HashMap<types::OutPoint, types::Output<C>>,
+    /// Mainchain heights of the blocks that include the deposits.
+    pub deposit_heights: HashMap<types::OutPoint, u32>,
+    /// Last mainchain block scanned for deposits.
     pub deposit_block_hash: Option<bitcoin::BlockHash>,
+    pub invalid_deposits: Vec<InvalidDeposit>,
+    /// Sidechain CTIP after the last scanned deposit.
+    pub ctip: Option<Ctip>,
+    /// Height of the mainchain block the data was collected up to.
+    pub main_block_height: u32,
+    /// Median time past of the mainchain block the data was collected up to.
+    pub main_block_time: u32,
     pub bundle_statuses: HashMap<bitcoin::Txid, WithdrawalBundleStatus>,
+    /// Payouts of the pending bundles reported as confirmed.
+    pub bundle_payouts: HashMap<bitcoin::Txid, BundlePayout>,
 }
 
 /*
@@ -65,7 +163,7 @@ pub struct DisconnectData {
 }
 */
 
-#[derive(Eq, PartialEq, Clone, Debug)]
+#[derive(Clone, Debug)]
 pub struct AggregatedWithdrawal<C> {
     pub spent_utxos: HashMap<OutPoint, types::Output<C>>,
     pub main_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
@@ -73,21 +171,46 @@ pub struct AggregatedWithdrawal<C> {
     pub main_fee: u64,
 }
 
+impl<C> AggregatedWithdrawal<C> {
+    fn sorted_outpoints(&self) -> Vec<OutPoint> {
+        let mut outpoints: Vec<_> = self.spent_utxos.keys().copied().collect();
+        outpoints.sort();
+        outpoints
+    }
+}
+
+/// Priority of a withdrawal in a bundle, greater goes first. Every
+/// withdrawal takes one bundle output, so the mainchain fee is also the fee
+/// per output. Ties are broken by higher value, then by lower output script
+/// and lastly by the spent outpoints, so the order is total and every node
+/// builds the same bundle from the same utxos.
 impl<C: std::cmp::Eq> Ord for AggregatedWithdrawal<C> {
     fn cmp(&self, other: &Self) -> Ordering {
-        if self == other {
-            Ordering::Equal
-        } else if self.main_fee > other.main_fee
-            || self.value > other.value
-            || self.main_address > other.main_address
-        {
-            Ordering::Greater
-        } else {
-            Ordering::Less
-        }
+        self.main_fee
+            .cmp(&other.main_fee)
+            .then(self.value.cmp(&other.value))
+            .then_with(|| {
+                let script_pubkey = self.main_address.payload.script_pubkey();
+                other
+                    .main_address
+                    .payload
+                    .script_pubkey()
+                    .cmp(&script_pubkey)
+            })
+            .then_with(|| other.sorted_outpoints().cmp(&self.sorted_outpoints()))
+    }
+}
+
+// Equality follows `Ord`, withdrawals spending the same outpoints to the same
+// script are the same.
+impl<C: std::cmp::Eq> PartialEq for AggregatedWithdrawal<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
     }
 }
 
+impl<C: std::cmp::Eq> Eq for AggregatedWithdrawal<C> {}
+
 impl<C: std::cmp::Eq> PartialOrd for AggregatedWithdrawal<C> {
     fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
         Some(self.cmp(other))
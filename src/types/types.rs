@@ -3,7 +3,7 @@ pub use crate::types::hashes::*;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 
-#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[derive(Hash, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub enum OutPoint {
     // Created by transactions.
     Regular { txid: Txid, vout: u32 },
@@ -11,6 +11,9 @@ pub enum OutPoint {
     Coinbase { merkle_root: MerkleRoot, vout: u32 },
     // Created by mainchain deposits.
     Deposit(bitcoin::OutPoint),
+    // Created by refunding the `vout`th withdrawal, in outpoint order, of a
+    // failed withdrawal bundle.
+    Refund { bundle: bitcoin::Txid, vout: u32 },
 }
 
 impl std::fmt::Display for OutPoint {
@@ -19,6 +22,7 @@ impl std::fmt::Display for OutPoint {
             Self::Regular { txid, vout } => write!(f, "regular {txid} {vout}"),
             Self::Coinbase { merkle_root, vout } => write!(f, "coinbase {merkle_root} {vout}"),
             Self::Deposit(bitcoin::OutPoint { txid, vout }) => write!(f, "deposit {txid} {vout}"),
+            Self::Refund { bundle, vout } => write!(f, "refund {bundle} {vout}"),
         }
     }
 }
@@ -77,10 +81,38 @@ impl<C: GetValue> GetValue for Content<C> {
     }
 }
 
+/// A transaction can't be included in a block until its lock time is reached.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum LockTime {
+    /// Sidechain block height.
+    Height(u32),
+    /// Mainchain block height.
+    MainHeight(u32),
+    /// Mainchain median time past, as a unix timestamp.
+    MainTime(u32),
+}
+
+/// An input can't be spent until the utxo it spends has enough
+/// confirmations, counted the same way as coinbase and deposit maturity: the
+/// block that created the utxo and every block after it up to the parent of
+/// the spending block. So `Blocks(1)` can be spent in the next block and only
+/// `Blocks(0)` in the same block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RelativeLock {
+    /// Sidechain confirmations.
+    Blocks(u32),
+    /// Mainchain confirmations, up to the mainchain block the spending block
+    /// builds on.
+    MainBlocks(u32),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Transaction<C> {
     pub inputs: Vec<OutPoint>,
     pub outputs: Vec<Output<C>>,
+    pub lock_time: Option<LockTime>,
+    /// Either empty, or one relative lock per input.
+    pub relative_locks: Vec<Option<RelativeLock>>,
 }
 
 impl<C: Serialize> Transaction<C> {
@@ -126,6 +158,33 @@ pub struct AuthorizedTransaction<A, C> {
     pub authorizations: Vec<A>,
 }
 
+/// Every input needs exactly one authorization.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("wrong number of authorizations {authorizations} for {inputs} inputs")]
+pub struct WrongNumberOfAuthorizations {
+    pub inputs: usize,
+    pub authorizations: usize,
+}
+
+/// Check that `authorizations` authorizations cover the inputs of
+/// `transactions` one to one, for a single transaction or a whole body.
+pub fn validate_authorization_count<'a, C: 'a>(
+    transactions: impl IntoIterator<Item = &'a Transaction<C>>,
+    authorizations: usize,
+) -> Result<(), WrongNumberOfAuthorizations> {
+    let inputs = transactions
+        .into_iter()
+        .map(|transaction| transaction.inputs.len())
+        .sum();
+    if inputs != authorizations {
+        return Err(WrongNumberOfAuthorizations {
+            inputs,
+            authorizations,
+        });
+    }
+    Ok(())
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Body<A, C> {
     pub coinbase: Vec<Output<C>>,
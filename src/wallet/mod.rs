@@ -0,0 +1,1316 @@
+mod sync;
+pub use crate::authorization::{get_address, Authorization};
+use crate::state::PegUtxoChanges;
+use crate::types::{
+    bitcoin, Address, AuthorizedTransaction, BlockHash, Body, GetValue, Header, LockTime, OutPoint,
+    Output, RelativeLock, Transaction,
+};
+use byteorder::{BigEndian, ByteOrder};
+use chacha20poly1305::aead::rand_core::RngCore as _;
+use chacha20poly1305::aead::{Aead as _, KeyInit as _, OsRng};
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use ed25519_dalek_bip32::*;
+use heed::types::*;
+use heed::{Database, RwTxn};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+pub use sync::{SyncSummary, WalletSync};
+
+/// Seed encrypted with a key derived from the wallet password with Argon2id.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct EncryptedSeed {
+    salt: [u8; 16],
+    nonce: [u8; 24],
+    ciphertext: Vec<u8>,
+}
+
+impl EncryptedSeed {
+    fn derive_key(password: &str, salt: &[u8; 16]) -> Result<[u8; 32], Error> {
+        let mut key = [0; 32];
+        argon2::Argon2::default()
+            .hash_password_into(password.as_bytes(), salt, &mut key)
+            .map_err(Error::Kdf)?;
+        Ok(key)
+    }
+
+    fn encrypt(seed: &[u8; 64], password: &str) -> Result<Self, Error> {
+        let mut salt = [0; 16];
+        OsRng.fill_bytes(&mut salt);
+        let mut nonce = [0; 24];
+        OsRng.fill_bytes(&mut nonce);
+        let key = Self::derive_key(password, &salt)?;
+        let ciphertext = XChaCha20Poly1305::new(&key.into())
+            .encrypt(XNonce::from_slice(&nonce), seed.as_slice())
+            .map_err(|_| Error::Encryption)?;
+        Ok(Self {
+            salt,
+            nonce,
+            ciphertext,
+        })
+    }
+
+    fn decrypt(&self, password: &str) -> Result<[u8; 64], Error> {
+        let key = Self::derive_key(password, &self.salt)?;
+        let seed = XChaCha20Poly1305::new(&key.into())
+            .decrypt(XNonce::from_slice(&self.nonce), self.ciphertext.as_slice())
+            .map_err(|_| Error::WrongPassword)?;
+        seed.try_into().map_err(|_| Error::WrongPassword)
+    }
+}
+
+struct UnlockedSeed {
+    seed: [u8; 64],
+    /// `None` if the wallet stays unlocked until `lock` is called.
+    until: Option<Instant>,
+}
+
+impl Drop for UnlockedSeed {
+    fn drop(&mut self) {
+        self.seed.fill(0);
+    }
+}
+
+/// Chain data a wallet is built from, implemented by `Node`.
+pub trait ChainSource<A, C> {
+    type Error: std::error::Error + Send + Sync + 'static;
+    /// Height of the best block, blocks are numbered from 1.
+    fn get_height(&self) -> Result<u32, Self::Error>;
+    fn get_header(&self, height: u32) -> Result<Option<Header>, Self::Error>;
+    fn get_body(&self, height: u32) -> Result<Option<Body<A, C>>, Self::Error>;
+    /// Utxo changes connected with the block at `height` that are not in its
+    /// body, such as deposits and refunds.
+    fn get_peg_utxo_changes(&self, height: u32) -> Result<PegUtxoChanges<C>, Self::Error>;
+    fn get_utxos_by_addresses(
+        &self,
+        addresses: &HashSet<Address>,
+    ) -> Result<HashMap<OutPoint, Output<C>>, Self::Error>;
+    /// Pending mempool transactions.
+    fn get_all_transactions(&self) -> Result<Vec<AuthorizedTransaction<A, C>>, Self::Error>;
+    /// Outpoints that can't be spent in the next block yet.
+    fn get_immature_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<OutPoint>, Self::Error>;
+}
+
+/// Output received by the wallet.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct HistoryEntry<C> {
+    pub outpoint: OutPoint,
+    pub output: Output<C>,
+    /// Height of the block that created the output, `None` for outputs only
+    /// found in the utxo set by `rescan_from`.
+    pub height: Option<u32>,
+    /// Height of the block that spent the output.
+    pub spent_height: Option<u32>,
+}
+
+/// History changes of a block, so it can be undone without going through
+/// the whole history.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct BlockChanges<C> {
+    created: Vec<OutPoint>,
+    /// Spent wallet utxos, with or without a history entry.
+    spent: Vec<(OutPoint, Output<C>)>,
+    /// Withdrawals put back into the utxo set after their bundle failed,
+    /// with the height of the block that spent them.
+    restored: Vec<(OutPoint, u32)>,
+}
+
+impl<C> Default for BlockChanges<C> {
+    fn default() -> Self {
+        Self {
+            created: vec![],
+            spent: vec![],
+            restored: vec![],
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct Wallet<C> {
+    env: heed::Env,
+    seed: Database<OwnedType<u8>, SerdeBincode<EncryptedSeed>>,
+    /// Unencrypted seed of wallets created before seeds were encrypted, it
+    /// is encrypted into `seed` on the first `unlock`.
+    plaintext_seed: Option<Database<OwnedType<u8>, OwnedType<[u8; 64]>>>,
+    /// Decrypted seed, shared by all clones of the wallet.
+    unlocked: Arc<Mutex<Option<UnlockedSeed>>>,
+    pub address_to_index: Database<SerdeBincode<Address>, OwnedType<[u8; 4]>>,
+    pub index_to_address: Database<OwnedType<[u8; 4]>, SerdeBincode<Address>>,
+    pub utxos: Database<SerdeBincode<OutPoint>, SerdeBincode<Output<C>>>,
+    /// Utxos that can't be spent yet, see `Node::get_immature_utxos`.
+    pub immature_utxos: Database<SerdeBincode<OutPoint>, Unit>,
+    /// Every output ever received, kept up to date by `WalletSync` and
+    /// rebuilt by `rescan_from`.
+    pub history: Database<SerdeBincode<OutPoint>, SerdeBincode<HistoryEntry<C>>>,
+    /// Hashes of the blocks the history is built from, by height.
+    pub block_hashes: Database<OwnedType<[u8; 4]>, SerdeBincode<BlockHash>>,
+    /// History changes by block height.
+    block_changes: Database<OwnedType<[u8; 4]>, SerdeBincode<BlockChanges<C>>>,
+    /// Utxos spent by pending mempool transactions, see `WalletSync`.
+    pub locked_utxos: Database<SerdeBincode<OutPoint>, Unit>,
+}
+
+impl<C: GetValue + Clone + Serialize + for<'de> Deserialize<'de> + 'static> Wallet<C> {
+    pub const NUM_DBS: u32 = 10;
+    /// Number of unused addresses in a row after which address discovery
+    /// stops, same as in BIP44.
+    pub const DEFAULT_GAP_LIMIT: u32 = 20;
+
+    pub fn new(path: &Path) -> Result<Self, Error> {
+        std::fs::create_dir_all(path)?;
+        let env = heed::EnvOpenOptions::new()
+            .map_size(10 * 1024 * 1024) // 10MB
+            .max_dbs(Self::NUM_DBS)
+            .open(path)?;
+        let seed_db = env.create_database(Some("encrypted_seed"))?;
+        let plaintext_seed = env.open_database(Some("seed"))?;
+        let address_to_index = env.create_database(Some("address_to_index"))?;
+        let index_to_address = env.create_database(Some("index_to_address"))?;
+        let utxos = env.create_database(Some("utxos"))?;
+        let immature_utxos = env.create_database(Some("immature_utxos"))?;
+        let history = env.create_database(Some("history"))?;
+        let block_hashes = env.create_database(Some("block_hashes"))?;
+        let block_changes = env.create_database(Some("block_changes"))?;
+        let locked_utxos = env.create_database(Some("locked_utxos"))?;
+        Ok(Self {
+            env,
+            seed: seed_db,
+            plaintext_seed,
+            unlocked: Arc::new(Mutex::new(None)),
+            address_to_index,
+            index_to_address,
+            utxos,
+            immature_utxos,
+            history,
+            block_hashes,
+            block_changes,
+            locked_utxos,
+        })
+    }
+
+    /// Store `seed` encrypted with `password`. The wallet stays unlocked
+    /// until `lock` is called.
+    pub fn set_seed(&self, seed: &[u8; 64], password: &str) -> Result<(), Error> {
+        let encrypted_seed = EncryptedSeed::encrypt(seed, password)?;
+        let mut txn = self.env.write_txn()?;
+        self.seed.put(&mut txn, &0, &encrypted_seed)?;
+        if let Some(plaintext_seed) = self.plaintext_seed {
+            plaintext_seed.clear(&mut txn)?;
+        }
+        self.address_to_index.clear(&mut txn)?;
+        self.index_to_address.clear(&mut txn)?;
+        self.utxos.clear(&mut txn)?;
+        self.immature_utxos.clear(&mut txn)?;
+        self.history.clear(&mut txn)?;
+        self.block_hashes.clear(&mut txn)?;
+        self.block_changes.clear(&mut txn)?;
+        self.locked_utxos.clear(&mut txn)?;
+        txn.commit()?;
+        *self.unlocked() = Some(UnlockedSeed {
+            seed: *seed,
+            until: None,
+        });
+        Ok(())
+    }
+
+    /// Generate a new BIP39 mnemonic of 12, 15, 18, 21 or 24 words.
+    pub fn generate_mnemonic(word_count: usize) -> Result<String, Error> {
+        if !(12..=24).contains(&word_count) || word_count % 3 != 0 {
+            return Err(Error::BadWordCount { word_count });
+        }
+        let mut entropy = [0; 32];
+        OsRng.fill_bytes(&mut entropy);
+        let mnemonic = bip39::Mnemonic::from_entropy(&entropy[..word_count / 3 * 4]);
+        entropy.fill(0);
+        Ok(mnemonic?.to_string())
+    }
+
+    /// Create or restore the wallet from a BIP39 mnemonic `phrase` and an
+    /// optional `passphrase` (empty for none), the derived seed is encrypted
+    /// with `password` like in `set_seed`.
+    pub fn set_mnemonic(
+        &self,
+        phrase: &str,
+        passphrase: &str,
+        password: &str,
+    ) -> Result<(), Error> {
+        let mnemonic = bip39::Mnemonic::parse(phrase)?;
+        let mut seed = mnemonic.to_seed(passphrase);
+        let result = self.set_seed(&seed, password);
+        seed.fill(0);
+        result
+    }
+
+    pub fn has_seed(&self) -> Result<bool, Error> {
+        let txn = self.env.read_txn()?;
+        Ok(self.seed.get(&txn, &0)?.is_some() || self.get_plaintext_seed(&txn)?.is_some())
+    }
+
+    /// Whether the seed is still stored unencrypted, the first `unlock`
+    /// encrypts it with the given password.
+    pub fn has_plaintext_seed(&self) -> Result<bool, Error> {
+        let txn = self.env.read_txn()?;
+        Ok(self.get_plaintext_seed(&txn)?.is_some())
+    }
+
+    fn get_plaintext_seed(&self, txn: &heed::RoTxn) -> Result<Option<[u8; 64]>, Error> {
+        match self.plaintext_seed {
+            Some(plaintext_seed) => Ok(plaintext_seed.get(txn, &0)?),
+            None => Ok(None),
+        }
+    }
+
+    /// Encrypt an unencrypted seed with `password` and delete the
+    /// unencrypted copy. Addresses and history are kept.
+    fn encrypt_plaintext_seed(&self, password: &str) -> Result<(), Error> {
+        let mut txn = self.env.write_txn()?;
+        if self.seed.get(&txn, &0)?.is_some() {
+            return Ok(());
+        }
+        let Some(mut seed) = self.get_plaintext_seed(&txn)? else {
+            return Ok(());
+        };
+        let encrypted_seed = EncryptedSeed::encrypt(&seed, password);
+        seed.fill(0);
+        self.seed.put(&mut txn, &0, &encrypted_seed?)?;
+        if let Some(plaintext_seed) = self.plaintext_seed {
+            plaintext_seed.clear(&mut txn)?;
+        }
+        txn.commit()?;
+        println!("encrypted the unencrypted wallet seed");
+        Ok(())
+    }
+
+    fn unlocked(&self) -> std::sync::MutexGuard<'_, Option<UnlockedSeed>> {
+        self.unlocked.lock().expect("wallet lock poisoned")
+    }
+
+    /// Decrypt the seed, so addresses can be derived and transactions
+    /// authorized. With a `timeout` the wallet locks itself again after it.
+    ///
+    /// An unencrypted seed of a wallet created before seeds were encrypted
+    /// is encrypted with `password` first.
+    pub fn unlock(&self, password: &str, timeout: Option<Duration>) -> Result<(), Error> {
+        self.encrypt_plaintext_seed(password)?;
+        let txn = self.env.read_txn()?;
+        let encrypted_seed = self.seed.get(&txn, &0)?.ok_or(Error::NoSeed)?;
+        let seed = encrypted_seed.decrypt(password)?;
+        *self.unlocked() = Some(UnlockedSeed {
+            seed,
+            until: timeout.map(|timeout| Instant::now() + timeout),
+        });
+        Ok(())
+    }
+
+    pub fn lock(&self) {
+        *self.unlocked() = None;
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.get_seed().is_err()
+    }
+
+    /// Re-encrypt the seed with `new_password`. Doesn't change whether the
+    /// wallet is locked.
+    pub fn change_password(&self, old_password: &str, new_password: &str) -> Result<(), Error> {
+        let mut txn = self.env.write_txn()?;
+        let encrypted_seed = self.seed.get(&txn, &0)?.ok_or(Error::NoSeed)?;
+        let mut seed = encrypted_seed.decrypt(old_password)?;
+        let encrypted_seed = EncryptedSeed::encrypt(&seed, new_password);
+        seed.fill(0);
+        self.seed.put(&mut txn, &0, &encrypted_seed?)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Unlocked seed, locks the wallet if the unlock timeout has passed.
+    fn get_seed(&self) -> Result<[u8; 64], Error> {
+        let mut unlocked = self.unlocked();
+        match &*unlocked {
+            Some(UnlockedSeed {
+                until: Some(until), ..
+            }) if *until <= Instant::now() => {
+                *unlocked = None;
+                Err(Error::Locked)
+            }
+            Some(UnlockedSeed { seed, .. }) => Ok(*seed),
+            None => Err(Error::Locked),
+        }
+    }
+
+    pub fn create_withdrawal(
+        &self,
+        main_address: bitcoin::Address<bitcoin::address::NetworkUnchecked>,
+        value: u64,
+        main_fee: u64,
+        fee: u64,
+    ) -> Result<Transaction<C>, Error> {
+        let (total, coins) = self.select_coins(value + fee + main_fee)?;
+        let change = total - value - fee;
+        let inputs = coins.into_keys().collect();
+        let outputs = vec![
+            Output {
+                address: self.get_new_address()?,
+                content: crate::types::Content::Withdrawal {
+                    value,
+                    main_fee,
+                    main_address,
+                },
+            },
+            Output {
+                address: self.get_new_address()?,
+                content: crate::types::Content::Value(change),
+            },
+        ];
+        Ok(Transaction {
+            inputs,
+            outputs,
+            lock_time: None,
+            relative_locks: vec![],
+        })
+    }
+
+    /// Spend withdrawal outputs that are not in a bundle yet back to a value
+    /// output, for example to get out of a withdrawal with a `main_fee` too
+    /// low to ever get bundled.
+    pub fn create_withdrawal_cancellation(
+        &self,
+        outpoints: &[OutPoint],
+        fee: u64,
+    ) -> Result<Transaction<C>, Error> {
+        let mut value: u64 = 0;
+        {
+            let txn = self.env.read_txn()?;
+            for outpoint in outpoints {
+                let output = self.utxos.get(&txn, outpoint)?.ok_or(Error::NoUtxo)?;
+                if !output.content.is_withdrawal() {
+                    return Err(Error::NotWithdrawal {
+                        outpoint: *outpoint,
+                    });
+                }
+                value += output.get_value();
+            }
+        }
+        if value < fee {
+            return Err(Error::NotEnoughFunds);
+        }
+        let outputs = vec![Output {
+            address: self.get_new_address()?,
+            content: crate::types::Content::Value(value - fee),
+        }];
+        Ok(Transaction {
+            inputs: outpoints.to_vec(),
+            outputs,
+            lock_time: None,
+            relative_locks: vec![],
+        })
+    }
+
+    pub fn create_transaction(
+        &self,
+        address: Address,
+        value: u64,
+        fee: u64,
+    ) -> Result<Transaction<C>, Error> {
+        self.create_locked_transaction(address, value, fee, None, None)
+    }
+
+    /// Create a transaction that can't be included in a block before
+    /// `lock_time`, with `relative_lock` set on every input.
+    pub fn create_locked_transaction(
+        &self,
+        address: Address,
+        value: u64,
+        fee: u64,
+        lock_time: Option<LockTime>,
+        relative_lock: Option<RelativeLock>,
+    ) -> Result<Transaction<C>, Error> {
+        let (total, coins) = self.select_coins(value + fee)?;
+        let change = total - value - fee;
+        let inputs: Vec<OutPoint> = coins.into_keys().collect();
+        let relative_locks = match relative_lock {
+            Some(relative_lock) => vec![Some(relative_lock); inputs.len()],
+            None => vec![],
+        };
+        let outputs = vec![
+            Output {
+                address,
+                content: crate::types::Content::Value(value),
+            },
+            Output {
+                address: self.get_new_address()?,
+                content: crate::types::Content::Value(change),
+            },
+        ];
+        Ok(Transaction {
+            inputs,
+            outputs,
+            lock_time,
+            relative_locks,
+        })
+    }
+
+    pub fn select_coins(&self, value: u64) -> Result<(u64, HashMap<OutPoint, Output<C>>), Error> {
+        let txn = self.env.read_txn()?;
+        let mut utxos = vec![];
+        for item in self.utxos.iter(&txn)? {
+            let (outpoint, output) = item?;
+            if self.immature_utxos.get(&txn, &outpoint)?.is_some()
+                || self.locked_utxos.get(&txn, &outpoint)?.is_some()
+            {
+                continue;
+            }
+            utxos.push((outpoint, output));
+        }
+        utxos.sort_unstable_by_key(|(_, output)| output.get_value());
+
+        let mut selected = HashMap::new();
+        let mut total: u64 = 0;
+        for (outpoint, output) in &utxos {
+            if output.content.is_withdrawal() {
+                continue;
+            }
+            if total > value {
+                break;
+            }
+            total += output.get_value();
+            selected.insert(*outpoint, output.clone());
+        }
+        if total < value {
+            return Err(Error::NotEnoughFunds);
+        }
+        return Ok((total, selected));
+    }
+
+    pub fn delete_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
+        let mut txn = self.env.write_txn()?;
+        for outpoint in outpoints {
+            self.utxos.delete(&mut txn, outpoint)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Replace the set of utxos that `select_coins` must skip because they
+    /// are not mature yet.
+    pub fn set_immature_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
+        let mut txn = self.env.write_txn()?;
+        self.immature_utxos.clear(&mut txn)?;
+        for outpoint in outpoints {
+            self.immature_utxos.put(&mut txn, outpoint, &())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Replace the set of utxos that `select_coins` must skip because
+    /// pending mempool transactions spend them.
+    pub fn set_locked_utxos(&self, outpoints: &[OutPoint]) -> Result<(), Error> {
+        let mut txn = self.env.write_txn()?;
+        self.locked_utxos.clear(&mut txn)?;
+        for outpoint in outpoints {
+            self.locked_utxos.put(&mut txn, outpoint, &())?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    /// Height and hash of the last block the history is built from.
+    pub fn get_last_block(&self) -> Result<Option<(u32, BlockHash)>, Error> {
+        let txn = self.env.read_txn()?;
+        let last_block = self
+            .block_hashes
+            .last(&txn)?
+            .map(|(height, block_hash)| (BigEndian::read_u32(&height), block_hash));
+        Ok(last_block)
+    }
+
+    pub fn put_utxos(&self, utxos: &HashMap<OutPoint, Output<C>>) -> Result<(), Error> {
+        let mut txn = self.env.write_txn()?;
+        for (outpoint, output) in utxos {
+            self.utxos.put(&mut txn, outpoint, output)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_balance(&self) -> Result<u64, Error> {
+        let mut balance: u64 = 0;
+        let txn = self.env.read_txn()?;
+        for item in self.utxos.iter(&txn)? {
+            let (_, utxo) = item?;
+            balance += utxo.get_value();
+        }
+        Ok(balance)
+    }
+
+    pub fn get_utxos(&self) -> Result<HashMap<OutPoint, Output<C>>, Error> {
+        let txn = self.env.read_txn()?;
+        let mut utxos = HashMap::new();
+        for item in self.utxos.iter(&txn)? {
+            let (outpoint, output) = item?;
+            utxos.insert(outpoint, output);
+        }
+        Ok(utxos)
+    }
+
+    pub fn get_addresses(&self) -> Result<HashSet<Address>, Error> {
+        let txn = self.env.read_txn()?;
+        let mut addresses = HashSet::new();
+        for item in self.index_to_address.iter(&txn)? {
+            let (_, address) = item?;
+            addresses.insert(address);
+        }
+        Ok(addresses)
+    }
+
+    /// Fails with `Error::Locked` if the wallet is locked.
+    pub fn authorize(
+        &self,
+        transaction: Transaction<C>,
+    ) -> Result<AuthorizedTransaction<Authorization, C>, Error> {
+        let txn = self.env.read_txn()?;
+        let mut authorizations = vec![];
+        for input in &transaction.inputs {
+            let spent_utxo = self.utxos.get(&txn, input)?.ok_or(Error::NoUtxo)?;
+            let index = self
+                .address_to_index
+                .get(&txn, &spent_utxo.address)?
+                .ok_or(Error::NoIndex {
+                    address: spent_utxo.address,
+                })?;
+            let index = BigEndian::read_u32(&index);
+            let keypair = self.get_keypair(index)?;
+            let signature = crate::authorization::sign(&keypair, &transaction)?;
+            authorizations.push(Authorization {
+                public_key: keypair.public,
+                signature,
+            });
+        }
+        Ok(AuthorizedTransaction {
+            authorizations,
+            transaction,
+        })
+    }
+
+    /// Fails with `Error::Locked` if the wallet is locked.
+    pub fn get_new_address(&self) -> Result<Address, Error> {
+        let mut txn = self.env.write_txn()?;
+        let (last_index, _) = self
+            .index_to_address
+            .last(&txn)?
+            .unwrap_or(([0; 4], [0; 20].into()));
+        let last_index = BigEndian::read_u32(&last_index);
+        let address = self.derive_address(&mut txn, last_index + 1)?;
+        txn.commit()?;
+        Ok(address)
+    }
+
+    fn derive_address(&self, txn: &mut RwTxn, index: u32) -> Result<Address, Error> {
+        let keypair = self.get_keypair(index)?;
+        let address = get_address(&keypair.public);
+        let index = index.to_be_bytes();
+        self.index_to_address.put(txn, &index, &address)?;
+        self.address_to_index.put(txn, &address, &index)?;
+        Ok(address)
+    }
+
+    /// Derive addresses up to `last_used + gap_limit`, returns whether any
+    /// new address was derived.
+    fn derive_lookahead(&self, last_used: u32, gap_limit: u32) -> Result<bool, Error> {
+        let num_addresses = self.get_num_addresses()?;
+        let mut txn = self.env.write_txn()?;
+        for index in num_addresses + 1..=last_used + gap_limit {
+            self.derive_address(&mut txn, index)?;
+        }
+        txn.commit()?;
+        Ok(last_used + gap_limit > num_addresses)
+    }
+
+    fn get_address_indices(&self) -> Result<HashMap<Address, u32>, Error> {
+        let txn = self.env.read_txn()?;
+        let mut indices = HashMap::new();
+        for item in self.address_to_index.iter(&txn)? {
+            let (address, index) = item?;
+            indices.insert(address, BigEndian::read_u32(&index));
+        }
+        Ok(indices)
+    }
+
+    pub fn get_history(&self) -> Result<Vec<HistoryEntry<C>>, Error> {
+        let txn = self.env.read_txn()?;
+        let mut history = vec![];
+        for item in self.history.iter(&txn)? {
+            let (_, entry) = item?;
+            history.push(entry);
+        }
+        Ok(history)
+    }
+
+    /// Add `output` to `history` if it pays to a wallet address, returns the
+    /// index of the address.
+    fn scan_output(
+        indices: &HashMap<Address, u32>,
+        history: &mut HashMap<OutPoint, HistoryEntry<C>>,
+        outpoint: OutPoint,
+        output: &Output<C>,
+        height: Option<u32>,
+    ) -> Option<u32> {
+        let index = indices.get(&output.address)?;
+        history.entry(outpoint).or_insert(HistoryEntry {
+            outpoint,
+            output: output.clone(),
+            height,
+            spent_height: None,
+        });
+        Some(*index)
+    }
+
+    /// Rebuild wallet utxos and immature utxos, and the history of blocks
+    /// from `height` on.
+    ///
+    /// Addresses are derived until `gap_limit` addresses in a row after the
+    /// last used one have never received anything, so after restoring a seed
+    /// `rescan_from(source, 0, Wallet::DEFAULT_GAP_LIMIT)` finds all funds.
+    /// The wallet must be unlocked to derive addresses.
+    pub fn rescan_from<A, S: ChainSource<A, C>>(
+        &self,
+        source: &S,
+        height: u32,
+        gap_limit: u32,
+    ) -> Result<(), Error> {
+        let chain_source_error = |err: S::Error| Error::ChainSource(Box::new(err));
+        let tip = source.get_height().map_err(chain_source_error)?;
+        self.derive_lookahead(0, gap_limit)?;
+        let mut indices = self.get_address_indices()?;
+        let mut last_used = 0;
+        // History of blocks before `height` is kept.
+        let mut history = HashMap::new();
+        for mut entry in self.get_history()? {
+            if entry
+                .height
+                .map_or(false, |entry_height| entry_height >= height)
+            {
+                continue;
+            }
+            if entry
+                .spent_height
+                .map_or(false, |spent_height| spent_height >= height)
+            {
+                entry.spent_height = None;
+            }
+            let index = indices.get(&entry.output.address).copied();
+            last_used = std::cmp::max(last_used, index.unwrap_or(0));
+            history.insert(entry.outpoint, entry);
+        }
+        // Outputs, spent outpoints and peg outputs of the scanned blocks, so
+        // addresses derived during the scan are checked without fetching the
+        // blocks again.
+        let mut blocks = vec![];
+        let mut block_hashes = vec![];
+        for block_height in std::cmp::max(height, 1)..=tip {
+            let header = source
+                .get_header(block_height)
+                .map_err(chain_source_error)?;
+            let body = source.get_body(block_height).map_err(chain_source_error)?;
+            // Later blocks are left for `WalletSync`.
+            let (Some(header), Some(body)) = (header, body) else {
+                break;
+            };
+            let peg_utxo_changes = source
+                .get_peg_utxo_changes(block_height)
+                .map_err(chain_source_error)?;
+            let mut spent = body.get_inputs();
+            spent.extend(peg_utxo_changes.spent);
+            block_hashes.push((block_height, header.hash()));
+            blocks.push((
+                block_height,
+                body.get_outputs(),
+                spent,
+                peg_utxo_changes.created,
+            ));
+        }
+        let mut utxos = HashMap::new();
+        // Restored withdrawals by restoring block height and outpoint, with
+        // the height of the block that spent them.
+        let mut restored = HashMap::new();
+        // Addresses not scanned for yet, all of them at first.
+        let mut new_indices = indices.clone();
+        loop {
+            for (block_height, outputs, spent, peg_outputs) in &blocks {
+                for (outpoint, output) in outputs {
+                    let index = Self::scan_output(
+                        &new_indices,
+                        &mut history,
+                        *outpoint,
+                        output,
+                        Some(*block_height),
+                    );
+                    last_used = std::cmp::max(last_used, index.unwrap_or(0));
+                }
+                for outpoint in spent {
+                    if let Some(entry) = history.get_mut(outpoint) {
+                        entry.spent_height = Some(*block_height);
+                    }
+                }
+                for (outpoint, output) in peg_outputs {
+                    if let Some(entry) = history.get_mut(outpoint) {
+                        if let Some(spent_height) = entry.spent_height.take() {
+                            restored.insert((*block_height, *outpoint), spent_height);
+                        }
+                        continue;
+                    }
+                    let index = Self::scan_output(
+                        &new_indices,
+                        &mut history,
+                        *outpoint,
+                        output,
+                        Some(*block_height),
+                    );
+                    last_used = std::cmp::max(last_used, index.unwrap_or(0));
+                }
+            }
+            let addresses = new_indices.keys().copied().collect();
+            let new_utxos = source
+                .get_utxos_by_addresses(&addresses)
+                .map_err(chain_source_error)?;
+            // Deposits connected before the node recorded peg utxo changes are
+            // only found in the utxo set.
+            for (outpoint, output) in &new_utxos {
+                let index = Self::scan_output(&new_indices, &mut history, *outpoint, output, None);
+                last_used = std::cmp::max(last_used, index.unwrap_or(0));
+            }
+            utxos.extend(new_utxos);
+            if !self.derive_lookahead(last_used, gap_limit)? {
+                break;
+            }
+            // Newly derived addresses may have received outputs in blocks
+            // that were already scanned.
+            let all_indices = self.get_address_indices()?;
+            new_indices = all_indices
+                .iter()
+                .filter(|(address, _)| !indices.contains_key(address))
+                .map(|(address, index)| (*address, *index))
+                .collect();
+            indices = all_indices;
+        }
+        let outpoints: Vec<OutPoint> = utxos.keys().copied().collect();
+        let immature_utxos = source
+            .get_immature_utxos(&outpoints)
+            .map_err(chain_source_error)?;
+        let mut block_changes: BTreeMap<u32, BlockChanges<C>> = BTreeMap::new();
+        for entry in history.values() {
+            if let Some(entry_height) = entry.height.filter(|entry_height| *entry_height >= height)
+            {
+                let changes = block_changes.entry(entry_height).or_default();
+                changes.created.push(entry.outpoint);
+            }
+            if let Some(spent_height) = entry.spent_height.filter(|spent| *spent >= height) {
+                let changes = block_changes.entry(spent_height).or_default();
+                changes.spent.push((entry.outpoint, entry.output.clone()));
+            }
+        }
+        for ((restored_height, outpoint), spent_height) in restored {
+            let changes = block_changes.entry(restored_height).or_default();
+            changes.restored.push((outpoint, spent_height));
+            if spent_height >= height {
+                let output = history[&outpoint].output.clone();
+                let changes = block_changes.entry(spent_height).or_default();
+                changes.spent.push((outpoint, output));
+            }
+        }
+        let mut txn = self.env.write_txn()?;
+        self.history.clear(&mut txn)?;
+        for (outpoint, entry) in &history {
+            self.history.put(&mut txn, outpoint, entry)?;
+        }
+        self.utxos.clear(&mut txn)?;
+        for (outpoint, output) in &utxos {
+            self.utxos.put(&mut txn, outpoint, output)?;
+        }
+        self.immature_utxos.clear(&mut txn)?;
+        for outpoint in &immature_utxos {
+            self.immature_utxos.put(&mut txn, outpoint, &())?;
+        }
+        self.block_hashes
+            .delete_range(&mut txn, &(height.to_be_bytes()..))?;
+        for (block_height, block_hash) in &block_hashes {
+            self.block_hashes
+                .put(&mut txn, &block_height.to_be_bytes(), block_hash)?;
+        }
+        self.block_changes
+            .delete_range(&mut txn, &(height.to_be_bytes()..))?;
+        for (block_height, changes) in &block_changes {
+            self.block_changes
+                .put(&mut txn, &block_height.to_be_bytes(), changes)?;
+        }
+        txn.commit()?;
+        Ok(())
+    }
+
+    pub fn get_num_addresses(&self) -> Result<u32, Error> {
+        let txn = self.env.read_txn()?;
+        let (last_index, _) = self
+            .index_to_address
+            .last(&txn)?
+            .unwrap_or(([0; 4], [0; 20].into()));
+        let last_index = BigEndian::read_u32(&last_index);
+        Ok(last_index)
+    }
+
+    /// Fails with `Error::Locked` if the wallet is locked.
+    fn get_keypair(&self, index: u32) -> Result<ed25519_dalek::Keypair, Error> {
+        let mut seed = self.get_seed()?;
+        let xpriv = ExtendedSecretKey::from_seed(&seed);
+        seed.fill(0);
+        let xpriv = xpriv?;
+        let derivation_path = DerivationPath::new([
+            ChildIndex::Hardened(1),
+            ChildIndex::Hardened(0),
+            ChildIndex::Hardened(0),
+            ChildIndex::Hardened(index),
+        ]);
+        let child = xpriv.derive(&derivation_path)?;
+        let public = child.public_key();
+        let secret = child.secret_key;
+        Ok(ed25519_dalek::Keypair { secret, public })
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("heed error")]
+    Heed(#[from] heed::Error),
+    #[error("bip32 error")]
+    Bip32(#[from] ed25519_dalek_bip32::Error),
+    #[error("address {address} does not exist")]
+    AddressDoesNotExist { address: crate::types::Address },
+    #[error("utxo doesn't exist")]
+    NoUtxo,
+    #[error("wallet doesn't have a seed")]
+    NoSeed,
+    #[error("no index for address {address}")]
+    NoIndex { address: Address },
+    #[error("authorization error")]
+    Authorization(#[from] crate::authorization::Error),
+    #[error("io error")]
+    Io(#[from] std::io::Error),
+    #[error("not enough funds")]
+    NotEnoughFunds,
+    #[error("utxo {outpoint} is not a withdrawal")]
+    NotWithdrawal { outpoint: OutPoint },
+    #[error("bip39 error")]
+    Bip39(#[from] bip39::Error),
+    #[error("mnemonic can't have {word_count} words")]
+    BadWordCount { word_count: usize },
+    #[error("chain source error")]
+    ChainSource(#[source] Box<dyn std::error::Error + Send + Sync>),
+    #[error("wallet is locked")]
+    Locked,
+    #[error("wrong password")]
+    WrongPassword,
+    #[error("key derivation error: {0}")]
+    Kdf(argon2::Error),
+    #[error("seed encryption error")]
+    Encryption,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitcoin::hashes::Hash as _;
+
+    pub(super) const PASSWORD: &str = "password";
+
+    /// Wallet in its own temporary directory, removed on drop.
+    pub(super) struct TestWallet {
+        pub(super) wallet: Wallet<()>,
+        path: std::path::PathBuf,
+    }
+
+    impl TestWallet {
+        pub(super) fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("ddk-wallet-{name}-{}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&path);
+            let wallet = Wallet::new(&path).unwrap();
+            Self { wallet, path }
+        }
+
+        /// Wallet with the seed `[seed; 64]`, unlocked.
+        pub(super) fn with_seed(name: &str, seed: u8) -> Self {
+            let test_wallet = Self::new(name);
+            test_wallet.wallet.set_seed(&[seed; 64], PASSWORD).unwrap();
+            test_wallet
+        }
+    }
+
+    impl Drop for TestWallet {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.path);
+        }
+    }
+
+    /// Chain source with blocks built by the test, the utxo set is replayed
+    /// from the blocks.
+    #[derive(Clone, Default)]
+    pub(super) struct FakeChain {
+        blocks: Vec<(Header, Body<Authorization, ()>, PegUtxoChanges<()>)>,
+        pub(super) immature: HashSet<OutPoint>,
+        pub(super) mempool: Vec<AuthorizedTransaction<Authorization, ()>>,
+        pub(super) body_requests: Arc<Mutex<usize>>,
+        pub(super) address_requests: Arc<Mutex<Vec<Address>>>,
+    }
+
+    impl FakeChain {
+        /// Append a block, returns the outpoints of its coinbase outputs.
+        pub(super) fn push_block(
+            &mut self,
+            coinbase: Vec<Output<()>>,
+            transactions: Vec<Transaction<()>>,
+            peg_utxo_changes: PegUtxoChanges<()>,
+        ) -> Vec<OutPoint> {
+            let transactions = transactions
+                .into_iter()
+                .map(|transaction| AuthorizedTransaction {
+                    transaction,
+                    authorizations: vec![],
+                })
+                .collect();
+            let body = Body::new(transactions, coinbase);
+            let prev_side_hash = match self.blocks.last() {
+                Some((header, _, _)) => header.hash(),
+                None => BlockHash::from([0; 32]),
+            };
+            let merkle_root = body.compute_merkle_root();
+            let header = Header {
+                merkle_root,
+                prev_side_hash,
+                prev_main_hash: bitcoin::BlockHash::from_byte_array([0; 32]),
+            };
+            let outpoints = (0..body.coinbase.len() as u32)
+                .map(|vout| OutPoint::Coinbase { merkle_root, vout })
+                .collect();
+            self.blocks.push((header, body, peg_utxo_changes));
+            outpoints
+        }
+
+        pub(super) fn pop_block(&mut self) {
+            self.blocks.pop();
+        }
+
+        fn get_utxos(&self) -> HashMap<OutPoint, Output<()>> {
+            let mut utxos = HashMap::new();
+            for (_, body, peg_utxo_changes) in &self.blocks {
+                utxos.extend(body.get_outputs());
+                for outpoint in body.get_inputs().iter().chain(&peg_utxo_changes.spent) {
+                    utxos.remove(outpoint);
+                }
+                utxos.extend(peg_utxo_changes.created.iter().cloned());
+            }
+            utxos
+        }
+    }
+
+    impl ChainSource<Authorization, ()> for FakeChain {
+        type Error = std::convert::Infallible;
+
+        fn get_height(&self) -> Result<u32, Self::Error> {
+            Ok(self.blocks.len() as u32)
+        }
+
+        fn get_header(&self, height: u32) -> Result<Option<Header>, Self::Error> {
+            let block = self.blocks.get((height as usize).wrapping_sub(1));
+            Ok(block.map(|(header, _, _)| header.clone()))
+        }
+
+        fn get_body(&self, height: u32) -> Result<Option<Body<Authorization, ()>>, Self::Error> {
+            *self.body_requests.lock().unwrap() += 1;
+            let block = self.blocks.get((height as usize).wrapping_sub(1));
+            Ok(block.map(|(_, body, _)| body.clone()))
+        }
+
+        fn get_peg_utxo_changes(&self, height: u32) -> Result<PegUtxoChanges<()>, Self::Error> {
+            let block = self.blocks.get((height as usize).wrapping_sub(1));
+            Ok(block
+                .map(|(_, _, changes)| changes.clone())
+                .unwrap_or_default())
+        }
+
+        fn get_utxos_by_addresses(
+            &self,
+            addresses: &HashSet<Address>,
+        ) -> Result<HashMap<OutPoint, Output<()>>, Self::Error> {
+            self.address_requests
+                .lock()
+                .unwrap()
+                .extend(addresses.iter().copied());
+            let mut utxos = self.get_utxos();
+            utxos.retain(|_, output| addresses.contains(&output.address));
+            Ok(utxos)
+        }
+
+        fn get_all_transactions(
+            &self,
+        ) -> Result<Vec<AuthorizedTransaction<Authorization, ()>>, Self::Error> {
+            Ok(self.mempool.clone())
+        }
+
+        fn get_immature_utxos(&self, outpoints: &[OutPoint]) -> Result<Vec<OutPoint>, Self::Error> {
+            Ok(outpoints
+                .iter()
+                .filter(|outpoint| self.immature.contains(outpoint))
+                .copied()
+                .collect())
+        }
+    }
+
+    pub(super) fn value_output(address: Address, value: u64) -> Output<()> {
+        Output {
+            address,
+            content: crate::types::Content::Value(value),
+        }
+    }
+
+    #[test]
+    fn unlock_with_right_password_only() {
+        let test_wallet = TestWallet::with_seed("unlock", 1);
+        let wallet = &test_wallet.wallet;
+        let address = wallet.get_new_address().unwrap();
+        wallet.lock();
+        assert!(wallet.is_locked());
+        assert!(matches!(wallet.get_new_address(), Err(Error::Locked)));
+        assert!(matches!(
+            wallet.unlock("wrong", None),
+            Err(Error::WrongPassword)
+        ));
+        assert!(wallet.is_locked());
+        wallet.unlock(PASSWORD, None).unwrap();
+        assert!(!wallet.is_locked());
+        // Keys are derived from the decrypted seed.
+        assert_eq!(get_address(&wallet.get_keypair(1).unwrap().public), address);
+    }
+
+    #[test]
+    fn unlock_timeout_locks_again() {
+        let test_wallet = TestWallet::with_seed("unlock_timeout", 1);
+        let wallet = &test_wallet.wallet;
+        wallet.lock();
+        wallet
+            .unlock(PASSWORD, Some(Duration::from_millis(50)))
+            .unwrap();
+        assert!(!wallet.is_locked());
+        std::thread::sleep(Duration::from_millis(100));
+        assert!(wallet.is_locked());
+        assert!(matches!(wallet.get_new_address(), Err(Error::Locked)));
+    }
+
+    #[test]
+    fn change_password() {
+        let test_wallet = TestWallet::with_seed("change_password", 1);
+        let wallet = &test_wallet.wallet;
+        let address = get_address(&wallet.get_keypair(1).unwrap().public);
+        assert!(matches!(
+            wallet.change_password("wrong", "new"),
+            Err(Error::WrongPassword)
+        ));
+        wallet.change_password(PASSWORD, "new").unwrap();
+        // Changing the password doesn't lock the wallet.
+        assert!(!wallet.is_locked());
+        wallet.lock();
+        assert!(matches!(
+            wallet.unlock(PASSWORD, None),
+            Err(Error::WrongPassword)
+        ));
+        wallet.unlock("new", None).unwrap();
+        assert_eq!(get_address(&wallet.get_keypair(1).unwrap().public), address);
+    }
+
+    #[test]
+    fn plaintext_seed_encrypted_on_first_unlock() {
+        let seed = [7; 64];
+        let expected = TestWallet::with_seed("plaintext_seed_expected", 7);
+        let expected_address = expected.wallet.get_new_address().unwrap();
+
+        // A wallet created before seeds were encrypted, with an address.
+        let mut test_wallet = TestWallet::new("plaintext_seed");
+        let wallet = &mut test_wallet.wallet;
+        let plaintext_seed = wallet.env.create_database(Some("seed")).unwrap();
+        wallet.plaintext_seed = Some(plaintext_seed);
+        {
+            let mut txn = wallet.env.write_txn().unwrap();
+            plaintext_seed.put(&mut txn, &0, &seed).unwrap();
+            wallet
+                .index_to_address
+                .put(&mut txn, &1u32.to_be_bytes(), &expected_address)
+                .unwrap();
+            wallet
+                .address_to_index
+                .put(&mut txn, &expected_address, &1u32.to_be_bytes())
+                .unwrap();
+            txn.commit().unwrap();
+        }
+        assert!(wallet.has_seed().unwrap());
+        assert!(wallet.has_plaintext_seed().unwrap());
+        assert!(wallet.is_locked());
+
+        wallet.unlock(PASSWORD, None).unwrap();
+        assert!(!wallet.has_plaintext_seed().unwrap());
+        assert!(wallet.has_seed().unwrap());
+        // Addresses are kept and the seed derives the same keys.
+        assert_eq!(wallet.get_num_addresses().unwrap(), 1);
+        assert_eq!(
+            get_address(&wallet.get_keypair(1).unwrap().public),
+            expected_address
+        );
+        assert_eq!(
+            wallet.get_new_address().unwrap(),
+            expected.wallet.get_new_address().unwrap()
+        );
+
+        // The migrated seed is encrypted with the password of the first unlock.
+        wallet.lock();
+        assert!(matches!(
+            wallet.unlock("wrong", None),
+            Err(Error::WrongPassword)
+        ));
+        wallet.unlock(PASSWORD, None).unwrap();
+        assert_eq!(
+            get_address(&wallet.get_keypair(1).unwrap().public),
+            expected_address
+        );
+    }
+
+    #[test]
+    fn mnemonic_test_vector() {
+        // Test vector from the BIP39 reference implementation.
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+                      abandon abandon about";
+        let test_wallet = TestWallet::new("mnemonic_test_vector");
+        let wallet = &test_wallet.wallet;
+        wallet.set_mnemonic(phrase, "TREZOR", PASSWORD).unwrap();
+        let seed = wallet.get_seed().unwrap();
+        assert_eq!(
+            hex::encode(seed),
+            "c55257c360c07c72029aebc1b53c05ed0362ada38ead3e3e9efa3708e5349553\
+             1f09a6987599d18264c1e1c92f2cf141630c7a3c4ab7c81b2f001698e7463b04"
+        );
+        let address = wallet.get_new_address().unwrap();
+        assert_eq!(
+            hex::encode(address.0),
+            "96007a80a4782e130bf9e795bf43150c6ee16748"
+        );
+    }
+
+    #[test]
+    fn mnemonic_with_bad_checksum_rejected() {
+        let phrase = "abandon abandon abandon abandon abandon abandon abandon abandon abandon \
+                      abandon abandon abandon";
+        let test_wallet = TestWallet::new("mnemonic_bad_checksum");
+        let wallet = &test_wallet.wallet;
+        assert!(matches!(
+            wallet.set_mnemonic(phrase, "", PASSWORD),
+            Err(Error::Bip39(_))
+        ));
+        assert!(!wallet.has_seed().unwrap());
+        assert!(wallet.is_locked());
+    }
+
+    #[test]
+    fn rescan_finds_funds_beyond_first_gap_window() {
+        const GAP_LIMIT: u32 = 3;
+        let test_wallet = TestWallet::with_seed("rescan", 1);
+        let wallet = &test_wallet.wallet;
+        let address = |index| get_address(&wallet.get_keypair(index).unwrap().public);
+        // Every payment is within the gap limit of the previous one only.
+        let mut chain = FakeChain::default();
+        let first = chain.push_block(
+            vec![value_output(address(2), 1_000)],
+            vec![],
+            PegUtxoChanges::default(),
+        )[0];
+        let second = chain.push_block(
+            vec![value_output(address(5), 2_000)],
+            vec![],
+            PegUtxoChanges::default(),
+        )[0];
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([1; 32]),
+            vout: 0,
+        });
+        chain.push_block(
+            vec![],
+            vec![],
+            PegUtxoChanges {
+                spent: vec![],
+                created: vec![(deposit, value_output(address(8), 3_000))],
+            },
+        );
+        let spend = Transaction {
+            inputs: vec![first],
+            outputs: vec![value_output(address(11), 900)],
+            lock_time: None,
+            relative_locks: vec![],
+        };
+        let change = OutPoint::Regular {
+            txid: spend.txid(),
+            vout: 0,
+        };
+        chain.push_block(vec![], vec![spend], PegUtxoChanges::default());
+        // Too far from any used address to be found.
+        chain.push_block(
+            vec![value_output(address(20), 5_000)],
+            vec![],
+            PegUtxoChanges::default(),
+        );
+        chain.immature.insert(second);
+        // Stale from before the rescan.
+        wallet.set_immature_utxos(&[first]).unwrap();
+
+        wallet.rescan_from(&chain, 0, GAP_LIMIT).unwrap();
+
+        let utxos = wallet.get_utxos().unwrap();
+        let outpoints: HashSet<OutPoint> = utxos.keys().copied().collect();
+        assert_eq!(outpoints, HashSet::from([second, deposit, change]));
+        assert_eq!(wallet.get_num_addresses().unwrap(), 11 + GAP_LIMIT);
+        let history: HashMap<OutPoint, HistoryEntry<()>> = wallet
+            .get_history()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.outpoint, entry))
+            .collect();
+        assert_eq!(history.len(), 4);
+        assert_eq!(history[&first].height, Some(1));
+        assert_eq!(history[&first].spent_height, Some(4));
+        assert_eq!(history[&deposit].height, Some(3));
+        assert_eq!(history[&change].height, Some(4));
+        assert_eq!(
+            wallet.get_last_block().unwrap().map(|(height, _)| height),
+            Some(5)
+        );
+
+        // Only the immature utxo reported by the chain source is skipped.
+        let txn = wallet.env.read_txn().unwrap();
+        let immature: Vec<OutPoint> = wallet
+            .immature_utxos
+            .iter(&txn)
+            .unwrap()
+            .map(|item| item.unwrap().0)
+            .collect();
+        assert_eq!(immature, vec![second]);
+        drop(txn);
+        let (total, selected) = wallet.select_coins(3_500).unwrap();
+        assert_eq!(total, 3_900);
+        assert!(!selected.contains_key(&second));
+
+        // Blocks are fetched once, and every address is looked up in the
+        // utxo set once, however many addresses were derived.
+        assert_eq!(*chain.body_requests.lock().unwrap(), 5);
+        let address_requests = chain.address_requests.lock().unwrap();
+        let unique: HashSet<&Address> = address_requests.iter().collect();
+        assert_eq!(address_requests.len(), unique.len());
+        assert_eq!(address_requests.len() as u32, 11 + GAP_LIMIT);
+    }
+}
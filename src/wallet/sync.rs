@@ -0,0 +1,481 @@
+//! Keeping a wallet up to date with a node.
+use super::{BlockChanges, ChainSource, Error, HistoryEntry, Wallet};
+use crate::state::PegUtxoChanges;
+use crate::types::{Body, GetValue, Header, OutPoint};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::marker::PhantomData;
+use std::time::Duration;
+
+/// What a call to `WalletSync::sync` changed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SyncSummary {
+    /// Blocks undone because the chain source no longer has them.
+    pub disconnected_blocks: u32,
+    pub connected_blocks: u32,
+    /// Height of the last processed block.
+    pub height: u32,
+    /// Wallet utxos spent by pending mempool transactions.
+    pub locked_utxos: usize,
+}
+
+/// Follows the blocks of a chain source, usually a `Node`, and updates the
+/// wallet utxos and history one block at a time. Blocks that are no longer
+/// in the chain are undone first, and utxos spent by pending mempool
+/// transactions are locked so `select_coins` skips them.
+///
+/// Deposits, withdrawals spent or restored by withdrawal bundles and refunds
+/// are not in block bodies, they are fetched with the block as
+/// `PegUtxoChanges`. Wallets synced before a node recorded those should be
+/// rebuilt once with `Wallet::rescan_from`.
+#[derive(Clone)]
+pub struct WalletSync<A, C, S> {
+    wallet: Wallet<C>,
+    source: S,
+    _authorization: PhantomData<fn() -> A>,
+}
+
+impl<
+        A: 'static,
+        C: GetValue + Clone + Serialize + for<'de> Deserialize<'de> + Send + Sync + 'static,
+        S: ChainSource<A, C> + Send + Sync + 'static,
+    > WalletSync<A, C, S>
+{
+    pub fn new(wallet: Wallet<C>, source: S) -> Self {
+        Self {
+            wallet,
+            source,
+            _authorization: PhantomData,
+        }
+    }
+
+    /// Sync every `interval` in a background task.
+    pub fn spawn(self, interval: Duration) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                if let Err(err) = self.sync() {
+                    println!("failed to sync wallet: {err:?}");
+                }
+                tokio::time::sleep(interval).await;
+            }
+        })
+    }
+
+    pub fn sync(&self) -> Result<SyncSummary, Error> {
+        let chain_source_error = |err: S::Error| Error::ChainSource(Box::new(err));
+        let mut summary = SyncSummary::default();
+        while let Some((height, block_hash)) = self.wallet.get_last_block()? {
+            let header = self.source.get_header(height).map_err(chain_source_error)?;
+            if header.map(|header| header.hash()) == Some(block_hash) {
+                summary.height = height;
+                break;
+            }
+            self.disconnect_block(height)?;
+            summary.disconnected_blocks += 1;
+        }
+        let tip = self.source.get_height().map_err(chain_source_error)?;
+        for height in summary.height + 1..=tip {
+            let header = self.source.get_header(height).map_err(chain_source_error)?;
+            let body = self.source.get_body(height).map_err(chain_source_error)?;
+            // The body is not downloaded yet.
+            let (Some(header), Some(body)) = (header, body) else {
+                break;
+            };
+            let peg_utxo_changes = self
+                .source
+                .get_peg_utxo_changes(height)
+                .map_err(chain_source_error)?;
+            self.connect_block(height, &header, &body, &peg_utxo_changes)?;
+            summary.connected_blocks += 1;
+            summary.height = height;
+        }
+        summary.locked_utxos = self.lock_mempool_utxos()?;
+        Ok(summary)
+    }
+
+    fn connect_block(
+        &self,
+        height: u32,
+        header: &Header,
+        body: &Body<A, C>,
+        peg_utxo_changes: &PegUtxoChanges<C>,
+    ) -> Result<(), Error> {
+        let wallet = &self.wallet;
+        let mut txn = wallet.env.write_txn()?;
+        let mut changes = BlockChanges::default();
+        for (outpoint, output) in body.get_outputs() {
+            if wallet
+                .address_to_index
+                .get(&txn, &output.address)?
+                .is_none()
+            {
+                continue;
+            }
+            let entry = HistoryEntry {
+                outpoint,
+                output: output.clone(),
+                height: Some(height),
+                spent_height: None,
+            };
+            wallet.history.put(&mut txn, &outpoint, &entry)?;
+            wallet.utxos.put(&mut txn, &outpoint, &output)?;
+            changes.created.push(outpoint);
+        }
+        let spent = body.get_inputs();
+        for outpoint in spent.iter().chain(&peg_utxo_changes.spent) {
+            let utxo = wallet.utxos.get(&txn, outpoint)?;
+            let history_output = match wallet.history.get(&txn, outpoint)? {
+                Some(mut entry) => {
+                    entry.spent_height = Some(height);
+                    wallet.history.put(&mut txn, outpoint, &entry)?;
+                    Some(entry.output)
+                }
+                None => None,
+            };
+            // Utxos added with `put_utxos` have no history entry.
+            if let Some(output) = utxo.or(history_output) {
+                changes.spent.push((*outpoint, output));
+            }
+            wallet.utxos.delete(&mut txn, outpoint)?;
+        }
+        for (outpoint, output) in &peg_utxo_changes.created {
+            match wallet.history.get(&txn, outpoint)? {
+                Some(mut entry) => {
+                    // A withdrawal restored after its bundle failed.
+                    if let Some(spent_height) = entry.spent_height.take() {
+                        wallet.history.put(&mut txn, outpoint, &entry)?;
+                        changes.restored.push((*outpoint, spent_height));
+                    }
+                }
+                None => {
+                    if wallet
+                        .address_to_index
+                        .get(&txn, &output.address)?
+                        .is_none()
+                    {
+                        continue;
+                    }
+                    let entry = HistoryEntry {
+                        outpoint: *outpoint,
+                        output: output.clone(),
+                        height: Some(height),
+                        spent_height: None,
+                    };
+                    wallet.history.put(&mut txn, outpoint, &entry)?;
+                    changes.created.push(*outpoint);
+                }
+            }
+            wallet.utxos.put(&mut txn, outpoint, output)?;
+        }
+        let height_bytes = height.to_be_bytes();
+        wallet
+            .block_changes
+            .put(&mut txn, &height_bytes, &changes)?;
+        wallet
+            .block_hashes
+            .put(&mut txn, &height_bytes, &header.hash())?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn disconnect_block(&self, height: u32) -> Result<(), Error> {
+        let wallet = &self.wallet;
+        let mut txn = wallet.env.write_txn()?;
+        let height_bytes = height.to_be_bytes();
+        let changes = wallet
+            .block_changes
+            .get(&txn, &height_bytes)?
+            .unwrap_or_default();
+        for (outpoint, spent_height) in &changes.restored {
+            if let Some(mut entry) = wallet.history.get(&txn, outpoint)? {
+                entry.spent_height = Some(*spent_height);
+                wallet.history.put(&mut txn, outpoint, &entry)?;
+            }
+            wallet.utxos.delete(&mut txn, outpoint)?;
+        }
+        for (outpoint, output) in &changes.spent {
+            if let Some(mut entry) = wallet.history.get(&txn, outpoint)? {
+                entry.spent_height = None;
+                wallet.history.put(&mut txn, outpoint, &entry)?;
+            }
+            wallet.utxos.put(&mut txn, outpoint, output)?;
+        }
+        for outpoint in &changes.created {
+            wallet.history.delete(&mut txn, outpoint)?;
+            wallet.utxos.delete(&mut txn, outpoint)?;
+        }
+        wallet.block_changes.delete(&mut txn, &height_bytes)?;
+        wallet.block_hashes.delete(&mut txn, &height_bytes)?;
+        txn.commit()?;
+        Ok(())
+    }
+
+    fn lock_mempool_utxos(&self) -> Result<usize, Error> {
+        let transactions = self
+            .source
+            .get_all_transactions()
+            .map_err(|err| Error::ChainSource(Box::new(err)))?;
+        let utxos = self.wallet.get_utxos()?;
+        let locked: HashSet<OutPoint> = transactions
+            .iter()
+            .flat_map(|transaction| transaction.transaction.inputs.iter())
+            .filter(|outpoint| utxos.contains_key(outpoint))
+            .copied()
+            .collect();
+        let locked: Vec<OutPoint> = locked.into_iter().collect();
+        self.wallet.set_locked_utxos(&locked)?;
+        Ok(locked.len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::authorization::{get_address, Authorization};
+    use crate::types::{bitcoin, Address, AuthorizedTransaction, Content, Output, Transaction};
+    use crate::wallet::tests::{value_output, FakeChain, TestWallet};
+    use bitcoin::hashes::Hash as _;
+    use std::collections::HashMap;
+
+    fn sync(wallet: &Wallet<()>, chain: &FakeChain) -> SyncSummary {
+        WalletSync::<Authorization, (), _>::new(wallet.clone(), chain.clone())
+            .sync()
+            .unwrap()
+    }
+
+    fn address(wallet: &Wallet<()>) -> Address {
+        wallet.get_new_address().unwrap()
+    }
+
+    fn outpoints(wallet: &Wallet<()>) -> HashSet<OutPoint> {
+        wallet.get_utxos().unwrap().into_keys().collect()
+    }
+
+    fn history(wallet: &Wallet<()>) -> HashMap<OutPoint, HistoryEntry<()>> {
+        wallet
+            .get_history()
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.outpoint, entry))
+            .collect()
+    }
+
+    fn spend(inputs: Vec<OutPoint>, outputs: Vec<Output<()>>) -> Transaction<()> {
+        Transaction {
+            inputs,
+            outputs,
+            lock_time: None,
+            relative_locks: vec![],
+        }
+    }
+
+    fn regular(transaction: &Transaction<()>, vout: u32) -> OutPoint {
+        OutPoint::Regular {
+            txid: transaction.txid(),
+            vout,
+        }
+    }
+
+    /// Address of a wallet that isn't ours.
+    fn other_address() -> Address {
+        let secret = ed25519_dalek::SecretKey::from_bytes(&[9; 32]).unwrap();
+        get_address(&(&secret).into())
+    }
+
+    #[test]
+    fn sync_connects_blocks() {
+        let test_wallet = TestWallet::with_seed("sync_connects_blocks", 1);
+        let wallet = &test_wallet.wallet;
+        let mut chain = FakeChain::default();
+        let coinbase = chain.push_block(
+            vec![value_output(address(wallet), 1_000)],
+            vec![],
+            PegUtxoChanges::default(),
+        )[0];
+        let transaction = spend(
+            vec![coinbase],
+            vec![
+                value_output(other_address(), 600),
+                value_output(address(wallet), 400),
+            ],
+        );
+        let change = regular(&transaction, 1);
+        chain.push_block(vec![], vec![transaction], PegUtxoChanges::default());
+
+        let summary = sync(wallet, &chain);
+        assert_eq!(
+            summary,
+            SyncSummary {
+                disconnected_blocks: 0,
+                connected_blocks: 2,
+                height: 2,
+                locked_utxos: 0,
+            }
+        );
+        assert_eq!(outpoints(wallet), HashSet::from([change]));
+        let history = history(wallet);
+        assert_eq!(history.len(), 2);
+        assert_eq!(history[&coinbase].height, Some(1));
+        assert_eq!(history[&coinbase].spent_height, Some(2));
+        assert_eq!(history[&change].height, Some(2));
+
+        // Nothing new to connect.
+        assert_eq!(sync(wallet, &chain).connected_blocks, 0);
+    }
+
+    #[test]
+    fn reorg_restores_spent_utxos() {
+        let test_wallet = TestWallet::with_seed("reorg_restores_spent_utxos", 1);
+        let wallet = &test_wallet.wallet;
+        let mut chain = FakeChain::default();
+        let coinbase = chain.push_block(
+            vec![value_output(address(wallet), 1_000)],
+            vec![],
+            PegUtxoChanges::default(),
+        )[0];
+        sync(wallet, &chain);
+        // A utxo the wallet was given without a history entry.
+        let given = OutPoint::Regular {
+            txid: [3; 32].into(),
+            vout: 0,
+        };
+        let given_output = value_output(address(wallet), 500);
+        wallet
+            .put_utxos(&HashMap::from([(given, given_output.clone())]))
+            .unwrap();
+        let transaction = spend(
+            vec![coinbase, given],
+            vec![value_output(address(wallet), 1_400)],
+        );
+        let received = regular(&transaction, 0);
+        chain.push_block(vec![], vec![transaction], PegUtxoChanges::default());
+        sync(wallet, &chain);
+        assert_eq!(outpoints(wallet), HashSet::from([received]));
+
+        // Block 2 is replaced by a block that doesn't spend anything.
+        chain.pop_block();
+        chain.push_block(
+            vec![value_output(other_address(), 1)],
+            vec![],
+            PegUtxoChanges::default(),
+        );
+        let summary = sync(wallet, &chain);
+        assert_eq!(summary.disconnected_blocks, 1);
+        assert_eq!(summary.connected_blocks, 1);
+        assert_eq!(summary.height, 2);
+        let utxos = wallet.get_utxos().unwrap();
+        assert_eq!(
+            utxos.keys().copied().collect::<HashSet<_>>(),
+            HashSet::from([coinbase, given])
+        );
+        assert_eq!(utxos[&given], given_output);
+        let history = history(wallet);
+        assert_eq!(history.len(), 1);
+        assert_eq!(history[&coinbase].spent_height, None);
+    }
+
+    #[test]
+    fn withdrawal_restored_after_failed_bundle() {
+        let test_wallet = TestWallet::with_seed("withdrawal_restored", 1);
+        let wallet = &test_wallet.wallet;
+        let main_address = bitcoin::Address::new(
+            bitcoin::Network::Regtest,
+            bitcoin::address::Payload::PubkeyHash(bitcoin::PubkeyHash::from_byte_array([0; 20])),
+        );
+        let withdrawal_output = Output {
+            address: address(wallet),
+            content: Content::Withdrawal {
+                value: 1_000,
+                main_fee: 10,
+                main_address: main_address.as_unchecked().clone(),
+            },
+        };
+        let deposit = OutPoint::Deposit(bitcoin::OutPoint {
+            txid: bitcoin::Txid::from_byte_array([1; 32]),
+            vout: 0,
+        });
+        let mut chain = FakeChain::default();
+        // Block 1 connects a deposit, block 2 withdraws it.
+        chain.push_block(
+            vec![],
+            vec![],
+            PegUtxoChanges {
+                spent: vec![],
+                created: vec![(deposit, value_output(address(wallet), 1_010))],
+            },
+        );
+        let transaction = spend(vec![deposit], vec![withdrawal_output.clone()]);
+        let withdrawal = regular(&transaction, 0);
+        chain.push_block(vec![], vec![transaction], PegUtxoChanges::default());
+        // Block 3 collects it into a bundle.
+        chain.push_block(
+            vec![],
+            vec![],
+            PegUtxoChanges {
+                spent: vec![withdrawal],
+                created: vec![],
+            },
+        );
+        sync(wallet, &chain);
+        assert!(outpoints(wallet).is_empty());
+        assert_eq!(history(wallet)[&deposit].height, Some(1));
+        assert_eq!(history(wallet)[&withdrawal].spent_height, Some(3));
+
+        // Block 4 restores it after the bundle failed.
+        chain.push_block(
+            vec![],
+            vec![],
+            PegUtxoChanges {
+                spent: vec![],
+                created: vec![(withdrawal, withdrawal_output)],
+            },
+        );
+        sync(wallet, &chain);
+        assert_eq!(outpoints(wallet), HashSet::from([withdrawal]));
+        assert_eq!(history(wallet)[&withdrawal].spent_height, None);
+        assert_eq!(history(wallet)[&withdrawal].height, Some(2));
+
+        // Undoing block 4 spends it again.
+        chain.pop_block();
+        chain.push_block(vec![], vec![], PegUtxoChanges::default());
+        let summary = sync(wallet, &chain);
+        assert_eq!(summary.disconnected_blocks, 1);
+        assert!(outpoints(wallet).is_empty());
+        assert_eq!(history(wallet)[&withdrawal].spent_height, Some(3));
+    }
+
+    #[test]
+    fn mempool_spends_lock_utxos() {
+        let test_wallet = TestWallet::with_seed("mempool_spends_lock_utxos", 1);
+        let wallet = &test_wallet.wallet;
+        let mut chain = FakeChain::default();
+        let coinbase = chain.push_block(
+            vec![
+                value_output(address(wallet), 1_000),
+                value_output(address(wallet), 2_000),
+            ],
+            vec![],
+            PegUtxoChanges::default(),
+        );
+        chain.mempool.push(AuthorizedTransaction {
+            transaction: spend(
+                vec![coinbase[1]],
+                vec![value_output(other_address(), 1_900)],
+            ),
+            authorizations: vec![],
+        });
+        assert_eq!(sync(wallet, &chain).locked_utxos, 1);
+        let (total, selected) = wallet.select_coins(500).unwrap();
+        assert_eq!(total, 1_000);
+        assert!(selected.contains_key(&coinbase[0]));
+        assert!(matches!(
+            wallet.select_coins(1_500),
+            Err(Error::NotEnoughFunds)
+        ));
+
+        // The transaction left the mempool without being included.
+        chain.mempool.clear();
+        assert_eq!(sync(wallet, &chain).locked_utxos, 0);
+        assert_eq!(wallet.select_coins(1_500).unwrap().0, 3_000);
+    }
+}